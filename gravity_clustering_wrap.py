@@ -23,16 +23,14 @@ def fit_predict(targets,command,feature_sub=None,distance=None,verbose=False,sam
     # np.array(targets)
     targets = targets.astype(dtype=float)
 
-    targets = "\n".join(["\t".join([str(y) for y in x]) for x in targets]) + "\n"
+    # Counts and labels go through .npy files, which keeps full precision and skips parsing text
 
-
-    input_temp = tmp.NamedTemporaryFile()
+    input_temp = tmp.NamedTemporaryFile(suffix=".npy")
+    labels_temp = tmp.NamedTemporaryFile(suffix=".npy")
     progress_temp = tmp.NamedTemporaryFile()
     # final_pos_temp = tmp.NamedTemporaryFile()
 
-    input_writer = open(input_temp.name,mode='w')
-    input_writer.write(targets)
-    input_writer.close()
+    np.save(input_temp.name,np.ascontiguousarray(targets))
 
     # print(targets)
 
@@ -45,7 +43,8 @@ def fit_predict(targets,command,feature_sub=None,distance=None,verbose=False,sam
     print("Running " + str(path_to_rust))
 
     arg_list = [str(path_to_rust),command]
-    arg_list.extend(["-c",input_temp.name])
+    arg_list.extend(["-npy",input_temp.name])
+    arg_list.extend(["-o",labels_temp.name])
     # arg_list.extend(["-stdin"])
     # arg_list.extend(["-stdout"])
     if verbose:
@@ -133,4 +132,4 @@ def fit_predict(targets,command,feature_sub=None,distance=None,verbose=False,sam
 
     # print(cp.stdout.read())
 
-    return(list(map(lambda x: int(x),cp.stdout.read().split())))
+    return(list(np.load(labels_temp.name)))
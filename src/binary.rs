@@ -0,0 +1,315 @@
+use std::fs::File;
+use std::io::{Error,ErrorKind};
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::slice;
+
+use memmap::{Mmap,MmapOptions};
+use ndarray::{Array,ArrayView,Ix1,Ix2};
+
+use io::AtomicFile;
+use sparse::{CsrMatrix,Rows};
+
+// Binary count format:
+//
+// 8 bytes   magic, b"GRAVBIN\x01"
+// 8 bytes   rows, little-endian u64
+// 8 bytes   columns, little-endian u64
+// 1 byte    dtype, 0 = f64, 1 = f32
+// 7 bytes   padding, keeps the data 8-byte aligned
+// ...       row-major little-endian values
+
+const MAGIC: &[u8; 8] = b"GRAVBIN\x01";
+const HEADER_LENGTH: usize = 32;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum DType {
+    F64,
+    F32,
+}
+
+impl DType {
+    fn code(&self) -> u8 {
+        match self {
+            DType::F64 => 0,
+            DType::F32 => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<DType,Error> {
+        match code {
+            0 => Ok(DType::F64),
+            1 => Ok(DType::F32),
+            _ => Err(Error::new(ErrorKind::InvalidData,format!("Unknown dtype code in binary header: {}",code))),
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            DType::F64 => 8,
+            DType::F32 => 4,
+        }
+    }
+
+    pub fn parse(argument: &str) -> DType {
+        match argument {
+            "f64" | "float64" | "double" => DType::F64,
+            "f32" | "float32" | "float" => DType::F32,
+            _ => {
+                eprintln!("Not a valid dtype, defaulting to f64");
+                DType::F64
+            }
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct BinaryHeader {
+    pub rows: usize,
+    pub cols: usize,
+    pub dtype: DType,
+}
+
+// Bytes taken by a rows x cols matrix, or None if that overflows. Shapes come from
+// file headers, so they are never trusted to multiply safely.
+pub fn data_length(rows: usize, cols: usize, width: usize) -> Option<usize> {
+    rows.checked_mul(cols).and_then(|x| x.checked_mul(width))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buffer)
+}
+
+impl BinaryHeader {
+
+    pub fn parse(bytes: &[u8]) -> Result<BinaryHeader,Error> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..8] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData,"Not a gravity binary count file (bad magic)"))
+        }
+        let rows = read_u64(&bytes[8..16]) as usize;
+        let cols = read_u64(&bytes[16..24]) as usize;
+        let dtype = DType::from_code(bytes[24])?;
+        let expected = data_length(rows,cols,dtype.width()).and_then(|x| x.checked_add(HEADER_LENGTH))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData,format!("Binary count file header is corrupt, {}x{} is too large",rows,cols)))?;
+        if bytes.len() < expected {
+            return Err(Error::new(ErrorKind::UnexpectedEof,format!("Binary count file is truncated, expected {} bytes, found {}",expected,bytes.len())))
+        }
+        Ok(BinaryHeader { rows, cols, dtype })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let mut header = [0u8; HEADER_LENGTH];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&(self.rows as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&(self.cols as u64).to_le_bytes());
+        header[24] = self.dtype.code();
+        header
+    }
+}
+
+/// A row-major f64 matrix that lives in a memory-mapped binary count or .npy file,
+/// starting offset bytes in.
+#[derive(Debug,Clone)]
+pub struct MappedArray {
+    map: Arc<Mmap>,
+    offset: usize,
+    rows: usize,
+    cols: usize,
+}
+
+impl MappedArray {
+
+    pub fn new(map: Arc<Mmap>, offset: usize, rows: usize, cols: usize) -> Result<MappedArray,Error> {
+        if !offset.is_multiple_of(8) {
+            return Err(Error::new(ErrorKind::InvalidData,format!("Mapped data has to start at a multiple of 8 bytes, not {}",offset)))
+        }
+        let end = data_length(rows,cols,8).and_then(|x| x.checked_add(offset))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData,format!("A {}x{} array is too large to map",rows,cols)))?;
+        if map.len() < end {
+            return Err(Error::new(ErrorKind::UnexpectedEof,format!("Mapped data is truncated, expected {} bytes, found {}",end,map.len())))
+        }
+        Ok(MappedArray { map, offset, rows, cols })
+    }
+
+    pub fn view(&self) -> ArrayView<'_,f64,Ix2> {
+        let data = &self.map[self.offset..];
+        // The map is page aligned and the offset is a multiple of 8 bytes, so the
+        // data segment is suitably aligned for f64.
+        let values: &[f64] = unsafe { slice::from_raw_parts(data.as_ptr() as *const f64, self.rows * self.cols) };
+        ArrayView::from_shape((self.rows,self.cols),values).unwrap()
+    }
+
+    pub fn shape(&self) -> (usize,usize) {
+        (self.rows,self.cols)
+    }
+}
+
+/// Input positions, either read into memory, mapped from a binary count file, or
+/// kept sparse. Sparse points can only be searched through as Rows, anything that
+/// needs the whole matrix densifies them first.
+#[derive(Debug,Clone)]
+pub enum Points {
+    Owned(Array<f64,Ix2>),
+    Mapped(MappedArray),
+    Sparse(Arc<CsrMatrix>),
+}
+
+impl Points {
+
+    pub fn view(&self) -> ArrayView<'_,f64,Ix2> {
+        match self {
+            Points::Owned(array) => array.view(),
+            Points::Mapped(mapped) => mapped.view(),
+            Points::Sparse(_) => panic!("Sparse points have to be densified before they can be viewed as an array"),
+        }
+    }
+
+    pub fn rows(&self) -> Rows<'_> {
+        match self {
+            Points::Sparse(matrix) => Rows::Sparse(matrix),
+            _ => Rows::Dense(self.view()),
+        }
+    }
+
+    pub fn row(&self, i: usize) -> Array<f64,Ix1> {
+        self.rows().row(i)
+    }
+
+    pub fn shape(&self) -> (usize,usize) {
+        match self {
+            Points::Owned(array) => (array.rows(),array.cols()),
+            Points::Mapped(mapped) => mapped.shape(),
+            Points::Sparse(matrix) => (matrix.rows,matrix.cols),
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self,Points::Sparse(_))
+    }
+
+    pub fn has_nan(&self) -> bool {
+        match self {
+            Points::Sparse(matrix) => matrix.data.iter().any(|x| x.is_nan()),
+            _ => self.view().iter().any(|x| x.is_nan()),
+        }
+    }
+
+    pub fn into_owned(self) -> Array<f64,Ix2> {
+        match self {
+            Points::Owned(array) => array,
+            Points::Mapped(mapped) => mapped.view().to_owned(),
+            Points::Sparse(matrix) => {
+                eprintln!("WARNING: densifying {}x{} sparse counts",matrix.rows,matrix.cols);
+                matrix.to_dense()
+            },
+        }
+    }
+}
+
+impl From<Array<f64,Ix2>> for Points {
+    fn from(array: Array<f64,Ix2>) -> Points {
+        Points::Owned(array)
+    }
+}
+
+pub fn read_binary(location: &str,verbose:bool) -> Result<Points,Error> {
+
+    let file = File::open(location)?;
+    let map = unsafe { MmapOptions::new().map(&file)? };
+    let header = BinaryHeader::parse(&map)?;
+
+    if verbose {
+        eprintln!("Binary counts: {},{} ({:?})",header.rows,header.cols,header.dtype);
+    }
+
+    if header.dtype == DType::F64 && cfg!(target_endian = "little") {
+        return Ok(Points::Mapped(MappedArray::new(Arc::new(map),HEADER_LENGTH,header.rows,header.cols)?))
+    }
+
+    // Anything we cannot hand out directly gets decoded into memory
+    if verbose {
+        eprintln!("Decoding {:?} binary counts into memory",header.dtype);
+    }
+
+    let width = header.dtype.width();
+    let data = &map[HEADER_LENGTH..HEADER_LENGTH + header.rows * header.cols * width];
+    let values: Vec<f64> = data.chunks(width).map(|chunk| {
+        match header.dtype {
+            DType::F64 => {
+                let mut buffer = [0u8; 8];
+                buffer.copy_from_slice(chunk);
+                f64::from_le_bytes(buffer)
+            },
+            DType::F32 => {
+                let mut buffer = [0u8; 4];
+                buffer.copy_from_slice(chunk);
+                f32::from_le_bytes(buffer) as f64
+            }
+        }
+    }).collect();
+
+    let array = Array::from_shape_vec((header.rows,header.cols),values).map_err(|e| Error::new(ErrorKind::InvalidData,format!("{:?}",e)))?;
+
+    Ok(Points::Owned(array))
+}
+
+pub fn write_binary(input: ArrayView<f64,Ix2>,dtype: DType,location: &str) -> Result<(),Error> {
+
+    let header = BinaryHeader { rows: input.rows(), cols: input.cols(), dtype };
+
+    let mut writer = AtomicFile::create(location)?;
+    writer.write_all(&header.to_bytes())?;
+
+    for value in input.iter() {
+        match dtype {
+            DType::F64 => writer.write_all(&value.to_le_bytes())?,
+            DType::F32 => writer.write_all(&(*value as f32).to_le_bytes())?,
+        }
+    }
+
+    writer.commit()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn location(name: &str) -> String {
+        env::temp_dir().join(format!("gravity_{}_{}.grav",name,std::process::id())).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn round_trips_both_dtypes() {
+        let array = Array::from_shape_vec((3,2),vec![1.,-2.,0.5,0.,1024.,-0.125]).unwrap();
+        for dtype in [DType::F64,DType::F32].iter() {
+            let location = location(&format!("{:?}",dtype));
+            write_binary(array.view(),*dtype,&location).unwrap();
+            let read = read_binary(&location,false).unwrap();
+            assert_eq!(matches!(read,Points::Mapped(_)),*dtype == DType::F64);
+            assert_eq!(read.into_owned(),array);
+            fs::remove_file(&location).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_headers() {
+        let header = |rows: u64, cols: u64, code: u8| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend_from_slice(&rows.to_le_bytes());
+            bytes.extend_from_slice(&cols.to_le_bytes());
+            bytes.push(code);
+            bytes.extend_from_slice(&[0; 7]);
+            bytes
+        };
+        assert_eq!(BinaryHeader::parse(&header(1 << 62,4,0)).err().unwrap().kind(),ErrorKind::InvalidData);
+        assert_eq!(BinaryHeader::parse(&header(2,2,0)).err().unwrap().kind(),ErrorKind::UnexpectedEof);
+        assert_eq!(BinaryHeader::parse(&header(0,0,9)).err().unwrap().kind(),ErrorKind::InvalidData);
+        assert_eq!(BinaryHeader::parse(b"GRAVBIN").err().unwrap().kind(),ErrorKind::InvalidData);
+        assert_eq!(BinaryHeader::parse(&header(0,5,1)).unwrap().cols,5);
+    }
+}
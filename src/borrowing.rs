@@ -0,0 +1,202 @@
+use std::f64;
+use std::io::Error;
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::cmp::Ordering;
+
+use ndarray::{Array,Ix1};
+use rayon::prelude::*;
+
+use io::{AtomicFile,Distance};
+use binary::Points;
+use sparse::CsrMatrix;
+
+// Borrowing over a sparse feature graph. Instead of multiplying the counts by the
+// full features x features similarity matrix, every feature only borrows from its
+// k most similar features, and from itself, with weights that sum to 1. A borrowed
+// value is then a weighted average of the sample's values in similar features, so
+// the counts keep their scale, and sparse counts stay sparse.
+//
+// Features are compared by cosine similarity under cosine distance and by
+// correlation otherwise, one feature at a time, so the full matrix never exists.
+// Only positive similarities are borrowed from.
+
+#[derive(Debug,Clone)]
+pub struct Neighbor {
+    pub feature: usize,
+    pub similarity: f64,
+    pub weight: f64,
+}
+
+#[derive(Debug,Clone)]
+pub struct FeatureGraph {
+    pub features: usize,
+    // For every feature, the features it borrows from, itself first
+    pub neighbors: Vec<Vec<Neighbor>>,
+}
+
+impl FeatureGraph {
+
+    pub fn build(points: &Points, k: usize, distance: Distance) -> FeatureGraph {
+
+        let (samples,features) = points.shape();
+        let n = samples as f64;
+
+        // Features as rows
+        let columns = match points {
+            Points::Sparse(counts) => counts.transpose(),
+            dense => CsrMatrix::from_dense(dense.view().t()),
+        };
+        let rows = points.rows();
+
+        let norms: Vec<f64> = (0..features).map(|j| columns.row(j).norm()).collect();
+        let means: Vec<f64> = (0..features).map(|j| columns.row(j).data.iter().sum::<f64>() / n).collect();
+        let deviations: Vec<f64> = (0..features).map(|j| ((norms[j].powi(2) / n) - means[j].powi(2)).max(0.).sqrt()).collect();
+
+        let neighbors = (0..features).into_par_iter().map(|j| {
+            // Products of feature j with every feature, through the samples it is nonzero in
+            let column = columns.row(j);
+            let mut products: Array<f64,Ix1> = Array::zeros(features);
+            for (i,x) in column.indices.iter().zip(column.data.iter()) {
+                rows.add_row(&mut products,*i,*x);
+            }
+            let similarity = |l: usize| match distance {
+                Distance::Cosine => products[l] / (norms[j] * norms[l]),
+                _ => (products[l] / n - means[j] * means[l]) / (deviations[j] * deviations[l]),
+            };
+            let mut candidates: Vec<(usize,f64)> = (0..features).filter(|l| *l != j).map(|l| (l,similarity(l))).filter(|(_,s)| s.is_finite() && *s > 0.).collect();
+            candidates.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+            candidates.truncate(k);
+
+            let total = 1. + candidates.iter().map(|(_,s)| s).sum::<f64>();
+            let mut neighbors = vec![Neighbor { feature: j, similarity: 1., weight: 1. / total }];
+            neighbors.extend(candidates.into_iter().map(|(l,s)| Neighbor { feature: l, similarity: s, weight: s / total }));
+            neighbors
+        }).collect();
+
+        eprintln!("Feature graph: {} features, up to {} neighbors each",features,k);
+
+        FeatureGraph { features, neighbors }
+    }
+
+    pub fn apply(&self, points: Points) -> Points {
+        let (samples,features) = points.shape();
+        if features != self.features {
+            panic!("Feature graph has {} features, the counts have {}",self.features,features);
+        }
+        match &points {
+            Points::Sparse(counts) => {
+                // Feature l lends to every feature that borrows from it
+                let mut lenders: Vec<Vec<(usize,f64)>> = vec![vec![]; features];
+                for (j,neighbors) in self.neighbors.iter().enumerate() {
+                    for neighbor in neighbors {
+                        lenders[neighbor.feature].push((j,neighbor.weight));
+                    }
+                }
+                let mut triplets = vec![];
+                for i in 0..samples {
+                    let row = counts.row(i);
+                    for (l,x) in row.indices.iter().zip(row.data.iter()) {
+                        triplets.extend(lenders[*l].iter().map(|(j,w)| (i,*j,w * x)));
+                    }
+                }
+                Points::Sparse(Arc::new(CsrMatrix::from_triplets(samples,features,triplets)))
+            },
+            dense => {
+                let view = dense.view();
+                let borrowed = Array::from_shape_fn((samples,features),|(i,j)| {
+                    self.neighbors[j].iter().map(|neighbor| neighbor.weight * view[[i,neighbor.feature]]).sum::<f64>()
+                });
+                borrowed.into()
+            },
+        }
+    }
+
+    pub fn write(&self, feature_names: Option<&[String]>, location: &str) -> Result<(),Error> {
+        let name = |j: usize| feature_names.map(|names| names[j].clone()).unwrap_or_else(|| j.to_string());
+        let mut graph_file = AtomicFile::create(location)?;
+        writeln!(graph_file,"feature\tneighbor\tsimilarity\tweight")?;
+        for (j,neighbors) in self.neighbors.iter().enumerate() {
+            for neighbor in neighbors {
+                writeln!(graph_file,"{}\t{}\t{:?}\t{:?}",name(j),name(neighbor.feature),neighbor.similarity,neighbor.weight)?;
+            }
+        }
+        graph_file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use io::Parameters;
+
+    // The first two features point the same way, the third half way to them and the
+    // last is orthogonal to everything
+    fn counts() -> Array<f64,ndarray::Ix2> {
+        Array::from_shape_vec((4,4),vec![
+            1.,2.,1.,0.,
+            1.,2.,0.,0.,
+            0.,0.,1.,0.,
+            0.,0.,0.,1.,
+        ]).unwrap()
+    }
+
+    fn graph() -> FeatureGraph {
+        FeatureGraph::build(&counts().into(),1,Distance::Cosine)
+    }
+
+    #[test]
+    fn keeps_the_k_most_similar_features() {
+        let neighbors: Vec<Vec<usize>> = graph().neighbors.iter().map(|x| x.iter().map(|n| n.feature).collect()).collect();
+        assert_eq!(neighbors,vec![vec![0,1],vec![1,0],vec![2,0],vec![3]]);
+        let graph = graph();
+        assert!((graph.neighbors[2][1].similarity - 0.5).abs() < 1e-12);
+        for neighbors in &graph.neighbors {
+            assert!((neighbors.iter().map(|n| n.weight).sum::<f64>() - 1.).abs() < 1e-12);
+        }
+        let wider = FeatureGraph::build(&counts().into(),3,Distance::Cosine);
+        assert_eq!(wider.neighbors[2].len(),3);
+    }
+
+    #[test]
+    fn borrowed_values_are_weighted_averages() {
+        let borrowed = graph().apply(counts().into()).into_owned();
+        let expected = [1.5,1.5,1.,0.];
+        assert!(borrowed.row(0).iter().zip(expected.iter()).all(|(a,b)| (a - b).abs() < 1e-12));
+        let sparse = Points::Sparse(Arc::new(CsrMatrix::from_dense(counts().view())));
+        let sparse_borrowed = graph().apply(sparse);
+        assert!(sparse_borrowed.is_sparse());
+        assert!(sparse_borrowed.into_owned().iter().zip(borrowed.iter()).all(|(a,b)| (a - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn writes_every_edge() {
+        let location = env::temp_dir().join(format!("gravity_feature_graph_{}",std::process::id()));
+        let location = location.to_str().unwrap();
+        let names: Vec<String> = ["a","b","c","d"].iter().map(|x| x.to_string()).collect();
+        graph().write(Some(&names),location).unwrap();
+        let written = fs::read_to_string(location).unwrap();
+        fs::remove_file(location).unwrap();
+        let edges: Vec<Vec<&str>> = written.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(edges.len(),8);
+        assert_eq!(edges[0],vec!["feature","neighbor","similarity","weight"]);
+        assert_eq!(edges.iter().skip(1).map(|x| (x[0],x[1])).collect::<Vec<_>>(),vec![("a","a"),("a","b"),("b","b"),("b","a"),("c","c"),("c","a"),("d","d")]);
+        assert!((edges[5][3].parse::<f64>().unwrap() - 2. / 3.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn borrow_k_borrows_once_unless_told_otherwise() {
+        let parse = |args: &[&str]| {
+            let mut args = ["gravity_clustering","fitpredict"].iter().chain(args.iter()).map(|x| x.to_string());
+            Parameters::read(&mut args)
+        };
+        let parameters = parse(&["-borrow_k","5"]);
+        assert_eq!((parameters.borrow,parameters.borrow_neighbors),(Some(1),Some(5)));
+        assert_eq!(parse(&["-borrow_k","5","-borrow","2"]).borrow,Some(2));
+        assert_eq!(parse(&["-borrow","2","-borrow_k","5"]).borrow,Some(2));
+        assert_eq!(parse(&[]).borrow,None);
+    }
+}
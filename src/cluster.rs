@@ -1,25 +1,23 @@
 use std::sync::Arc;
-use std::collections::HashSet;
-use ndarray::{Array,Ix1,Ix2,Ix3,Zip,Axis,ArrayView,stack};
+use ndarray::{Array,Ix1,Ix2,ArrayView};
 use std::f64;
-use std::cmp::max;
-use rayon::prelude::*;
 
 use io::Parameters;
 
-use single_pathfinder::Pathfinder;
 use io::Distance;
-use length;
+use weights::Weights;
 
 #[derive(Clone)]
 pub struct Cluster {
     pub id: usize,
-    pub weight: usize,
+    pub weight: f64,
     pub radius: f64,
     pub center: Array<f64,Ix1>,
     pub members: Vec<usize>,
+    pub merge_threshold: Option<f64>,
     pub array: Arc<Array<f64,Ix2>>,
-    distance: Distance
+    distance: Distance,
+    sample_weights: Option<Arc<Weights>>,
 }
 
 impl Cluster {
@@ -27,17 +25,24 @@ impl Cluster {
         let point = points.row(point_id);
         let mut array = Array::zeros((1,point.shape()[0]));
         array.row_mut(0).assign(&point);
+        let sample_weights = parameters.weights.clone();
         Cluster {
-            id: id,
-            weight: 1,
+            id,
+            weight: sample_weights.as_ref().map(|w| w.weight(point_id)).unwrap_or(1.),
             radius: 0.0,
             center: point.to_owned(),
             members: vec![point_id],
+            merge_threshold: None,
             array: points.clone(),
-            distance: parameters.distance.unwrap_or(Distance::Cosine)
+            distance: parameters.distance.unwrap_or(Distance::Cosine),
+            sample_weights,
         }
     }
 
+    fn member_weight(&self, point_id: usize) -> f64 {
+        self.sample_weights.as_ref().map(|w| w.weight(point_id)).unwrap_or(1.)
+    }
+
     pub fn merge_cluster(&self,cluster: &Cluster) -> Cluster {
         let new_weight = self.weight + cluster.weight;
         // let new_center = ((&self.center * self.weight as f64) + (&cluster.center * cluster.weight as f64)) / (new_weight) as f64;
@@ -48,8 +53,13 @@ impl Cluster {
             radius: 0.0,
             center: self.center.clone(),
             members: new_members,
+            merge_threshold: match (self.merge_threshold,cluster.merge_threshold) {
+                (Some(t1),Some(t2)) => Some(t1.max(t2)),
+                (t1,t2) => t1.or(t2),
+            },
             array: self.array.clone(),
-            distance: self.distance
+            distance: self.distance,
+            sample_weights: self.sample_weights.clone(),
         };
         new_cluster.center = new_cluster.center();
         new_cluster.radius = new_cluster.radius();
@@ -58,26 +68,72 @@ impl Cluster {
 
     pub fn merge_point(&mut self ,point: ArrayView<f64,Ix1>, point_id:usize) -> usize {
 
-        self.center *= self.weight as f64/(self.weight as f64 + 1.);
-        self.center.scaled_add(1./(self.weight as f64 + 1.), &point);
-        self.weight += 1;
+        let point_weight = self.member_weight(point_id);
+        let new_weight = self.weight + point_weight;
+
+        if new_weight > 0. {
+            self.center *= self.weight/new_weight;
+            self.center.scaled_add(point_weight/new_weight, &point);
+        }
+        self.weight = new_weight;
         self.members.push(point_id);
         self.radius = self.radius();
         self.id
     }
 
     pub fn radius(&self) -> f64 {
-        let radius = self.members.iter().map(|x| self.distance.measure(self.array.row(*x).view(), self.center.view())).sum::<f64>() / self.weight as f64;
+        if self.weight <= 0. {
+            return 0.
+        }
+        let radius = self.members.iter().map(|x| self.member_weight(*x) * self.distance.measure(self.array.row(*x).view(), self.center.view())).sum::<f64>() / self.weight;
         // eprintln!("R:{:?}",radius);
         radius
     }
 
     pub fn center(&self) -> Array<f64,Ix1> {
-        let mut center = Array::zeros(self.array.shape()[1]);
+        self.center_in(self.array.view())
+    }
+
+    pub fn center_in(&self, points: ArrayView<f64,Ix2>) -> Array<f64,Ix1> {
+        let mut center = Array::zeros(points.shape()[1]);
+        if self.weight <= 0. {
+            return center
+        }
         for i in self.members.iter() {
-            center += &(&self.array.row(*i) / self.weight as f64);
+            center.scaled_add(self.member_weight(*i) / self.weight, &points.row(*i));
         }
         center
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn parameters(weights: Vec<f64>) -> Arc<Parameters> {
+        let mut parameters = Parameters::empty();
+        parameters.distance = Some(Distance::Euclidean);
+        parameters.weights = Some(Arc::new(Weights::new(weights)));
+        Arc::new(parameters)
+    }
+
+    #[test]
+    fn centers_are_weighted() {
+        let points = Arc::new(Array::from_shape_vec((3,2),vec![0.,0.,4.,0.,0.,8.]).unwrap());
+        let parameters = parameters(vec![1.,3.,0.]);
+        let mut cluster = Cluster::init(0,points.clone(),0,parameters.clone());
+        cluster.merge_point(points.row(1),1);
+        assert_eq!(cluster.weight,4.);
+        assert_eq!(cluster.center.to_vec(),vec![3.,0.]);
+        assert_eq!(cluster.radius,(3. + 3. * 1.) / 4.);
+        // A weightless member doesn't move the center
+        let other = Cluster::init(1,points.clone(),2,parameters);
+        let merged = cluster.merge_cluster(&other);
+        assert_eq!(merged.members,vec![0,1,2]);
+        assert_eq!(merged.center.to_vec(),vec![3.,0.]);
+        let shifted = points.map(|x| x + 1.);
+        assert_eq!(merged.center_in(shifted.view()).to_vec(),vec![4.,1.]);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::HashMap;
+
+use ndarray::{Array,Ix1};
+
+use io::AtomicFile;
+
+// Co-association across repeated clustering rounds: how often two samples were
+// given the same cluster label.
+//
+// Samples with exactly the same labels in every round co-occur with each other,
+// and with everyone else, in exactly the same rounds. They are grouped into an
+// atom, and the matrix is stored between atoms only, which stays small even when
+// a cluster holds most of the samples. Noise labels (negative) never co-occur.
+
+#[derive(Debug,Clone)]
+pub struct CoAssociation {
+    pub rounds: usize,
+    pub atoms: Vec<usize>,
+    pub atom_sizes: Vec<usize>,
+    pub counts: HashMap<(usize,usize),usize>,
+}
+
+impl CoAssociation {
+
+    pub fn from_labels(rounds: &[Array<i64,Ix1>]) -> CoAssociation {
+
+        let samples = rounds.first().map(|x| x.len()).unwrap_or(0);
+
+        let mut signatures: HashMap<Vec<i64>,usize> = HashMap::new();
+        let mut atoms = Vec::with_capacity(samples);
+        let mut atom_sizes = vec![];
+        let mut atom_signatures = vec![];
+
+        for i in 0..samples {
+            let signature: Vec<i64> = rounds.iter().map(|labels| labels[i]).collect();
+            let next_atom = signatures.len();
+            let atom = *signatures.entry(signature.clone()).or_insert(next_atom);
+            if atom == next_atom {
+                atom_sizes.push(0);
+                atom_signatures.push(signature);
+            }
+            atom_sizes[atom] += 1;
+            atoms.push(atom);
+        }
+
+        let mut counts: HashMap<(usize,usize),usize> = HashMap::new();
+
+        for round in 0..rounds.len() {
+            let mut clusters: HashMap<i64,Vec<usize>> = HashMap::new();
+            for (atom,signature) in atom_signatures.iter().enumerate() {
+                if signature[round] >= 0 {
+                    clusters.entry(signature[round]).or_default().push(atom);
+                }
+            }
+            for cluster_atoms in clusters.values() {
+                for (k,a) in cluster_atoms.iter().enumerate() {
+                    for b in cluster_atoms[k..].iter() {
+                        *counts.entry((*a,*b)).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        CoAssociation {
+            rounds: rounds.len(),
+            atoms,
+            atom_sizes,
+            counts,
+        }
+    }
+
+    // Links every pair of samples that shared a cluster in at least the threshold
+    // fraction of rounds and labels the connected components, in order of their
+    // first sample.
+
+    pub fn cut(&self, threshold: f64) -> Array<i64,Ix1> {
+
+        let atom_count = self.atom_sizes.len();
+        let mut parents: Vec<usize> = (0..atom_count).collect();
+
+        fn find(parents: &mut [usize], mut x: usize) -> usize {
+            while parents[x] != x {
+                parents[x] = parents[parents[x]];
+                x = parents[x];
+            }
+            x
+        }
+
+        let linked = |count: usize| count as f64 / self.rounds.max(1) as f64 >= threshold;
+
+        for (&(a,b),&count) in self.counts.iter() {
+            if a != b && linked(count) {
+                let (root_a,root_b) = (find(&mut parents,a),find(&mut parents,b));
+                parents[root_a.max(root_b)] = root_a.min(root_b);
+            }
+        }
+
+        let self_linked: Vec<bool> = (0..atom_count).map(|a| linked(*self.counts.get(&(a,a)).unwrap_or(&0))).collect();
+
+        let mut labels = Array::zeros(self.atoms.len());
+        let mut component_labels: HashMap<usize,i64> = HashMap::new();
+        let mut next_label = 0;
+
+        for (i,atom) in self.atoms.iter().enumerate() {
+            // Members of an atom that does not hold together on its own are singletons
+            if !self_linked[*atom] {
+                labels[i] = next_label;
+                next_label += 1;
+                continue
+            }
+            let root = find(&mut parents,*atom);
+            let label = *component_labels.entry(root).or_insert(next_label);
+            if label == next_label {
+                next_label += 1;
+            }
+            labels[i] = label;
+        }
+
+        labels
+    }
+
+    // Writes the atom of every sample, one per line, and the sparse atom by atom
+    // matrix as "atom_a\tatom_b\tfrequency" lines with atom_a <= atom_b.
+
+    pub fn write(&self, atom_location: &str, matrix_location: &str) -> Result<(),Error> {
+
+        let mut atom_file = AtomicFile::create(atom_location)?;
+        for atom in &self.atoms {
+            writeln!(atom_file,"{}",atom)?;
+        }
+        atom_file.commit()?;
+
+        let mut pairs: Vec<(&(usize,usize),&usize)> = self.counts.iter().collect();
+        pairs.sort();
+
+        let mut matrix_file = AtomicFile::create(matrix_location)?;
+        for ((a,b),count) in pairs {
+            writeln!(matrix_file,"{}\t{}\t{:?}",a,b,*count as f64 / self.rounds.max(1) as f64)?;
+        }
+        matrix_file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rounds() -> Vec<Array<i64,Ix1>> {
+        vec![
+            Array::from(vec![0,0,1,1,-1]),
+            Array::from(vec![0,0,1,1,1]),
+            Array::from(vec![0,0,0,1,1]),
+            Array::from(vec![2,2,0,1,1]),
+        ]
+    }
+
+    #[test]
+    fn counts_shared_rounds_between_atoms() {
+        let co_association = CoAssociation::from_labels(&rounds());
+        assert_eq!(co_association.atoms,vec![0,0,1,2,3]);
+        assert_eq!(co_association.atom_sizes,vec![2,1,1,1]);
+        let frequency = |i: usize, j: usize| {
+            let (a,b) = (co_association.atoms[i],co_association.atoms[j]);
+            *co_association.counts.get(&(a.min(b),a.max(b))).unwrap_or(&0) as f64 / 4.
+        };
+        let matrix: Vec<Vec<f64>> = (0..5).map(|i| (0..5).map(|j| frequency(i,j)).collect()).collect();
+        assert_eq!(matrix,vec![
+            vec![1.,1.,0.25,0.,0.],
+            vec![1.,1.,0.25,0.,0.],
+            vec![0.25,0.25,1.,0.5,0.25],
+            vec![0.,0.,0.5,1.,0.75],
+            vec![0.,0.,0.25,0.75,0.75],
+        ]);
+    }
+
+    #[test]
+    fn cuts_at_the_threshold() {
+        let co_association = CoAssociation::from_labels(&rounds());
+        assert_eq!(co_association.cut(0.5).to_vec(),vec![0,0,1,1,1]);
+        assert_eq!(co_association.cut(0.75).to_vec(),vec![0,0,1,2,2]);
+        // The last sample was noise once, so it doesn't even hold together with itself
+        assert_eq!(co_association.cut(1.).to_vec(),vec![0,0,1,2,3]);
+    }
+}
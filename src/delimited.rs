@@ -0,0 +1,304 @@
+use std::io::BufRead;
+use std::collections::{HashSet,HashMap};
+use std::f64;
+
+use ndarray::{Array,Ix2};
+
+use validation::ValidationReport;
+
+// Reader for delimited text matrices, with or without a header row of feature
+// names and a first column of sample names. Blank lines and lines starting with
+// the comment prefix are skipped. Without an explicit delimiter it is guessed from
+// the first line read: tabs, then commas, then semicolons, otherwise whitespace.
+// Cells may be quoted, within a line.
+//
+// Empty cells and the usual spellings of NA are missing values. Rows with a
+// different number of fields than most rows are left out, and any other cells
+// that aren't numbers are read as missing too. Both are listed in the validation
+// report, with their line numbers, for the caller to decide what to do.
+
+// Cells read as missing values rather than reported as unparsable
+const MISSING: &[&str] = &["","NA","na","N/A","null","NULL"];
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Delimiter {
+    Tab,
+    Comma,
+    Semicolon,
+    Whitespace,
+}
+
+impl Delimiter {
+
+    pub fn parse(input: &str) -> Delimiter {
+        match input {
+            "tab" | "\\t" | "\t" => Delimiter::Tab,
+            "comma" | "," => Delimiter::Comma,
+            "semicolon" | ";" => Delimiter::Semicolon,
+            "space" | "whitespace" | " " => Delimiter::Whitespace,
+            _ => panic!("Not a valid delimiter, please choose from tab, comma, semicolon or whitespace"),
+        }
+    }
+
+    pub fn detect(line: &str) -> Delimiter {
+        if line.contains('\t') {
+            Delimiter::Tab
+        }
+        else if line.contains(',') {
+            Delimiter::Comma
+        }
+        else if line.contains(';') {
+            Delimiter::Semicolon
+        }
+        else {
+            Delimiter::Whitespace
+        }
+    }
+
+    fn separates(&self, c: char) -> bool {
+        match self {
+            Delimiter::Tab => c == '\t',
+            Delimiter::Comma => c == ',',
+            Delimiter::Semicolon => c == ';',
+            Delimiter::Whitespace => c.is_whitespace(),
+        }
+    }
+
+    // Cells are quoted as in RFC 4180: a cell starting with a double quote runs to
+    // the next lone quote, delimiters inside it are kept and "" stands for a quote.
+    // Quoted cells can't span lines, so an unclosed quote is an error. Unquoted
+    // cells are trimmed, and runs of whitespace are a single delimiter.
+    pub fn split(&self, line: &str) -> Result<Vec<String>,String> {
+        let mut cells = vec![];
+        let mut cell = String::new();
+        let (mut quoted,mut in_quotes) = (false,false);
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_quotes {
+                if c != '"' {
+                    cell.push(c);
+                }
+                else if chars.peek() == Some(&'"') {
+                    cell.push('"');
+                    chars.next();
+                }
+                else {
+                    in_quotes = false;
+                }
+            }
+            else if self.separates(c) {
+                if *self != Delimiter::Whitespace || quoted || !cell.is_empty() {
+                    cells.push(if quoted { cell.clone() } else { cell.trim().to_string() });
+                }
+                cell.clear();
+                quoted = false;
+            }
+            else if c == '"' && !quoted && cell.trim().is_empty() {
+                cell.clear();
+                quoted = true;
+                in_quotes = true;
+            }
+            else if !(quoted && c.is_whitespace()) {
+                cell.push(c);
+            }
+        }
+        if in_quotes {
+            return Err(format!("Unclosed quote in {:?}",line))
+        }
+        if *self != Delimiter::Whitespace || quoted || !cell.is_empty() {
+            cells.push(if quoted { cell } else { cell.trim().to_string() });
+        }
+        Ok(cells)
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct DelimitedOptions {
+    pub delimiter: Option<Delimiter>,
+    pub comment: String,
+    pub header_row: bool,
+    pub row_names: bool,
+}
+
+impl DelimitedOptions {
+    pub fn new() -> DelimitedOptions {
+        DelimitedOptions {
+            delimiter: None,
+            comment: "#".to_string(),
+            header_row: false,
+            row_names: false,
+        }
+    }
+}
+
+pub struct Delimited {
+    pub counts: Array<f64,Ix2>,
+    pub sample_names: Option<Vec<String>>,
+    pub feature_names: Option<Vec<String>>,
+    pub validation: ValidationReport,
+}
+
+pub fn read_delimited<R: BufRead>(reader: R, options: &DelimitedOptions, verbose: bool) -> Delimited {
+
+    let mut delimiter = options.delimiter;
+
+    let mut header: Option<Vec<String>> = None;
+    let mut sample_names: Vec<String> = vec![];
+    let mut counts: Vec<f64> = Vec::new();
+    let mut samples = 0;
+    let mut validation = ValidationReport::new();
+    let mut sample_lines = vec![];
+
+    // Rows are held until the most common field count is known, so a ragged first
+    // row doesn't make every other row ragged
+    let mut rows: Vec<(usize,String)> = vec![];
+    let mut field_counts: HashMap<usize,(usize,usize)> = HashMap::new();
+
+    for (i,line) in reader.lines().enumerate() {
+
+        let line_number = i + 1;
+        let line = line.expect("Readline error");
+
+        if line.trim().is_empty() || (!options.comment.is_empty() && line.trim_start().starts_with(&options.comment)) {
+            continue
+        }
+
+        let delimiter = *delimiter.get_or_insert_with(|| Delimiter::detect(&line));
+        let cells = delimiter.split(&line).unwrap_or_else(|e| panic!("Line {}: {}",line_number,e));
+
+        if options.header_row && header.is_none() {
+            header = Some(cells);
+            continue
+        }
+
+        field_counts.entry(cells.len()).or_insert((0,rows.len())).0 += 1;
+        rows.push((line_number,line));
+    }
+
+    // Ties go to the count seen first
+    let expected_fields = field_counts.iter()
+        .max_by(|a,b| (a.1).0.cmp(&(b.1).0).then((b.1).1.cmp(&(a.1).1)))
+        .map(|(fields,_)| *fields);
+    let delimiter = delimiter.unwrap_or(Delimiter::Tab);
+
+    for (line_number,line) in rows {
+
+        let mut cells = delimiter.split(&line).expect("Quotes were checked when the row was read");
+
+        let fields = expected_fields.unwrap_or(0);
+        if cells.len() != fields {
+            validation.ragged_rows.push((line_number,cells.len()));
+            continue
+        }
+
+        if options.row_names {
+            sample_names.push(cells.remove(0));
+        }
+
+        let offset = if options.row_names { 2 } else { 1 };
+
+        for (j,cell) in cells.iter().enumerate() {
+            match cell.parse::<f64>() {
+                Ok(value) => counts.push(value),
+                Err(_) if MISSING.contains(&cell.as_str()) => counts.push(f64::NAN),
+                Err(_) => {
+                    validation.non_numeric.push((line_number,j + offset,cell.clone()));
+                    counts.push(f64::NAN);
+                }
+            }
+        }
+
+        sample_lines.push(line_number);
+        samples += 1;
+
+        if samples % 100 == 0 && verbose {
+            eprintln!("{}", samples);
+        }
+    }
+
+    let features = counts.len().checked_div(samples).unwrap_or(0);
+
+    let array = Array::from_shape_vec((samples,features),counts).expect("Ragged rows should have been left out");
+
+    validation.expected_fields = expected_fields.unwrap_or(0);
+    validation.sample_lines = Some(sample_lines);
+
+    // A header can have a leading cell above the sample names, or not, as R writes it
+    let feature_names = header.map(|mut header| {
+        if options.row_names && header.len() == features + 1 {
+            header.remove(0);
+        }
+        deduplicate(header)
+    });
+
+    if verbose {
+        eprintln!("===========");
+        eprintln!("{},{}", array.shape()[0], array.shape()[1]);
+    }
+
+    Delimited {
+        counts: array,
+        sample_names: if options.row_names { Some(sample_names) } else { None },
+        feature_names,
+        validation,
+    }
+}
+
+// Renames repeated names the way read_header does, by appending a counter
+
+pub fn deduplicate(names: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    names.into_iter().map(|name| {
+        let mut renamed = name.clone();
+        let mut j = 1;
+        while seen.contains(&renamed) {
+            renamed = [name.clone(),j.to_string()].join("");
+            j += 1;
+        }
+        if renamed != name {
+            eprintln!("WARNING: Two individual features were named the same thing: {}",name);
+        }
+        seen.insert(renamed.clone());
+        renamed
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn splits_quoted_cells() {
+        assert_eq!(Delimiter::Comma.split(r#" "a, b" ,"say ""hi""",,3 "#).unwrap(),vec!["a, b","say \"hi\"","","3"]);
+        assert_eq!(Delimiter::Tab.split("\"x\ty\"\t 2").unwrap(),vec!["x\ty","2"]);
+        assert_eq!(Delimiter::Whitespace.split("  \"a b\"   \"\"  c ").unwrap(),vec!["a b","","c"]);
+        assert!(Delimiter::Comma.split("1,\"2,3").is_err());
+    }
+
+    #[test]
+    fn reads_quoted_names() {
+        let text = "\"\",\"gene, 1\",\"gene 2\"\n\"cell \"\"a\"\"\",1,2\n";
+        let options = DelimitedOptions { header_row: true, row_names: true, ..DelimitedOptions::new() };
+        let delimited = read_delimited(text.as_bytes(),&options,false);
+        assert_eq!(delimited.feature_names,Some(vec!["gene, 1".to_string(),"gene 2".to_string()]));
+        assert_eq!(delimited.sample_names,Some(vec!["cell \"a\"".to_string()]));
+        assert_eq!(delimited.counts.row(0).to_vec(),vec![1.,2.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unclosed quote")]
+    fn panics_on_unclosed_quotes() {
+        read_delimited("1,2\n\"3,4\n".as_bytes(),&DelimitedOptions::new(),false);
+    }
+
+    #[test]
+    fn ragged_rows_are_judged_by_the_most_common_field_count() {
+        let text = "1\t2\t3\t4\n5\t6\t7\n8\t9\t10\n11\t12\t13\n";
+        let delimited = read_delimited(text.as_bytes(),&DelimitedOptions::new(),false);
+        assert_eq!(delimited.counts.dim(),(3,3));
+        assert_eq!(delimited.validation.expected_fields,3);
+        assert_eq!(delimited.validation.ragged_rows,vec![(1,4)]);
+        assert_eq!(delimited.validation.sample_lines,Some(vec![2,3,4]));
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::Error;
+use std::io::prelude::*;
+
+use io::AtomicFile;
+
+// The history of GravityField::merge_clusters. Leaves are the coarse clusters
+// found by cluster_points, numbered 0..leaves in the order they were found, and
+// the k-th merge creates node leaves + k, as in a scipy linkage matrix.
+//
+// Once no more clusters are close enough to merge, the remaining ones are still
+// joined pairwise by closest centers, so that the hierarchy has a single root and
+// can be cut at any level. Those merges are marked as beyond the threshold.
+
+#[derive(Debug,Clone)]
+pub struct MergeRecord {
+    pub first: usize,
+    pub second: usize,
+    pub distance: f64,
+    pub threshold: f64,
+    pub size: usize,
+    pub within_threshold: bool,
+}
+
+#[derive(Debug,Clone)]
+pub struct Dendrogram {
+    pub leaves: usize,
+    pub merges: Vec<MergeRecord>,
+}
+
+impl Dendrogram {
+
+    pub fn new(leaves: usize) -> Dendrogram {
+        Dendrogram {
+            leaves,
+            merges: vec![],
+        }
+    }
+
+    /// Records a merge and returns the id of the new node
+    pub fn record(&mut self, record: MergeRecord) -> usize {
+        self.merges.push(record);
+        self.leaves + self.merges.len() - 1
+    }
+
+    // Merge heights are center distances, raised where needed so that they never
+    // decrease going up the tree, which scipy and most tree viewers expect.
+
+    fn heights(&self) -> Vec<f64> {
+        let mut height = 0.;
+        self.merges.iter().map(|merge| {
+            height = merge.distance.max(height);
+            height
+        }).collect()
+    }
+
+    /// scipy.cluster.hierarchy style rows: first, second, height, size
+    pub fn linkage(&self) -> Vec<(usize,usize,f64,usize)> {
+        self.merges.iter().zip(self.heights()).map(|(merge,height)| {
+            (merge.first.min(merge.second),merge.first.max(merge.second),height,merge.size)
+        }).collect()
+    }
+
+    pub fn newick(&self) -> String {
+
+        if self.leaves == 0 {
+            return ";".to_string()
+        }
+
+        let heights = self.heights();
+        let node_height = |node: usize| if node < self.leaves { 0. } else { heights[node - self.leaves] };
+
+        let mut subtrees: Vec<Option<String>> = (0..self.leaves).map(|i| Some(format!("C{}",i))).collect();
+
+        for (merge,height) in self.merges.iter().zip(heights.iter()) {
+            let first = subtrees[merge.first].take().unwrap();
+            let second = subtrees[merge.second].take().unwrap();
+            subtrees.push(Some(format!(
+                "({}:{:?},{}:{:?})",
+                first,height - node_height(merge.first),
+                second,height - node_height(merge.second),
+            )));
+        }
+
+        let root = subtrees.into_iter().rev().find_map(|x| x).unwrap_or_default();
+
+        format!("{};",root)
+    }
+
+    pub fn write(&self, log_location: &str, linkage_location: &str, newick_location: &str) -> Result<(),Error> {
+
+        let mut log_file = AtomicFile::create(log_location)?;
+        writeln!(log_file,"node\tfirst\tsecond\tdistance\tthreshold\tsize\twithin_threshold")?;
+        for (k,merge) in self.merges.iter().enumerate() {
+            writeln!(log_file,"{}\t{}\t{}\t{:?}\t{:?}\t{}\t{}",self.leaves + k,merge.first,merge.second,merge.distance,merge.threshold,merge.size,merge.within_threshold)?;
+        }
+        log_file.commit()?;
+
+        let mut linkage_file = AtomicFile::create(linkage_location)?;
+        for (first,second,height,size) in self.linkage() {
+            writeln!(linkage_file,"{}\t{}\t{:?}\t{}",first,second,height,size)?;
+        }
+        linkage_file.commit()?;
+
+        let mut newick_file = AtomicFile::create(newick_location)?;
+        writeln!(newick_file,"{}",self.newick())?;
+        newick_file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn merge(first: usize, second: usize, distance: f64, size: usize) -> MergeRecord {
+        MergeRecord { first, second, distance, threshold: 1., size, within_threshold: distance < 1. }
+    }
+
+    fn history() -> Dendrogram {
+        let mut dendrogram = Dendrogram::new(4);
+        assert_eq!(dendrogram.record(merge(2,0,0.5,2)),4);
+        // Closer than the merge before it, so its height is raised
+        assert_eq!(dendrogram.record(merge(1,3,0.25,2)),5);
+        assert_eq!(dendrogram.record(merge(4,5,2.,4)),6);
+        dendrogram
+    }
+
+    #[test]
+    fn linkage_rows_are_sorted_and_monotonic() {
+        assert_eq!(history().linkage(),vec![(0,2,0.5,2),(1,3,0.5,2),(4,5,2.,4)]);
+    }
+
+    #[test]
+    fn newick_branch_lengths_are_height_differences() {
+        assert_eq!(history().newick(),"((C2:0.5,C0:0.5):1.5,(C1:0.5,C3:0.5):1.5);");
+        assert_eq!(Dendrogram::new(1).newick(),"C0;");
+        assert_eq!(Dendrogram::new(0).newick(),";");
+    }
+}
@@ -0,0 +1,140 @@
+use std::f64;
+use std::cmp::Ordering;
+
+use ndarray::{Array,Ix1,Ix2};
+use rayon::prelude::*;
+
+use io::Distance;
+use binary::Points;
+use sparse::{CsrMatrix,Rows};
+
+// Smoothing across similar samples, as in MAGIC. Every sample is linked to its k
+// nearest samples under the configured distance, with a Gaussian affinity whose
+// width is the distance to its k-th neighbor, so dense and sparse regions of the
+// data are treated alike. The affinities are made symmetric, every sample is linked
+// to itself, and rows are normalized into a Markov transition matrix. Multiplying
+// the counts by it t times replaces every sample by an average over the samples a
+// t step random walk reaches from it.
+//
+// The result is dense, but sparse counts are read as they are while the graph is
+// built and for the first step.
+
+pub fn diffuse(points: Points, k: usize, steps: usize, distance: Distance) -> Points {
+
+    let (samples,features) = points.shape();
+    let transitions = transition_matrix(points.rows(),k,distance);
+
+    let mut smoothed: Array<f64,Ix2> = multiply(&transitions,points.rows(),features);
+    for _ in 1..steps {
+        smoothed = multiply(&transitions,Rows::Dense(smoothed.view()),features);
+    }
+
+    eprintln!("Diffused {} samples over {} neighbors for {} steps",samples,k,steps);
+
+    smoothed.into()
+}
+
+fn transition_matrix(rows: Rows, k: usize, distance: Distance) -> CsrMatrix {
+
+    let samples = rows.rows();
+    if k >= samples {
+        panic!("Diffusion needs fewer neighbors than samples, asked for {} of {}",k,samples);
+    }
+
+    let neighbors: Vec<Vec<(usize,f64)>> = (0..samples).into_par_iter().map(|i| {
+        let point = rows.row(i);
+        let norm = rows.norm(i);
+        let mut distances: Vec<(usize,f64)> = (0..samples)
+            .filter(|j| *j != i)
+            .map(|j| (j,rows.measure_from(distance,point.view(),norm,j)))
+            // Cosine to an all zero sample is NaN, so those samples only keep their self loop
+            .filter(|(_,d)| d.is_finite())
+            .map(|(j,d)| (j,d.max(0.)))
+            .collect();
+        distances.sort_by(|a,b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        distances.truncate(k);
+        distances
+    }).collect();
+
+    let mut triplets = Vec::with_capacity(samples * (2 * k + 1));
+    for (i,nearest) in neighbors.iter().enumerate() {
+        let width = nearest.last().map(|(_,d)| *d).unwrap_or(0.);
+        triplets.push((i,i,1.));
+        for (j,d) in nearest {
+            let affinity = if width > 0. { (-(d / width).powi(2)).exp() } else { 1. };
+            // Half from each end, summed when both samples are neighbors of each other
+            triplets.push((i,*j,affinity / 2.));
+            triplets.push((*j,i,affinity / 2.));
+        }
+    }
+
+    let mut transitions = CsrMatrix::from_triplets(samples,samples,triplets);
+    for i in 0..samples {
+        let (start,end) = (transitions.indptr[i],transitions.indptr[i + 1]);
+        let total: f64 = transitions.data[start..end].iter().sum();
+        transitions.data[start..end].iter_mut().for_each(|x| *x /= total);
+    }
+
+    transitions
+}
+
+fn multiply(transitions: &CsrMatrix, rows: Rows, features: usize) -> Array<f64,Ix2> {
+    let smoothed: Vec<Array<f64,Ix1>> = (0..transitions.rows).into_par_iter().map(|i| {
+        let mut row = Array::zeros(features);
+        let transition = transitions.row(i);
+        for (j,p) in transition.indices.iter().zip(transition.data.iter()) {
+            rows.add_row(&mut row,*j,*p);
+        }
+        row
+    }).collect();
+    let mut matrix = Array::zeros((transitions.rows,features));
+    for (i,row) in smoothed.into_iter().enumerate() {
+        matrix.row_mut(i).assign(&row);
+    }
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn counts() -> Array<f64,Ix2> {
+        Array::from_shape_vec((5,2),vec![0.,1.,0.1,1.,0.2,1.,5.,1.,5.3,1.]).unwrap()
+    }
+
+    fn entries(transitions: &CsrMatrix, i: usize) -> Vec<(usize,f64)> {
+        let row = transitions.row(i);
+        row.indices.iter().cloned().zip(row.data.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn transitions_sum_to_one() {
+        let transitions = transition_matrix(Rows::Dense(counts().view()),2,Distance::Euclidean);
+        for i in 0..5 {
+            assert!((entries(&transitions,i).iter().map(|(_,p)| p).sum::<f64>() - 1.).abs() < 1e-12);
+        }
+        // The two far samples are each other's nearest neighbors
+        let reached: Vec<usize> = entries(&transitions,3).iter().map(|(j,_)| *j).collect();
+        assert!(reached.contains(&3) && reached.contains(&4));
+        // A constant feature stays constant however many steps are taken
+        let diffused = diffuse(counts().into(),2,3,Distance::Euclidean).into_owned();
+        assert!(diffused.column(1).iter().all(|x| (x - 1.).abs() < 1e-12));
+        let (low,high) = (diffused[[0,0]],diffused[[4,0]]);
+        assert!(low > 0. && high < 5.3);
+    }
+
+    #[test]
+    fn undefined_distances_are_ignored() {
+        // Cosine distance to the all zero sample is NaN
+        let counts = Array::from_shape_vec((4,2),vec![1.,0.,1.,0.1,0.,0.,0.,1.]).unwrap();
+        let transitions = transition_matrix(Rows::Dense(counts.view()),2,Distance::Cosine);
+        assert_eq!(entries(&transitions,2),vec![(2,1.)]);
+        for i in [0,1,3].iter() {
+            assert!(entries(&transitions,*i).iter().all(|(j,_)| *j != 2));
+        }
+        let diffused = diffuse(counts.into(),2,2,Distance::Cosine).into_owned();
+        assert_eq!(diffused.row(2).to_vec(),vec![0.,0.]);
+        assert!(!diffused.iter().any(|x| x.is_nan()));
+    }
+}
@@ -0,0 +1,345 @@
+use std::fs;
+use std::io::{Error,ErrorKind};
+use std::collections::HashMap;
+use std::cmp::Ordering;
+
+use ndarray::{Array,Axis,Ix2};
+
+// Flow Cytometry Standard 3.0/3.1 files. A 58 byte HEADER gives the offsets of the
+// TEXT and DATA segments as right-justified ASCII. TEXT is a list of keyword/value
+// pairs split by a delimiter, its first byte, with doubled delimiters standing for
+// the character itself. DATA is events x parameters in list mode, as 32 or 64 bit
+// floats or as unsigned integers of $PnB bits, masked to $PnR.
+//
+// Channels are named by $PnN, and can be selected either by $PnN or by the marker
+// name in $PnS. A $SPILLOVER matrix, when present, can be used to compensate the
+// channels it lists, before the optional arcsinh(x / cofactor).
+
+#[derive(Debug,Clone)]
+pub struct FcsOptions {
+    pub channels: Option<Vec<String>>,
+    pub cofactor: Option<f64>,
+    pub compensate: bool,
+}
+
+impl FcsOptions {
+    pub fn new() -> FcsOptions {
+        FcsOptions {
+            channels: None,
+            cofactor: None,
+            compensate: false,
+        }
+    }
+}
+
+pub struct Fcs {
+    pub counts: Array<f64,Ix2>,
+    pub channels: Vec<String>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData,message)
+}
+
+fn header_offset(bytes: &[u8], start: usize) -> Result<usize,Error> {
+    let field = bytes.get(start..start + 8).ok_or_else(|| invalid("Truncated FCS header".to_string()))?;
+    let field = String::from_utf8_lossy(field);
+    let field = field.trim();
+    if field.is_empty() {
+        return Ok(0)
+    }
+    field.parse::<usize>().map_err(|_| invalid(format!("Bad FCS header offset {:?}",field)))
+}
+
+fn parse_text(text: &[u8]) -> Result<HashMap<String,String>,Error> {
+
+    let delimiter = *text.first().ok_or_else(|| invalid("Empty FCS TEXT segment".to_string()))?;
+
+    let mut tokens: Vec<String> = vec![];
+    let mut current: Vec<u8> = vec![];
+    let mut i = 1;
+    while i < text.len() {
+        if text[i] == delimiter {
+            if text.get(i + 1) == Some(&delimiter) {
+                current.push(delimiter);
+                i += 2;
+                continue
+            }
+            tokens.push(String::from_utf8_lossy(&current).into_owned());
+            current.clear();
+        }
+        else {
+            current.push(text[i]);
+        }
+        i += 1;
+    }
+    if !current.is_empty() {
+        tokens.push(String::from_utf8_lossy(&current).into_owned());
+    }
+
+    Ok(tokens.chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| (pair[0].trim().to_uppercase(),pair[1].trim().to_string()))
+        .collect())
+}
+
+pub fn read_fcs(location: &str, options: &FcsOptions, verbose: bool) -> Result<Fcs,Error> {
+
+    let bytes = fs::read(location)?;
+
+    let version = String::from_utf8_lossy(bytes.get(..6).ok_or_else(|| invalid("Truncated FCS header".to_string()))?).into_owned();
+    if !version.starts_with("FCS") {
+        return Err(invalid(format!("Not an FCS file: {}",location)))
+    }
+    if version != "FCS3.0" && version != "FCS3.1" {
+        eprintln!("WARNING: {} is {}, only FCS3.0 and FCS3.1 are supported, reading it anyway",location,version);
+    }
+
+    let (text_start,text_end) = (header_offset(&bytes,10)?,header_offset(&bytes,18)?);
+    let text = bytes.get(text_start..=text_end).ok_or_else(|| invalid("FCS TEXT segment is outside of the file".to_string()))?;
+    let keywords = parse_text(text)?;
+
+    let keyword = |key: &str| keywords.get(key).ok_or_else(|| invalid(format!("FCS file has no {} keyword",key)));
+    let number = |key: &str| keyword(key).and_then(|x| x.parse::<usize>().map_err(|_| invalid(format!("Bad FCS {} keyword: {:?}",key,x))));
+
+    // Large files leave the header offsets at 0 and put them in TEXT
+    let (mut data_start,mut data_end) = (header_offset(&bytes,26)?,header_offset(&bytes,34)?);
+    if data_start == 0 && data_end == 0 {
+        data_start = number("$BEGINDATA")?;
+        data_end = number("$ENDDATA")?;
+    }
+
+    let parameters = number("$PAR")?;
+    let events = number("$TOT")?;
+    let datatype = keyword("$DATATYPE")?.to_uppercase();
+    if keywords.get("$MODE").map(|x| x.to_uppercase()).unwrap_or_else(|| "L".to_string()) != "L" {
+        return Err(invalid("Only list mode FCS data is supported".to_string()))
+    }
+    let big_endian = match keyword("$BYTEORD")?.replace(' ',"").as_str() {
+        "1,2,3,4" | "1,2" | "1,2,3,4,5,6,7,8" => false,
+        "4,3,2,1" | "2,1" | "8,7,6,5,4,3,2,1" => true,
+        other => return Err(invalid(format!("Unsupported FCS byte order {:?}",other))),
+    };
+
+    let names: Vec<String> = (1..=parameters).map(|p| keywords.get(&format!("$P{}N",p)).cloned().unwrap_or_else(|| format!("P{}",p))).collect();
+    let markers: Vec<Option<String>> = (1..=parameters).map(|p| keywords.get(&format!("$P{}S",p)).cloned().filter(|x| !x.is_empty())).collect();
+
+    let widths: Vec<usize> = match datatype.as_str() {
+        "F" => vec![4; parameters],
+        "D" => vec![8; parameters],
+        "I" => (1..=parameters).map(|p| {
+            let bits = number(&format!("$P{}B",p))?;
+            if ![8,16,32,64].contains(&bits) {
+                return Err(invalid(format!("Unsupported FCS integer width of {} bits for parameter {}",bits,p)))
+            }
+            Ok(bits / 8)
+        }).collect::<Result<Vec<usize>,Error>>()?,
+        other => return Err(invalid(format!("Unsupported FCS data type {:?}, expected I, F or D",other))),
+    };
+
+    // Integer values only use the bits up to their range
+    let masks: Vec<u64> = (1..=parameters).map(|p| {
+        keywords.get(&format!("$P{}R",p))
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|range| *range >= 1. && *range < 2f64.powi(63))
+            .map(|range| (range as u64).next_power_of_two() - 1)
+            .unwrap_or(u64::MAX)
+    }).collect();
+
+    let event_width: usize = widths.iter().sum();
+    let data_length = events.checked_mul(event_width)
+        .and_then(|length| length.checked_add(data_start))
+        .ok_or_else(|| invalid(format!("FCS header is corrupt, {} events of {} bytes is too large",events,event_width)))?;
+    let data = bytes.get(data_start..data_length)
+        .filter(|_| data_end.saturating_add(1) >= data_length)
+        .ok_or_else(|| invalid(format!("FCS DATA segment is too short for {} events of {} bytes",events,event_width)))?;
+
+    let mut values: Vec<f64> = Vec::with_capacity(events * parameters);
+    let mut offset = 0;
+    for _ in 0..events {
+        for p in 0..parameters {
+            let width = widths[p];
+            let mut buffer = [0u8; 8];
+            buffer[..width].copy_from_slice(&data[offset..offset + width]);
+            if big_endian {
+                buffer[..width].reverse();
+            }
+            values.push(match datatype.as_str() {
+                "F" => f32::from_le_bytes([buffer[0],buffer[1],buffer[2],buffer[3]]) as f64,
+                "D" => f64::from_le_bytes(buffer),
+                _ => (u64::from_le_bytes(buffer) & masks[p]) as f64,
+            });
+            offset += width;
+        }
+    }
+
+    let mut counts = Array::from_shape_vec((events,parameters),values).map_err(|e| invalid(format!("{:?}",e)))?;
+
+    if verbose {
+        eprintln!("FCS: {} events, {} channels ({}, {})",events,parameters,datatype,if big_endian { "big-endian" } else { "little-endian" });
+    }
+
+    if options.compensate {
+        match keywords.get("$SPILLOVER").or_else(|| keywords.get("SPILL")).or_else(|| keywords.get("$SPILL")) {
+            Some(spillover) => compensate(&mut counts,spillover,&names)?,
+            None => eprintln!("WARNING: {} has no spillover matrix, leaving it uncompensated",location),
+        }
+    }
+
+    let selected: Vec<usize> = match options.channels.as_ref() {
+        Some(channels) => channels.iter().map(|channel| {
+            (0..parameters)
+                .find(|p| names[*p] == *channel || markers[*p].as_ref() == Some(channel))
+                .ok_or_else(|| invalid(format!("No channel {:?} in {}, found {:?}",channel,location,names)))
+        }).collect::<Result<Vec<usize>,Error>>()?,
+        None => (0..parameters).collect(),
+    };
+
+    let mut counts = counts.select(Axis(1),&selected);
+    let channels: Vec<String> = selected.iter().map(|p| names[*p].clone()).collect();
+
+    if let Some(cofactor) = options.cofactor {
+        counts.mapv_inplace(|x| (x / cofactor).asinh());
+    }
+
+    Ok(Fcs { counts, channels })
+}
+
+// The spillover keyword is "n,channel_1,...,channel_n,s_11,s_12,...,s_nn", row major.
+// Observed values are true values times the spillover matrix, so the listed channels
+// are multiplied by its inverse.
+
+fn compensate(counts: &mut Array<f64,Ix2>, spillover: &str, names: &[String]) -> Result<(),Error> {
+
+    let fields: Vec<&str> = spillover.split(',').map(|x| x.trim()).collect();
+    let n = fields.first().and_then(|x| x.parse::<usize>().ok()).ok_or_else(|| invalid(format!("Bad spillover matrix {:?}",spillover)))?;
+    if fields.len() != 1 + n + n * n {
+        return Err(invalid(format!("Spillover matrix lists {} fields, expected {}",fields.len(),1 + n + n * n)))
+    }
+
+    let columns: Vec<usize> = fields[1..=n].iter().map(|channel| {
+        names.iter().position(|x| x == channel).ok_or_else(|| invalid(format!("Spillover channel {:?} is not in the file",channel)))
+    }).collect::<Result<Vec<usize>,Error>>()?;
+
+    let values: Vec<f64> = fields[n + 1..].iter()
+        .map(|x| x.parse::<f64>().map_err(|_| invalid(format!("Bad spillover value {:?}",x))))
+        .collect::<Result<Vec<f64>,Error>>()?;
+    let spill = Array::from_shape_vec((n,n),values).unwrap();
+    let inverse = invert(&spill).ok_or_else(|| invalid("The spillover matrix is singular".to_string()))?;
+
+    let compensated = counts.select(Axis(1),&columns).dot(&inverse);
+    for (k,column) in columns.iter().enumerate() {
+        counts.column_mut(*column).assign(&compensated.column(k));
+    }
+
+    eprintln!("Compensated {} channels",n);
+
+    Ok(())
+}
+
+// Gauss-Jordan elimination with partial pivoting, spillover matrices are small
+fn invert(matrix: &Array<f64,Ix2>) -> Option<Array<f64,Ix2>> {
+    let n = matrix.rows();
+    let mut left = matrix.clone();
+    let mut right: Array<f64,Ix2> = Array::eye(n);
+    for column in 0..n {
+        let pivot = (column..n).max_by(|a,b| left[[*a,column]].abs().partial_cmp(&left[[*b,column]].abs()).unwrap_or(Ordering::Equal))?;
+        if left[[pivot,column]].abs() < 1e-12 {
+            return None
+        }
+        for j in 0..n {
+            left.swap([column,j],[pivot,j]);
+            right.swap([column,j],[pivot,j]);
+        }
+        let scale = left[[column,column]];
+        left.row_mut(column).mapv_inplace(|x| x / scale);
+        right.row_mut(column).mapv_inplace(|x| x / scale);
+        for i in 0..n {
+            if i != column {
+                let factor = left[[i,column]];
+                if factor != 0. {
+                    let (pivot_left,pivot_right) = (left.row(column).to_owned(),right.row(column).to_owned());
+                    left.row_mut(i).scaled_add(-factor,&pivot_left);
+                    right.row_mut(i).scaled_add(-factor,&pivot_right);
+                }
+            }
+        }
+    }
+    Some(right)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+
+    // A minimal FCS3.0 file with TEXT right after the header and DATA after TEXT
+    fn write_fcs(name: &str, keywords: &[(&str,&str)], data: &[u8]) -> String {
+        let mut text = String::from("/");
+        for (key,value) in keywords {
+            text.push_str(&format!("{}/{}/",key,value));
+        }
+        let (text_start,text_end) = (58,58 + text.len() - 1);
+        let (data_start,data_end) = (text_end + 1,text_end + data.len());
+        let mut bytes = format!("FCS3.0    {:>8}{:>8}{:>8}{:>8}{:>8}{:>8}",text_start,text_end,data_start,data_end,0,0).into_bytes();
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.extend_from_slice(data);
+        let location = env::temp_dir().join(format!("gravity_{}_{}.fcs",name,std::process::id()));
+        fs::write(&location,bytes).unwrap();
+        location.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn reads_float_events_by_name_and_marker() {
+        let values: [f32; 6] = [1.,2.,3.,4.,5.,6.];
+        let data: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        let location = write_fcs("float",&[("$PAR","2"),("$TOT","3"),("$DATATYPE","F"),("$MODE","L"),("$BYTEORD","1,2,3,4"),("$P1N","FSC-A"),("$P2N","FL1-A"),("$P2S","CD3")],&data);
+
+        let fcs = read_fcs(&location,&FcsOptions::new(),false).unwrap();
+        assert_eq!(fcs.channels,vec!["FSC-A","FL1-A"]);
+        assert_eq!(fcs.counts,Array::from_shape_vec((3,2),vec![1.,2.,3.,4.,5.,6.]).unwrap());
+
+        let options = FcsOptions { channels: Some(vec!["CD3".to_string()]), cofactor: Some(2.), compensate: false };
+        let fcs = read_fcs(&location,&options,false).unwrap();
+        fs::remove_file(&location).unwrap();
+        assert_eq!(fcs.channels,vec!["FL1-A"]);
+        assert_eq!(fcs.counts.column(0).to_vec(),vec![1f64.asinh(),2f64.asinh(),3f64.asinh()]);
+    }
+
+    #[test]
+    fn masks_big_endian_integers_to_their_range() {
+        let data: Vec<u8> = [0x0405u16,0xF00A].iter().flat_map(|x| x.to_be_bytes().to_vec()).collect();
+        let location = write_fcs("integer",&[("$PAR","1"),("$TOT","2"),("$DATATYPE","I"),("$BYTEORD","2,1"),("$P1B","16"),("$P1R","1024"),("$P1N","FSC-A")],&data);
+        let fcs = read_fcs(&location,&FcsOptions::new(),false).unwrap();
+        fs::remove_file(&location).unwrap();
+        assert_eq!(fcs.counts.column(0).to_vec(),vec![5.,10.]);
+    }
+
+    #[test]
+    fn rejects_a_short_data_segment() {
+        let location = write_fcs("short",&[("$PAR","2"),("$TOT","3"),("$DATATYPE","F"),("$BYTEORD","1,2,3,4")],&[0; 20]);
+        let error = read_fcs(&location,&FcsOptions::new(),false).err().unwrap();
+        fs::remove_file(&location).unwrap();
+        assert_eq!(error.kind(),ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compensates_by_the_inverse_spillover() {
+        let spill = Array::from_shape_vec((2,2),vec![1.,0.1,0.2,1.]).unwrap();
+        let truth = Array::from_shape_vec((2,2),vec![10.,20.,30.,40.]).unwrap();
+        let mut counts = truth.dot(&spill);
+        compensate(&mut counts,"2,A,B,1,0.1,0.2,1",&["A".to_string(),"B".to_string()]).unwrap();
+        assert!(counts.iter().zip(truth.iter()).all(|(a,b)| (a - b).abs() < 1e-9));
+    }
+
+    #[test]
+    fn inverts_a_known_matrix() {
+        // Needs a row swap to find its first pivot
+        let matrix = Array::from_shape_vec((3,3),vec![0.,1.,2.,1.,0.,3.,4.,-3.,8.]).unwrap();
+        let expected = Array::from_shape_vec((3,3),vec![-4.5,7.,-1.5,-2.,4.,-1.,1.5,-2.,0.5]).unwrap();
+        let inverse = invert(&matrix).unwrap();
+        assert!(inverse.iter().zip(expected.iter()).all(|(a,b)| (a - b).abs() < 1e-12));
+        assert!(invert(&Array::from_shape_vec((2,2),vec![1.,2.,2.,4.]).unwrap()).is_none());
+    }
+}
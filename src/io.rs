@@ -1,6 +1,8 @@
+use std::fs;
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Error;
+use std::io::{Error,BufWriter};
+use std::path::{Path,PathBuf};
+use std::process;
 
 use std::io;
 use std::io::prelude::*;
@@ -8,10 +10,25 @@ use std::collections::HashMap;
 use num_cpus;
 use std::f64;
 use std::fmt::Debug;
-use rayon::iter::IntoParallelIterator;
-use std::cmp::Ordering;
 
 use ndarray::{Array,ArrayView,Ix1,Ix2,Axis};
+use binary::{Points,DType,read_binary};
+use weights::{Weights,read_weights};
+use delimited::{Delimited,DelimitedOptions,Delimiter,read_delimited};
+use validation::{ValidationReport,Policy};
+use missing::Impute;
+use sparse::read_mtx_file;
+use tenx::read_10x;
+use preprocess::Step;
+use selection::Dispersion;
+use sanitize::Sanitize;
+use pca::PcaTarget;
+use projection::Projection;
+use rand::{SeedableRng,FromEntropy};
+use rand::rngs::StdRng;
+use fcs::{FcsOptions,read_fcs};
+use npy::{NpyElement,read_npy,read_npz,write_npy};
+use std::sync::Arc;
 // use ndarray_linalg::*;
 
 
@@ -20,11 +37,13 @@ pub struct Parameters {
     auto: bool,
     pub verbose: bool,
     pub command: Command,
-    pub counts: Option<Array<f64,Ix2>>,
+    pub counts: Option<Points>,
     pub feature_names: Option<Vec<String>>,
     pub sample_names: Option<Vec<String>>,
     pub report_address: Option<String>,
     pub dump_error: Option<String>,
+    pub output_dir: Option<String>,
+    pub overwrite: bool,
 
     pub feature_subsample: Option<usize>,
     pub sample_subsample: Option<usize>,
@@ -36,14 +55,45 @@ pub struct Parameters {
     pub smoothing: Option<usize>,
     pub distance: Option<Distance>,
     pub borrow: Option<usize>,
-    pub standardize: bool,
+    pub borrow_neighbors: Option<usize>,
+    pub sanitize: Option<Sanitize>,
+    pub collinear: Option<f64>,
+    pub preprocess: Vec<Step>,
+    pub preprocess_from: Option<String>,
+    pub pca: Option<PcaTarget>,
+    pub seed: Option<u64>,
+    pub projection: Projection,
+    pub project: Option<usize>,
+    pub diffuse: Option<usize>,
+    pub diffuse_k: Option<usize>,
     pub fuzz: Option<usize>,
     pub step_fraction: Option<f64>,
     pub steps: Option<usize>,
+    pub collapse: Option<f64>,
+    pub weights: Option<Arc<Weights>>,
+    pub weighted_subsample: bool,
+    pub min_cluster_size: Option<f64>,
+    pub max_fuzz: Option<f64>,
+    pub max_displacement: Option<f64>,
+    pub memberships: bool,
+    pub consensus: Option<f64>,
+    pub sweep: Option<Vec<usize>>,
+    pub dtype: DType,
+    pub delimited: DelimitedOptions,
+    pub validation: Option<ValidationReport>,
+    pub policy: Policy,
+    pub max_missing: Option<f64>,
+    pub impute: Option<Impute>,
+    pub npz_key: Option<String>,
+    pub npy_output: bool,
+    pub fcs: FcsOptions,
 
     count_array_file: String,
     feature_header_file: Option<String>,
     sample_header_file: Option<String>,
+    impute_k: Option<usize>,
+    count_format: Option<CountFormat>,
+    weight_column: Option<usize>,
 
     processor_limit: Option<usize>,
 
@@ -52,7 +102,8 @@ pub struct Parameters {
 impl Parameters {
 
     pub fn empty() -> Parameters {
-        let arg_struct = Parameters {
+        
+        Parameters {
             auto: false,
             verbose: false,
             command: Command::FitPredict,
@@ -61,15 +112,48 @@ impl Parameters {
             feature_header_file: None,
             feature_names: None,
             sample_header_file: None,
+            impute_k: None,
+            count_format: None,
+            weight_column: None,
             sample_names: None,
             report_address: None,
             dump_error: None,
+            output_dir: None,
+            overwrite: true,
             distance: None,
             borrow: None,
-            standardize: false,
+            borrow_neighbors: None,
+            sanitize: None,
+            collinear: None,
+            preprocess: vec![],
+            preprocess_from: None,
+            pca: None,
+            seed: None,
+            projection: Projection::Gaussian,
+            project: None,
+            diffuse: None,
+            diffuse_k: None,
             fuzz: None,
             step_fraction: None,
             steps: None,
+            collapse: None,
+            weights: None,
+            weighted_subsample: false,
+            min_cluster_size: None,
+            max_fuzz: None,
+            max_displacement: None,
+            memberships: false,
+            consensus: None,
+            sweep: None,
+            dtype: DType::F64,
+            delimited: DelimitedOptions::new(),
+            validation: None,
+            policy: Policy::Default,
+            max_missing: None,
+            impute: None,
+            npz_key: None,
+            npy_output: false,
+            fcs: FcsOptions::new(),
 
             processor_limit: None,
 
@@ -83,8 +167,7 @@ impl Parameters {
 
             scaling_factor: None,
 
-        };
-        arg_struct
+        }
     }
 
     pub fn read<T: Iterator<Item = String>>(args: &mut T) -> Parameters {
@@ -108,17 +191,85 @@ impl Parameters {
                 },
                 "-auto" | "-a"=> {
                     arg_struct.auto = true;
-                    arg_struct.auto()
                 },
                 "-c" | "-counts" => {
                     arg_struct.count_array_file = args.next().expect("Error parsing count location!");
-                    arg_struct.counts = Some(read_counts(&arg_struct.count_array_file,arg_struct.verbose))
+                    arg_struct.count_format = Some(CountFormat::Delimited);
+                },
+                "-delimiter" | "-sep" => {
+                    arg_struct.delimited.delimiter = Some(Delimiter::parse(&args.next().expect("Error processing delimiter")));
+                },
+                "-comment" => {
+                    arg_struct.delimited.comment = args.next().expect("Error processing comment prefix");
+                },
+                "-strict" => {
+                    arg_struct.policy = Policy::Strict;
+                },
+                "-lenient" => {
+                    arg_struct.policy = Policy::Lenient;
+                },
+                "-max_missing" => {
+                    arg_struct.max_missing = Some(args.next().map(|x| x.parse::<f64>()).expect("Missing fraction parse error. Not a number?").expect("Iteration error"));
+                },
+                "-impute" => {
+                    arg_struct.impute = Some(Impute::parse(&args.next().expect("Error processing imputation")));
+                },
+                "-impute_k" => {
+                    arg_struct.impute_k = Some(args.next().map(|x| x.parse::<usize>()).expect("Imputation neighbors parse error. Not a number?").expect("Iteration error"));
+                },
+                "-header_row" => {
+                    arg_struct.delimited.header_row = true;
+                },
+                "-row_names" => {
+                    arg_struct.delimited.row_names = true;
+                },
+                "-bin" | "-binary" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing binary count location!");
+                    arg_struct.count_format = Some(CountFormat::Binary);
+                },
+                "-mtx" | "-matrix_market" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing Matrix Market count location!");
+                    arg_struct.count_format = Some(CountFormat::MatrixMarket);
+                },
+                "-10x" | "-tenx" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing 10x directory location!");
+                    arg_struct.count_format = Some(CountFormat::TenX);
+                },
+                "-fcs" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing FCS location!");
+                    arg_struct.count_format = Some(CountFormat::Fcs);
+                },
+                "-channels" => {
+                    arg_struct.fcs.channels = Some(args.next().expect("Error parsing channels").split(',').map(|x| x.to_string()).collect());
+                },
+                "-cofactor" | "-fcs_cofactor" => {
+                    arg_struct.fcs.cofactor = Some(args.next().map(|x| x.parse::<f64>()).expect("Cofactor parse error. Not a number?").expect("Iteration error"));
+                },
+                "-compensate" => {
+                    arg_struct.fcs.compensate = true;
+                },
+                "-npy" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing .npy count location!");
+                    arg_struct.count_format = Some(CountFormat::Npy);
+                },
+                "-npz" => {
+                    arg_struct.count_array_file = args.next().expect("Error parsing .npz count location!");
+                    arg_struct.count_format = Some(CountFormat::Npz);
+                },
+                "-npz_key" => {
+                    arg_struct.npz_key = Some(args.next().expect("Error parsing .npz key"));
+                },
+                "-npy_output" | "-write_npy" => {
+                    arg_struct.npy_output = true;
+                },
+                "-dtype" => {
+                    arg_struct.dtype = DType::parse(&args.next().expect("Error processing dtype"));
                 },
                 "-verbose" | "-v"=> {
                     arg_struct.verbose = true;
                 },
                 "-stdin" => {
-                    arg_struct.counts = Some(read_standard_in());
+                    arg_struct.count_format = Some(CountFormat::Stdin);
                 }
                 "-stdout" => {
                     arg_struct.report_address = None;
@@ -137,14 +288,22 @@ impl Parameters {
                     arg_struct.sample_header_file = Some(args.next().expect("Error processing feature file"));
                     arg_struct.sample_names = Some(read_sample_names(arg_struct.sample_header_file.as_ref().unwrap()));
                 }
+                "-w" | "-weights" => {
+                    let weights = read_weights(&args.next().expect("Error processing weight file"));
+                    arg_struct.weights = Some(Arc::new(Weights::new(weights)));
+                },
+                "-wc" | "-weight_column" => {
+                    // Split off once the counts and feature names have all been read
+                    arg_struct.weight_column = Some(args.next().expect("Error processing weight column").parse::<usize>().expect("Error parsing weight column"));
+                },
+                "-weighted_subsample" => {
+                    arg_struct.weighted_subsample = true;
+                },
                 "-fs" | "-feature_sub" => {
                     arg_struct.feature_subsample = Some(args.next().expect("Error processing feature subsample arg").parse::<usize>().expect("Error feature subsample arg"));
                 },
                 "-ss" | "-sample_sub" => {
                     arg_struct.sample_subsample = Some(args.next().expect("Error processing sample subsample arg").parse::<usize>().expect("Error sample subsample arg"));
-                    if arg_struct.sample_subsample.as_ref().unwrap_or(&0) > &(arg_struct.counts.as_ref().unwrap_or(&Array::zeros((0,0))).shape()[0]) {
-                        panic!("Subsamples cannot be greater than samples")
-                    }
                 },
                 "-scaling" | "-step" | "-sf" | "-scaling_factor" => {
                     arg_struct.scaling_factor = Some(args.next().map(|x| x.parse::<f64>()).expect("Scaling factor parse error. Not a number?").expect("Iteration error"));
@@ -155,6 +314,15 @@ impl Parameters {
                 "-error" => {
                     arg_struct.dump_error = Some(args.next().expect("Error processing error destination"))
                 },
+                "-outdir" | "-output_dir" => {
+                    arg_struct.output_dir = Some(args.next().expect("Error processing output directory"))
+                },
+                "-no_overwrite" => {
+                    arg_struct.overwrite = false;
+                },
+                "-overwrite" => {
+                    arg_struct.overwrite = true;
+                },
                 "-convergence" => {
                     arg_struct.convergence_factor = Some(args.next().map(|x| x.parse::<f64>()).expect("Convergence distance parse error. Not a number?").expect("Iteration error"));
                 },
@@ -170,11 +338,84 @@ impl Parameters {
                 "-steps" => {
                     arg_struct.steps = Some(args.next().map(|x| x.parse::<usize>()).expect("Steps not parsed, not a number?").expect("Iteration error"))
                 }
+                "-min_size" | "-min_cluster_size" => {
+                    arg_struct.min_cluster_size = Some(args.next().map(|x| x.parse::<f64>()).expect("Minimum cluster size parse error. Not a number?").expect("Iteration error"))
+                }
+                "-max_fuzz" => {
+                    arg_struct.max_fuzz = Some(args.next().map(|x| x.parse::<f64>()).expect("Maximum fuzz parse error. Not a number?").expect("Iteration error"))
+                }
+                "-max_displacement" => {
+                    arg_struct.max_displacement = Some(args.next().map(|x| x.parse::<f64>()).expect("Maximum displacement parse error. Not a number?").expect("Iteration error"))
+                }
+                "-memberships" | "-soft" => {
+                    arg_struct.memberships = true;
+                }
+                "-sweep" => {
+                    let rates: Vec<usize> = args.next().expect("Error processing sweep arg").split(',').map(|x| x.trim().parse::<usize>().expect("Error parsing sweep subsample rates, expected a comma separated list")).collect();
+                    arg_struct.sweep = Some(rates);
+                },
+                "-consensus" => {
+                    arg_struct.consensus = Some(args.next().map(|x| x.parse::<f64>()).expect("Consensus threshold parse error. Not a number?").expect("Iteration error"))
+                }
+                "-collapse" => {
+                    arg_struct.collapse = Some(args.next().map(|x| x.parse::<f64>()).expect("Collapse tolerance parse error. Not a number?").expect("Iteration error"))
+                }
                 "-borrow" => {
                     arg_struct.borrow = Some(args.next().map(|x| x.parse::<usize>()).expect("Borrowing parse error. Not a number?").expect("Iteration error"));
                 },
+                "-borrow_k" | "-borrow_neighbors" => {
+                    arg_struct.borrow_neighbors = Some(args.next().map(|x| x.parse::<usize>()).expect("Borrowing neighbor parse error. Not a number?").expect("Iteration error"));
+                },
+                "-sanitize" => {
+                    arg_struct.sanitize = Some(Sanitize::parse(&args.next().expect("Error parsing sanitization, drop or repair?")));
+                },
+                "-collinear" => {
+                    let threshold = args.next().map(|x| x.parse::<f64>()).expect("Collinearity parse error. Not a number?").expect("Iteration error");
+                    if !(threshold > 0. && threshold <= 1.) {
+                        panic!("The collinearity threshold is an absolute correlation, between 0 and 1");
+                    }
+                    arg_struct.collinear = Some(threshold);
+                },
                 "-standardize" => {
-                    arg_struct.standardize = true;
+                    arg_struct.preprocess.push(Step::ZScore);
+                },
+                "-preprocess" => {
+                    arg_struct.preprocess.extend(Step::parse_list(&args.next().expect("Error parsing preprocessing steps")));
+                },
+                "-hvf" | "-variable_features" => {
+                    arg_struct.preprocess.push(Step::Variable(args.next().map(|x| x.parse::<usize>()).expect("Variable feature parse error. Not a number?").expect("Iteration error"),Dispersion::Raw));
+                },
+                "-pca" => {
+                    arg_struct.pca = Some(PcaTarget::Components(args.next().map(|x| x.parse::<usize>()).expect("PCA component parse error. Not a number?").expect("Iteration error")));
+                },
+                "-pca_variance" => {
+                    let fraction = args.next().map(|x| x.parse::<f64>()).expect("PCA variance parse error. Not a number?").expect("Iteration error");
+                    if fraction <= 0. || fraction > 1. {
+                        panic!("-pca_variance is a fraction of the variance to explain, between 0 and 1");
+                    }
+                    arg_struct.pca = Some(PcaTarget::Variance(fraction));
+                },
+                "-diffuse" | "-diffusion" => {
+                    let steps = args.next().map(|x| x.parse::<usize>()).expect("Diffusion step parse error. Not a number?").expect("Iteration error");
+                    if steps == 0 {
+                        panic!("Diffusion needs at least one step");
+                    }
+                    arg_struct.diffuse = Some(steps);
+                },
+                "-diffuse_k" => {
+                    arg_struct.diffuse_k = Some(args.next().map(|x| x.parse::<usize>()).expect("Diffusion neighbor parse error. Not a number?").expect("Iteration error"));
+                },
+                "-project" | "-random_projection" => {
+                    arg_struct.project = Some(args.next().map(|x| x.parse::<usize>()).expect("Projection dimension parse error. Not a number?").expect("Iteration error"));
+                },
+                "-projection" => {
+                    arg_struct.projection = Projection::parse(&args.next().expect("Error parsing projection"));
+                },
+                "-seed" => {
+                    arg_struct.seed = Some(args.next().map(|x| x.parse::<u64>()).expect("Seed parse error. Not a number?").expect("Iteration error"));
+                },
+                "-preprocess_from" => {
+                    arg_struct.preprocess_from = Some(args.next().expect("Error parsing preprocessing location"));
                 },
                 "-l" | "-locality" => {
                     arg_struct.locality = Some(args.next().map(|x| x.parse::<f64>()).expect("Locality parse error. Not a number?").expect("Iteration error"))
@@ -193,6 +434,50 @@ impl Parameters {
             }
         }
 
+        // Counts are read once every option for their reader has been parsed
+        if let Some(format) = arg_struct.count_format {
+            arg_struct.read_counts(format);
+        }
+
+        if arg_struct.auto {
+            arg_struct.auto();
+        }
+
+        if let Some(column) = arg_struct.weight_column {
+            let counts = arg_struct.counts.take().expect("Please specify counts to take the weight column from").into_owned();
+            if column >= counts.cols() {
+                panic!("Weight column {} is out of bounds for {} columns",column,counts.cols());
+            }
+            let weights = counts.column(column).to_vec();
+            let kept: Vec<usize> = (0..counts.cols()).filter(|j| *j != column).collect();
+            if let Some(feature_names) = arg_struct.feature_names.as_mut() {
+                if feature_names.len() == counts.cols() {
+                    feature_names.remove(column);
+                }
+            }
+            arg_struct.counts = Some(counts.select(Axis(1),&kept).into());
+            arg_struct.weights = Some(Arc::new(Weights::new(weights)));
+        }
+
+        // Checked once the counts have been read, wherever they came in the arguments
+        let samples = arg_struct.counts.as_ref().map(|x| x.shape().0).unwrap_or(0);
+        if arg_struct.sample_subsample.unwrap_or(0) > samples || arg_struct.sweep.iter().flatten().any(|x| *x > samples) {
+            panic!("Subsamples cannot be greater than samples")
+        }
+
+        // A feature graph on its own is borrowed from once
+        if arg_struct.borrow_neighbors.is_some() && arg_struct.borrow.is_none() {
+            arg_struct.borrow = Some(1);
+        }
+
+        if !arg_struct.preprocess.is_empty() && arg_struct.preprocess_from.is_some() {
+            panic!("-preprocess_from applies a fitted pipeline, it can't be combined with -preprocess, -standardize or -hvf");
+        }
+
+        if let (Some(Impute::Knn(_)),Some(k)) = (arg_struct.impute,arg_struct.impute_k) {
+            arg_struct.impute = Some(Impute::Knn(k));
+        }
+
         arg_struct
 
     }
@@ -201,10 +486,9 @@ impl Parameters {
 
     fn auto(&mut self) {
 
-        let counts = self.counts.as_ref().expect("Please specify counts file before the \"-auto\" argument.");
+        let counts = self.counts.as_ref().expect("Please specify a counts file for the \"-auto\" argument.");
 
-        let features = counts.shape()[1];
-        let samples = counts.shape()[0];
+        let (samples,features) = counts.shape();
 
         let mut output_features = ((features as f64 / (features as f64).log10()) as usize).min(features);
 
@@ -260,8 +544,133 @@ impl Parameters {
 
     }
 
-    pub fn distance(&self, p1:ArrayView<f64,Ix1>,p2:ArrayView<f64,Ix1>) -> f64 {
-        self.distance.as_ref().unwrap_or(&Distance::Cosine).measure(p1,p2)
+    // Names read along with the counts replace any given before them
+
+    fn read_counts(&mut self, format: CountFormat) {
+        let location = &self.count_array_file;
+        match format {
+            CountFormat::Delimited => {
+                let count_array_file = File::open(location).expect("Count file error!");
+                let delimited = read_delimited(io::BufReader::new(count_array_file),&self.delimited,self.verbose);
+                self.take_delimited(delimited);
+            },
+            CountFormat::Stdin => {
+                let stdin = io::stdin();
+                let delimited = read_delimited(stdin.lock(),&self.delimited,self.verbose);
+                self.take_delimited(delimited);
+            },
+            CountFormat::Binary => {
+                self.counts = Some(read_binary(location,self.verbose).expect("Binary count file error!"));
+            },
+            CountFormat::MatrixMarket => {
+                self.counts = Some(Points::Sparse(Arc::new(read_mtx_file(location,self.verbose).expect("Matrix Market count file error!"))));
+            },
+            CountFormat::TenX => {
+                let tenx = read_10x(location,self.verbose).expect("10x directory error!");
+                self.counts = Some(Points::Sparse(Arc::new(tenx.counts)));
+                self.sample_names = Some(tenx.barcodes);
+                self.feature_names = Some(tenx.features);
+            },
+            CountFormat::Fcs => {
+                let fcs = read_fcs(location,&self.fcs,self.verbose).expect("FCS file error!");
+                self.counts = Some(fcs.counts.into());
+                self.feature_names = Some(fcs.channels);
+            },
+            CountFormat::Npy => {
+                self.counts = Some(read_npy(location,self.verbose).expect("NumPy count file error!"));
+            },
+            CountFormat::Npz => {
+                self.counts = Some(read_npz(location,self.npz_key.as_deref(),self.verbose).expect("NumPy count file error!"));
+            },
+        }
+    }
+
+    fn take_delimited(&mut self, delimited: Delimited) {
+        self.counts = Some(delimited.counts.into());
+        self.validation = Some(delimited.validation);
+        if delimited.sample_names.is_some() {
+            self.sample_names = delimited.sample_names;
+        }
+        if delimited.feature_names.is_some() {
+            self.feature_names = delimited.feature_names;
+        }
+    }
+
+    pub fn reporting(&self) -> bool {
+        self.dump_error.is_some() || self.output_dir.is_some()
+    }
+
+    // Where a report is written: under its fixed name in the output directory, or
+    // after the -error prefix.
+
+    pub fn report(&self, name: &str) -> Option<String> {
+        match (self.output_dir.as_ref(),self.dump_error.as_ref()) {
+            (Some(directory),_) => Some(Path::new(directory).join(name).to_string_lossy().into_owned()),
+            (None,Some(prefix)) => Some([prefix,name].join("")),
+            (None,None) => None,
+        }
+    }
+
+    // Labels go to -o, or to labels.tsv in the output directory. Neither means stdout.
+
+    pub fn labels(&self) -> Option<String> {
+        self.report_address.clone().or_else(|| {
+            self.output_dir.as_ref().map(|directory| Path::new(directory).join(self.array_report("labels")).to_string_lossy().into_owned())
+        })
+    }
+
+    // Borrowing can't take all-zero or duplicated features, so it sanitizes even
+    // when not asked to
+    pub fn sanitizing(&self) -> Option<Sanitize> {
+        if self.sanitize.is_some() {
+            self.sanitize
+        }
+        else if self.collinear.is_some() {
+            Some(Sanitize::Drop)
+        }
+        else {
+            None
+        }
+    }
+
+    // Seeded with -seed for reproducible randomized preprocessing
+    pub fn rng(&self) -> StdRng {
+        match self.seed {
+            Some(seed) => {
+                let mut bytes = [0u8; 32];
+                bytes[..8].copy_from_slice(&seed.to_le_bytes());
+                StdRng::from_seed(bytes)
+            },
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    // Labels, fuzz and final positions are written as .npy with -npy_output
+    pub fn array_report(&self, stem: &str) -> &'static str {
+        match (stem,self.npy_output) {
+            ("labels",false) => "labels.tsv",
+            ("labels",true) => "labels.npy",
+            ("final_pos",false) => "final_pos.tsv",
+            ("final_pos",true) => "final_pos.npy",
+            ("fuzz",false) => "fuzz.tsv",
+            ("fuzz",true) => "fuzz.npy",
+            _ => panic!("No array report named {}",stem),
+        }
+    }
+
+    // Checked before any work is done, so that a long run isn't thrown away at the end.
+
+    pub fn check_outputs(&self) {
+        if self.overwrite {
+            return
+        }
+        let mut targets: Vec<String> = self.labels().into_iter().collect();
+        if self.reporting() {
+            targets.extend(OUTPUT_FILES.iter().filter_map(|(name,_)| self.report(name)));
+        }
+        if let Some(existing) = targets.iter().find(|x| Path::new(x).exists()) {
+            panic!("Refusing to overwrite {}, remove it or run without -no_overwrite",existing);
+        }
     }
 
 }
@@ -288,7 +697,7 @@ fn read_header(location: &str,verbose:bool) -> Vec<String> {
         header_map.insert(renamed,i);
     };
 
-    let mut header_inter: Vec<(String,usize)> = header_map.iter().map(|x| (x.0.clone().clone(),x.1.clone())).collect();
+    let mut header_inter: Vec<(String,usize)> = header_map.iter().map(|x| (x.0.clone().clone(),*x.1)).collect();
     header_inter.sort_unstable_by_key(|x| x.1);
     let header_vector: Vec<String> = header_inter.into_iter().map(|x| x.0).collect();
 
@@ -313,125 +722,6 @@ fn read_sample_names(location: &str) -> Vec<String> {
 
 
 
-fn read_counts(location:&str,verbose:bool) -> Array<f64,Ix2> {
-
-
-    let count_array_file = File::open(location).expect("Count file error!");
-    let mut count_array_lines = io::BufReader::new(&count_array_file).lines();
-
-    let mut counts: Vec<f64> = Vec::new();
-    let mut samples = 0;
-
-    for (i,line) in count_array_lines.by_ref().enumerate() {
-
-        samples += 1;
-        let mut gene_vector = Vec::new();
-
-        let gene_line = line.expect("Readline error");
-
-        for (j,gene) in gene_line.split_whitespace().enumerate() {
-
-            if j == 0 && i%200==0{
-                eprint!("\n");
-            }
-
-            if i%200==0 && j%200 == 0 {
-                eprint!("{} ", gene.parse::<f64>().unwrap_or(-1.) );
-            }
-
-            // if !((gene.0 == 1686) || (gene.0 == 4660)) {
-            //     continue
-            // }
-
-            match gene.parse::<f64>() {
-                Ok(exp_val) => {
-
-                    gene_vector.push(exp_val);
-
-                },
-                Err(msg) => {
-
-                    if gene != "nan" && gene != "NAN" {
-                        println!("Couldn't parse a cell in the text file, Rust sez: {:?}",msg);
-                        println!("Cell content: {:?}", gene);
-                    }
-                    gene_vector.push(f64::NAN);
-                }
-            }
-
-        }
-
-        counts.append(&mut gene_vector);
-
-        if i % 100 == 0 && verbose {
-            eprintln!("{}", i);
-        }
-
-
-    };
-
-    let array = Array::from_shape_vec((samples,counts.len()/samples),counts).unwrap_or(Array::zeros((0,0)));
-
-    if verbose {
-        eprintln!("===========");
-        eprintln!("{},{}", array.shape()[0], array.shape()[1]);
-    }
-
-    array
-}
-
-fn read_standard_in() -> Array<f64,Ix2> {
-
-    let stdin = io::stdin();
-    let count_array_pipe_guard = stdin.lock();
-
-    let mut counts: Vec<f64> = Vec::new();
-    let mut samples = 0;
-
-    for (_i,line) in count_array_pipe_guard.lines().enumerate() {
-
-        samples += 1;
-        let mut gene_vector = Vec::new();
-
-        for (_j,gene) in line.as_ref().expect("readline error").split_whitespace().enumerate() {
-
-            match gene.parse::<f64>() {
-                Ok(exp_val) => {
-
-                    if exp_val.is_nan() {
-                        eprintln!("Read a nan: {:?},{:?}", gene,exp_val);
-                        panic!("Read a nan!")
-                    }
-                    gene_vector.push(exp_val);
-
-                },
-                Err(msg) => {
-
-                    if gene != "nan" && gene != "NAN" {
-                        eprintln!("Couldn't parse a cell in the text file, Rust sez: {:?}",msg);
-                        eprintln!("Cell content: {:?}", gene);
-                        panic!("Parsing error");
-                    }
-                    gene_vector.push(f64::NAN);
-                }
-            }
-
-        }
-
-        counts.append(&mut gene_vector);
-
-    };
-
-    // eprintln!("Counts read:");
-    // eprintln!("{:?}", counts);
-
-    let array = Array::from_shape_vec((samples,counts.len()/samples),counts).unwrap_or(Array::zeros((0,0)));
-
-    assert!(!array.iter().any(|x| x.is_nan()));
-
-    array
-}
-
 pub fn borrow(input: Array<f64,Ix2>, distance:&Distance,verbose:bool) -> Array<f64,Ix2> {
 
 
@@ -459,12 +749,8 @@ pub fn borrow(input: Array<f64,Ix2>, distance:&Distance,verbose:bool) -> Array<f
 
     let standardized = standardize(&input);
     // let similarity = correlation_matrix(input.view().t());
-    let similarity = standardized.t().dot(&standardized);
     let similarity = match distance {
-        Distance::Euclidean => {
-            let standardized = standardize(&input);
-            standardized.t().dot(&standardized)
-        },
+        Distance::Euclidean => standardized.t().dot(&standardized),
         Distance::Cosine => cosine_similarity_matrix(input.t()),
         _ => euclidean_similarity_matrix(input.view().t()),
     } ;
@@ -485,122 +771,9 @@ pub fn borrow(input: Array<f64,Ix2>, distance:&Distance,verbose:bool) -> Array<f
 
 }
 
-//
-// pub fn pca(mut input: Array<f64,Ix2>,pc_lim:usize) -> (Array<f64,Ix2>,Array<f64,Ix2>) {
-//
-//     eprintln!("PCA by means of SVD");
-//     eprintln!("{:?}",input.shape());
-//     eprintln!("Keeping {:?}",pc_lim);
-//
-    // eprintln!("{:?}", input);
-    //
-    // if let Ok((Some(u),sig,Some(v))) = input.clone().svd(true,true) {
-    //     // let order = argsort(&(0..input.shape()[0]).map(|i| sig[i]).collect());
-    //     let mut diagonal: Array<f64,Ix2> = Array::zeros((u.shape()[1],u.shape()[1]));
-    //     for (i,sv) in sig.iter().enumerate() {
-    //         eprintln!("{:?}",sv);
-    //         diagonal[[i,i]] = *sv;
-    //     }
-    //     let eigenvectors: Array<f64,Ix2> = v.slice(s![.. , 0..pc_lim]).t().to_owned();
-    //     let scores: Array<f64,Ix2> = (u * diagonal).slice(s![ .. , 0..pc_lim]).to_owned();
-    //
-    //     eprintln!("PCA Was Performed, retaining {} PCs", pc_lim);
-    //     eprintln!("{:?},{:?}",scores.shape(),eigenvectors.shape());
-    //     eprintln!("{:?}",scores.slice(s![..10,..]));
-    //
-    //     (scores,eigenvectors)
-    //
-    // }
-    //
-    // else {
-    //     let shape = (input.shape()[0],input.shape()[1]);
-    //     eprintln!("WARNING, SVD FAILED, ATTEMPTING CLUSTERING ON WHOLE MATRIX");
-    //     (input,Array::zeros(shape))
-    // // }
-
-//     let mut rla_mtx: Matrix<f64> = Matrix::new(input.shape()[0],input.shape()[1],input.iter().cloned().collect::<Vec<f64>>());
-//
-//     let mut means: Matrix<f64> = Matrix::from(rla_mtx.mean(Axes::Row)).transpose();
-//     let mut variances: Matrix<f64> = Matrix::from(rla_mtx.variance(Axes::Row).unwrap()).transpose();
-//
-//     let mut masked: Vec<usize> = variances.iter().enumerate().filter(|x| *x.1 == 0.).map(|x| x.0).collect();
-//
-//     for column in &masked {
-//         rla_mtx = rla_mtx.sub_slice([0,0],rla_mtx.rows(),*column).into_matrix().hcat(&rla_mtx.sub_slice([0,column+1],rla_mtx.rows(),rla_mtx.cols()-column-1).into_matrix());
-//         means = means.sub_slice([0,0],means.rows(),*column).into_matrix().hcat(&means.sub_slice([0,column+1],means.rows(),means.cols()-column-1).into_matrix());
-//         variances = variances.sub_slice([0,0],variances.rows(),*column).into_matrix().hcat(&variances.sub_slice([0,column+1],variances.rows(),variances.cols()-column-1).into_matrix());
-//     }
-//
-//     eprintln!("Masked {}",masked.len());
-//     eprintln!("{:?}",(rla_mtx.rows(),rla_mtx.cols()));
-//
-//
-//     eprintln!("{:?}",variances);
-//     eprintln!("{:?}",means);
-//
-//     for row in rla_mtx.row_iter_mut() {
-//         // eprintln!("{:?}",row);
-//         let centered = &*row - &means;
-//         // eprintln!("{:?}",centered);
-//         let mut standardized = centered.elediv(&variances);
-//         for v in standardized.iter_mut() {
-//             if !v.is_finite() {
-//                 *v = 0.;
-//             }
-//         }
-//         // eprintln!("{:?}",standardized);
-//         row.set_to(standardized);
-//     }
-//
-//     // eprintln!("{:?}", rla_mtx);
-//
-//     eprintln!("Covariance?");
-//
-//     // let cov =  (&rla_mtx.transpose() * &rla_mtx) * (1./ (rla_mtx.rows() - 1) as f64);
-//     let cov =  &rla_mtx.transpose() * &rla_mtx;
-//     //
-//     eprintln!("{:?}",(cov.rows(),cov.cols()));
-//
-//     eprintln!("Covariance established");
-//
-//     // cov.clone().eigendecomp().unwrap();
-//
-//     if let Ok((eigenvalues,eigenvectors_col)) = cov.eigendecomp() {
-//         eprintln!("{:?}",eigenvalues);
-//         // eprintln!("{:?}", (eigenvectors_col.rows(),eigenvectors_col.cols()));
-//         let mut order = argsort(&eigenvalues);
-//         order.truncate(pc_lim);
-//         // eprintln!("Attempting {},{}",pc_lim, eigenvectors_col.rows());
-//         // eprintln!("{}",order.len());
-//         let pcs: Matrix<f64> = Matrix::new(order.len(),eigenvectors_col.rows(), order.into_iter().flat_map(|x| eigenvectors_col.col(x).iter().cloned()).collect::<Vec<f64>>());
-//         let pcs_col = pcs.transpose();
-//         let transformed = rla_mtx * pcs_col;
-//
-//         let pcs_array: Array<f64,Ix2> = Array::from_shape_vec((pcs.rows(),pcs.cols()),pcs.into_vec()).unwrap();
-//         let transformed_array: Array<f64,Ix2> = Array::from_shape_vec((transformed.rows(),transformed.cols()),transformed.into_vec()).unwrap();
-//
-//         eprintln!("Returning PCA");
-//
-//         eprintln!("{:?}",transformed_array.shape());
-//         eprintln!("{:?}",pcs_array.shape());
-//
-//         (transformed_array,pcs_array)
-//     }
-//     else {
-//
-//         eprintln!("FAILED EIGENDECOMPOSITION");
-//         panic!();
-//
-//         let shape = (input.shape()[0],input.shape()[1]);
-//         (input,Array::zeros((shape.0,shape.1)))
-//     }
-//
-//
-// }
-
 pub fn standardize(input: &Array<f64,Ix2>) -> Array<f64,Ix2> {
-    let mut means = input.mean_axis(Axis(0));
-    let mut variances = input.var_axis(Axis(0),0.);
+    let means = input.mean_axis(Axis(0));
+    let deviations = input.std_axis(Axis(0),0.);
 
     let mut standardized = input.clone();
 
@@ -609,7 +782,7 @@ pub fn standardize(input: &Array<f64,Ix2>) -> Array<f64,Ix2> {
         // eprintln!("{:?}",row);
         let centered_row = &row - &means;
         // eprintln!("{:?}",centered);
-        let mut standardized_row = centered_row * &variances;
+        let mut standardized_row = centered_row / &deviations;
         for v in standardized_row.iter_mut() {
             if !v.is_finite() {
                 *v = 0.;
@@ -623,28 +796,18 @@ pub fn standardize(input: &Array<f64,Ix2>) -> Array<f64,Ix2> {
 
 }
 
-pub fn sanitize(mut input: Array<f64,Ix2>) -> Array<f64,Ix2> {
-    for ref mut feature in input.axis_iter_mut(Axis(1)) {
-        if feature.iter().sum::<f64>() == 0. {
-            feature.fill(1.)
-        }
-    };
-    input
-}
-
-
 pub fn cosine_similarity_matrix(slice: ArrayView<f64,Ix2>) -> Array<f64,Ix2> {
     let mut products = slice.dot(&slice.t());
     // eprintln!("Products");
     let mut geo = (&slice * &slice).sum_axis(Axis(1));
     if geo.iter().any(|x| *x == 0.) {
-        panic!("Unsanitized input, detected an all-0 feature (column), please use a different distance metric, or sanitize your input");
+        panic!("Unsanitized input, detected an all-0 feature (column), please use a different distance metric, or sanitize your input with \"-sanitize drop\" or \"-sanitize repair\"");
     }
     // eprintln!("geo");
     geo.mapv_inplace(f64::sqrt);
     for i in 0..slice.rows() {
         for j in 0..slice.rows() {
-            products[[i,j]] /= (&geo[i] * &geo[j])
+            products[[i,j]] /= geo[i] * geo[j] 
         }
     }
     for i in 0..slice.rows() {
@@ -657,12 +820,12 @@ pub fn cosine_similarity_matrix(slice: ArrayView<f64,Ix2>) -> Array<f64,Ix2> {
 pub fn euclidean_similarity_matrix(slice: ArrayView<f64,Ix2>) -> Array<f64,Ix2> {
     let mut products = slice.dot(&slice.t());
     // eprintln!("Products");
-    let mut geo = (&slice * &slice).sum_axis(Axis(1));
+    let geo = (&slice * &slice).sum_axis(Axis(1));
     // eprintln!("geo");
 
     for i in 0..slice.rows() {
         for j in 0..slice.rows() {
-            products[[i,j]] = 1.0 / (&geo[i] + &geo[j] - 2.0 * products[[i,j]]).sqrt();
+            products[[i,j]] = 1.0 / (geo[i] + geo[j] - 2.0 * products[[i,j]]).sqrt();
             if !products[[i,j]].is_finite() {
                 products[[i,j]] = 1.0;
             }
@@ -676,24 +839,18 @@ pub fn euclidean_similarity_matrix(slice: ArrayView<f64,Ix2>) -> Array<f64,Ix2>
     products
 }
 
-pub fn correlation_matrix(slice: ArrayView<f64,Ix2>) -> Array<f64,Ix2> {
-    let mut output = Array::zeros((slice.rows(),slice.cols()));
-    for i in 0..slice.rows() {
-        for j in 0..slice.cols() {
-            output[[i,j]] = correlation(slice.row(i),slice.row(j));
-        }
-    }
-    output
-}
 
-
-fn argsort(input: &Vec<f64>) -> Vec<usize> {
-    let mut intermediate1 = input.iter().enumerate().collect::<Vec<(usize,&f64)>>();
-    intermediate1.sort_unstable_by(|a,b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Greater));
-    let mut intermediate2 = intermediate1.iter().enumerate().collect::<Vec<(usize,&(usize,&f64))>>();
-    intermediate2.sort_unstable_by(|a,b| ((a.1).0).cmp(&(b.1).0));
-    let out = intermediate2.iter().map(|x| x.0).collect();
-    out
+// Where the counts come from, the file being -c, -bin, ... or stdin
+#[derive(Debug,Clone,Copy)]
+enum CountFormat {
+    Delimited,
+    Stdin,
+    Binary,
+    MatrixMarket,
+    TenX,
+    Fcs,
+    Npy,
+    Npz,
 }
 
 #[derive(Debug,Clone,Copy)]
@@ -706,7 +863,7 @@ pub enum Distance {
 
 impl Distance {
     pub fn parse(argument: &str) -> Distance {
-        match &argument[..] {
+        match argument {
             "manhattan" | "m" | "cityblock" => Distance::Manhattan,
             "euclidean" | "e" => Distance::Euclidean,
             "cosine" | "c" | "cos" => Distance::Cosine,
@@ -747,20 +904,22 @@ pub enum Command {
     FitPredict,
     Fuzzy,
     Mobile,
+    Convert,
 }
 
 impl Command {
 
     pub fn parse(command: &str) -> Command {
 
-        match &command[..] {
+        match command {
             "fit" => Command::Fit,
             "predict" => Command::Predict,
             "fitpredict" | "fit_predict" | "combined" => Command::FitPredict,
             "fuzzy_predict" | "fuzzy" => Command::Fuzzy,
             "mobile" => Command::Mobile,
+            "convert" => Command::Convert,
             _ =>{
-                eprintln!("Not a valid top-level command, please choose from \"fit\",\"predict\", or \"fitpredict\". Exiting");
+                eprintln!("Not a valid top-level command, please choose from \"fit\",\"predict\",\"fitpredict\",\"fuzzy\",\"mobile\", or \"convert\". Exiting");
                 panic!()
             }
         }
@@ -780,8 +939,8 @@ fn correlation(p1: ArrayView<f64,Ix1>,p2: ArrayView<f64,Ix1>) -> f64 {
     let mean1: f64 = mean(&p1);
     let mean2: f64 = mean(&p2);
 
-    let dev1: Vec<f64> = p1.iter().map(|x| (x - mean1)).collect();
-    let dev2: Vec<f64> = p2.iter().map(|x| (x - mean2)).collect();
+    let dev1: Vec<f64> = p1.iter().map(|x| x - mean1 ).collect();
+    let dev2: Vec<f64> = p2.iter().map(|x| x - mean2 ).collect();
 
     let covariance = dev1.iter().zip(dev2.iter()).map(|(x,y)| x * y).sum::<f64>() / (p1.len() as f64 - 1.);
 
@@ -796,55 +955,145 @@ fn correlation(p1: ArrayView<f64,Ix1>,p2: ArrayView<f64,Ix1>) -> f64 {
 
 }
 
-pub fn write_array<T: Debug>(input: Array<T,Ix2>,target:&Option<String>) -> Result<(),Error> {
-    let formatted =
-        input
-        .outer_iter()
-        .map(|x| x.iter()
-            .map(|y| format!("{:?}",y))
-            .collect::<Vec<String>>()
-            .join("\t")
-        )
-        .collect::<Vec<String>>()
-        .join("\n");
+// Every output file, under the name it gets in an output directory or after the
+// -error prefix, and what it holds.
+
+pub const OUTPUT_FILES: &[(&str,&str)] = &[
+    ("labels.tsv","cluster label of every sample, -1 for noise"),
+    ("final_pos.tsv","final position of every sample"),
+    ("fuzz.tsv","fuzz of every sample"),
+    ("labels.npy","cluster labels as a NumPy int64 array"),
+    ("final_pos.npy","final positions as a NumPy float64 array"),
+    ("fuzz.npy","fuzz as a NumPy float64 array"),
+    ("outlier_scores.tsv","outlier score of every sample"),
+    ("clusters.tsv","per-cluster report"),
+    ("clusters.json","per-cluster report"),
+    ("cluster_centers.tsv","center of every cluster"),
+    ("membership_entropy.tsv","entropy of the soft memberships of every sample"),
+    ("memberships.tsv","soft memberships, samples by clusters"),
+    ("consensus_atoms.tsv","co-association atom of every sample"),
+    ("consensus.tsv","sparse co-association between atoms"),
+    ("merges.tsv","log of cluster merges"),
+    ("linkage.tsv","merge history as a linkage matrix"),
+    ("dendrogram.nwk","merge history as a Newick tree"),
+    ("sweep_tree.tsv","clusters linked across subsample rates"),
+    ("preprocessing.tsv","fitted preprocessing steps, reusable with -preprocess_from"),
+    ("selected_features.tsv","dispersion of each feature and whether each variable feature selection step kept it"),
+    ("feature_graph.tsv","features each feature borrows from, with similarities and weights"),
+    ("sanitization.tsv","features dropped or repaired before borrowing, and why"),
+    ("pca_loadings.tsv","PCA loadings, features by components"),
+    ("pca_variance.tsv","variance explained by each PCA component"),
+    ("validation.tsv","problems found in the input"),
+    ("manifest.tsv","this list"),
+];
+
+// Writes to a temporary file next to the target and renames it into place on
+// commit, so the target is either the old file or the complete new one. Dropping
+// it without committing removes the temporary file.
+
+pub struct AtomicFile {
+    target: PathBuf,
+    temporary: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
 
-    match target {
-        Some(location) => {
-            let mut target_file = OpenOptions::new().create(true).append(true).open(location).unwrap();
-            target_file.write(&formatted.as_bytes())?;
-            target_file.write(b"\n")?;
-            Ok(())
+impl AtomicFile {
+
+    pub fn create(location: &str) -> Result<AtomicFile,Error> {
+        let target = PathBuf::from(location);
+        let file_name = target.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+        let temporary = target.with_file_name(format!(".{}.{}.tmp",file_name,process::id()));
+        let writer = BufWriter::new(File::create(&temporary)?);
+        Ok(AtomicFile { target, temporary, writer: Some(writer) })
+    }
+
+    pub fn commit(mut self) -> Result<(),Error> {
+        let writer = self.writer.take().unwrap();
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.temporary,&self.target)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.temporary);
         }
-        None => {
-            let mut stdout = io::stdout();
-            let mut stdout_handle = stdout.lock();
-            stdout_handle.write(&formatted.as_bytes())?;
-            stdout_handle.write(b"\n")?;
-            Ok(())
+    }
+}
+
+// With row names every line starts with the name of its row, and with column names
+// a header line is written first, led by an empty cell if rows are named too. Targets
+// ending in .npy get the bare array as NumPy binary instead, without names.
+
+pub fn write_named_array<T: Debug + NpyElement>(input: Array<T,Ix2>,row_names: Option<&[String]>,column_names: Option<&[String]>,target:&Option<String>) -> Result<(),Error> {
+    if let Some(location) = target.as_ref().filter(|x| x.ends_with(".npy")) {
+        return write_npy(&input,location)
+    }
+
+    let mut lines: Vec<String> = vec![];
+
+    if let Some(column_names) = column_names {
+        let mut header = column_names.to_vec();
+        if row_names.is_some() {
+            header.insert(0,"".to_string());
         }
+        lines.push(header.join("\t"));
     }
+
+    for (i,row) in input.outer_iter().enumerate() {
+        let mut line = row.iter().map(|y| format!("{:?}",y)).collect::<Vec<String>>();
+        if let Some(row_names) = row_names {
+            line.insert(0,row_names[i].clone());
+        }
+        lines.push(line.join("\t"));
+    }
+
+    write_lines(lines.join("\n"),target)
 }
 
-pub fn write_vector<T: Debug>(input: Array<T,Ix1>,target: &Option<String>) -> Result<(),Error> {
+pub fn write_named_vector<T: Debug + NpyElement>(input: Array<T,Ix1>,names: Option<&[String]>,target: &Option<String>) -> Result<(),Error> {
+    if let Some(location) = target.as_ref().filter(|x| x.ends_with(".npy")) {
+        return write_npy(&input,location)
+    }
+
     let formatted =
         input
         .iter()
-        .map(|x| format!("{:?}",x))
+        .enumerate()
+        .map(|(i,x)| match names {
+            Some(names) => format!("{}\t{:?}",names[i],x),
+            None => format!("{:?}",x),
+        })
         .collect::<Vec<String>>()
         .join("\n");
 
+    write_lines(formatted,target)
+}
+
+fn write_lines(formatted: String,target: &Option<String>) -> Result<(),Error> {
     match target {
         Some(location) => {
-            let mut target_file = OpenOptions::new().create(true).append(true).open(location).unwrap();
-            target_file.write(&formatted.as_bytes())?;
-            target_file.write(b"\n")?;
-            Ok(())
+            let mut target_file = AtomicFile::create(location)?;
+            target_file.write_all(formatted.as_bytes())?;
+            target_file.write_all(b"\n")?;
+            target_file.commit()
         }
         None => {
-            let mut stdout = io::stdout();
+            let stdout = io::stdout();
             let mut stdout_handle = stdout.lock();
-            stdout_handle.write(&formatted.as_bytes())?;
-            stdout_handle.write(b"\n")?;
+            stdout_handle.write_all(formatted.as_bytes())?;
+            stdout_handle.write_all(b"\n")?;
             Ok(())
         }
     }
@@ -868,3 +1117,95 @@ pub fn write_vector<T: Debug>(input: Array<T,Ix1>,target: &Option<String>) -> Re
 
 
 //
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::panic;
+    use npy::tests::{npy_bytes,zip_bytes};
+
+    fn location(name: &str) -> String {
+        env::temp_dir().join(format!("gravity_io_{}_{}",name,std::process::id())).to_str().unwrap().to_string()
+    }
+
+    fn parse(args: &[&str]) -> Parameters {
+        let mut args = ["gravity_clustering","fitpredict"].iter().chain(args.iter()).map(|x| x.to_string());
+        Parameters::read(&mut args)
+    }
+
+    #[test]
+    fn reader_options_can_follow_the_counts() {
+        let counts = location("counts.csv");
+        fs::write(&counts,"a,b\n1,2\n3,4\n").unwrap();
+        let parameters = parse(&["-c",&counts,"-header_row","-delimiter","comma"]);
+        fs::remove_file(&counts).unwrap();
+        assert_eq!(parameters.feature_names,Some(vec!["a".to_string(),"b".to_string()]));
+        assert_eq!(parameters.counts.unwrap().into_owned(),Array::from_shape_vec((2,2),vec![1.,2.,3.,4.]).unwrap());
+    }
+
+    #[test]
+    fn npz_key_can_follow_the_archive() {
+        let first: Vec<u8> = [1.,2.].iter().flat_map(|x: &f64| x.to_le_bytes().to_vec()).collect();
+        let second: Vec<u8> = [3.,4.].iter().flat_map(|x: &f64| x.to_le_bytes().to_vec()).collect();
+        let archive = location("arrays.npz");
+        fs::write(&archive,zip_bytes(&[
+            ("first.npy",npy_bytes("{'descr': '<f8', 'fortran_order': False, 'shape': (1, 2), }",&first)),
+            ("second.npy",npy_bytes("{'descr': '<f8', 'fortran_order': False, 'shape': (1, 2), }",&second)),
+        ])).unwrap();
+        let parameters = parse(&["-npz",&archive,"-npz_key","second"]);
+        fs::remove_file(&archive).unwrap();
+        assert_eq!(parameters.counts.unwrap().into_owned(),Array::from_shape_vec((1,2),vec![3.,4.]).unwrap());
+    }
+
+    #[test]
+    fn the_last_counts_given_are_read() {
+        let (first,second) = (location("first.tsv"),location("second.tsv"));
+        fs::write(&first,"1\t2\n").unwrap();
+        fs::write(&second,"1\t2\t3\n").unwrap();
+        let parameters = parse(&["-c",&first,"-c",&second]);
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        assert_eq!(parameters.counts.unwrap().shape(),(1,3));
+    }
+
+    #[test]
+    fn sanitization_is_opt_in() {
+        assert_eq!(parse(&["-borrow","1"]).sanitizing(),None);
+        assert_eq!(parse(&["-borrow","1","-sanitize","repair"]).sanitizing(),Some(Sanitize::Repair));
+        assert_eq!(parse(&["-collinear","0.99"]).sanitizing(),Some(Sanitize::Drop));
+    }
+
+    #[test]
+    fn uncommitted_files_leave_the_target_alone() {
+        let target = location("atomic.tsv");
+        fs::write(&target,"old\n").unwrap();
+        {
+            let mut file = AtomicFile::create(&target).unwrap();
+            writeln!(file,"partial").unwrap();
+            // Dropped without a commit, as when a write fails part way
+        }
+        assert_eq!(fs::read_to_string(&target).unwrap(),"old\n");
+        let mut file = AtomicFile::create(&target).unwrap();
+        writeln!(file,"new").unwrap();
+        file.commit().unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(),"new\n");
+        fs::remove_file(&target).unwrap();
+        let prefix = format!(".{}",Path::new(&target).file_name().unwrap().to_string_lossy());
+        let leftovers = fs::read_dir(env::temp_dir()).unwrap().filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&prefix)).count();
+        assert_eq!(leftovers,0);
+    }
+
+    #[test]
+    fn no_overwrite_refuses_existing_outputs() {
+        let labels = location("existing_labels.tsv");
+        fs::write(&labels,"0\n").unwrap();
+        parse(&["-o",&labels]).check_outputs();
+        let refusing = parse(&["-o",&labels,"-no_overwrite"]);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| refusing.check_outputs()));
+        fs::remove_file(&labels).unwrap();
+        assert!(result.is_err());
+        parse(&["-o",&labels,"-no_overwrite"]).check_outputs();
+    }
+}
@@ -1,11 +1,12 @@
 use std::env;
 use std::io::Write;
-use std::fs::File;
-use std::fs::OpenOptions;
+use std::fs;
 
 
 extern crate rand;
 extern crate num_cpus;
+extern crate memmap;
+extern crate flate2;
 
 #[macro_use]
 extern crate ndarray;
@@ -14,17 +15,43 @@ extern crate ndarray;
 extern crate rayon;
 
 mod io;
+mod binary;
+mod weights;
+mod delimited;
+mod validation;
+mod sparse;
+mod npy;
+mod tenx;
+mod fcs;
+mod preprocess;
+mod selection;
+mod sanitize;
+mod borrowing;
+mod diffusion;
+mod pca;
+mod projection;
+mod missing;
+mod consensus;
+mod dendrogram;
+mod report;
+mod sweep;
 mod mobile_gravity_field;
 mod single_pathfinder;
 mod cluster;
-use io::{write_array,write_vector};
-use mobile_gravity_field::GravityField;
+use io::{write_named_array,write_named_vector,AtomicFile,OUTPUT_FILES};
+use mobile_gravity_field::{GravityField,membership_entropy};
 use io::{Parameters,Command,Distance};
-use ndarray::{Array,Axis,Ix1,Ix2,Zip,ArrayView};
+use ndarray::{Array,Ix1,Ix2,Zip,ArrayView};
 use std::sync::Arc;
 use std::io::Error;
 use io::borrow;
-use io::standardize;
+use preprocess::{Pipeline,Step};
+use pca::Pca;
+use sanitize::Sanitization;
+use borrowing::FeatureGraph;
+use binary::{Points,write_binary};
+use sweep::Sweep;
+use report::{write_cluster_tsv,write_cluster_json};
 
 fn main() -> Result<(),Error> {
 
@@ -32,24 +59,133 @@ fn main() -> Result<(),Error> {
     //
     // Ok(())
 
-    let mut arg_iter = env::args();
+    run(env::args())
+
+}
+
+fn run<T: Iterator<Item = String>>(mut arg_iter: T) -> Result<(),Error> {
 
     let mut parameters_raw = Parameters::read(&mut arg_iter);
 
+    if let Some(directory) = parameters_raw.output_dir.as_ref() {
+        fs::create_dir_all(directory)?;
+    }
+
+    parameters_raw.check_outputs();
+
     let mut gravity_points = parameters_raw.counts.take().unwrap();
 
-    if parameters_raw.standardize {
-        gravity_points = standardize(&gravity_points);
+    let mut validation = parameters_raw.validation.take().unwrap_or_default();
+    match &gravity_points {
+        Points::Sparse(matrix) => validation.check_sparse(matrix),
+        points => validation.check_values(points.view()),
     }
+    validation.print();
+    if let Some(location) = parameters_raw.report("validation.tsv") {
+        validation.write(&location)?;
+    }
+    validation.enforce(parameters_raw.policy);
 
-    for _ in 0..*parameters_raw.borrow.as_ref().unwrap_or(&0)  {
-        gravity_points = borrow(gravity_points,parameters_raw.distance.as_ref().unwrap_or(&Distance::Cosine),parameters_raw.verbose);
+    if let Command::Convert = parameters_raw.command {
+        let target = parameters_raw.report_address.as_ref().expect("Please specify a destination for the converted counts with -o");
+        if gravity_points.is_sparse() {
+            return write_binary(gravity_points.into_owned().view(),parameters_raw.dtype,target)
+        }
+        return write_binary(gravity_points.view(),parameters_raw.dtype,target)
+    }
+
+    gravity_points = missing::handle_missing(gravity_points,&mut parameters_raw);
+
+    if let Some(location) = parameters_raw.preprocess_from.as_ref() {
+        let pipeline = Pipeline::read(location).expect("Preprocessing file error!");
+        gravity_points = pipeline.transform(gravity_points);
+        select_feature_names(&pipeline,&mut parameters_raw.feature_names);
+    }
+    else if !parameters_raw.preprocess.is_empty() {
+        let (pipeline,points) = Pipeline::fit_transform(&parameters_raw.preprocess,gravity_points);
+        gravity_points = points;
+        if let Some(location) = parameters_raw.report("preprocessing.tsv") {
+            pipeline.write(&location)?;
+        }
+        if let Some(location) = parameters_raw.report("selected_features.tsv").filter(|_| !pipeline.selections().is_empty()) {
+            pipeline.write_selections(parameters_raw.feature_names.as_deref(),&location)?;
+        }
+        select_feature_names(&pipeline,&mut parameters_raw.feature_names);
+    }
+
+    if let Some(dimension) = parameters_raw.project {
+        gravity_points = parameters_raw.projection.project(gravity_points,dimension,&mut parameters_raw.rng());
+        parameters_raw.feature_names = Some(projection::names(dimension));
+    }
+
+    if let Some(target) = parameters_raw.pca {
+        let counts = gravity_points.into_owned();
+        let pca = Pca::fit(counts.view(),target,&mut parameters_raw.rng());
+        gravity_points = pca.transform(counts.view()).into();
+        if let (Some(loadings),Some(variance)) = (parameters_raw.report("pca_loadings.tsv"),parameters_raw.report("pca_variance.tsv")) {
+            pca.write(parameters_raw.feature_names.as_deref(),&loadings,&variance)?;
+        }
+        parameters_raw.feature_names = Some(pca.names());
+    }
+
+    if let Some(steps) = parameters_raw.diffuse {
+        gravity_points = diffusion::diffuse(gravity_points,parameters_raw.diffuse_k.unwrap_or(10),steps,parameters_raw.distance.unwrap_or(Distance::Cosine));
+    }
+
+    if let Some(mode) = parameters_raw.sanitizing() {
+        let (points,sanitization) = Sanitization::sanitize(gravity_points,mode,parameters_raw.collinear);
+        gravity_points = points;
+        let names = parameters_raw.feature_names.clone();
+        keep_feature_names(sanitization.features,&sanitization.kept,&mut parameters_raw.feature_names);
+        if let Some(location) = parameters_raw.report("sanitization.tsv") {
+            sanitization.write(names.as_deref(),&location)?;
+        }
+    }
+
+    let rounds = *parameters_raw.borrow.as_ref().unwrap_or(&0);
+    match parameters_raw.borrow_neighbors {
+        Some(k) if rounds > 0 => {
+            let graph = FeatureGraph::build(&gravity_points,k,parameters_raw.distance.unwrap_or(Distance::Cosine));
+            if let Some(location) = parameters_raw.report("feature_graph.tsv") {
+                graph.write(parameters_raw.feature_names.as_deref(),&location)?;
+            }
+            for _ in 0..rounds {
+                gravity_points = graph.apply(gravity_points);
+            }
+        },
+        _ => {
+            for _ in 0..rounds  {
+                gravity_points = borrow(gravity_points.into_owned(),parameters_raw.distance.as_ref().unwrap_or(&Distance::Cosine),parameters_raw.verbose).into();
+            }
+        },
+    }
+
+    let (samples,features) = gravity_points.shape();
+
+    if let Some(sample_names) = parameters_raw.sample_names.as_ref() {
+        if sample_names.len() != samples {
+            panic!("Read {} sample names for {} samples",sample_names.len(),samples);
+        }
+    }
+
+    if let Some(feature_names) = parameters_raw.feature_names.as_ref() {
+        if feature_names.len() != features {
+            panic!("Read {} feature names for {} features",feature_names.len(),features);
+        }
+    }
+
+    if parameters_raw.memberships && !parameters_raw.reporting() {
+        eprintln!("WARNING: memberships are only written alongside the other reports, please give a prefix with -error or a directory with -outdir");
     }
 
     let mut parameters = Arc::new(parameters_raw);
 
     eprintln!("Clustering with parameters:{:?}",parameters);
 
+    if let Some(rates) = parameters.sweep.clone() {
+        return sweep(gravity_points,&parameters,rates)
+    }
+
     let mut field = GravityField::init(gravity_points, parameters.clone());
 
     match parameters.command {
@@ -66,9 +202,9 @@ fn main() -> Result<(),Error> {
             let mut predictions = field.fuzzy_predict();
 
             if parameters.refining {
-                let mut refining_parameters = Arc::make_mut(&mut parameters).clone();
+                let refining_parameters = Arc::make_mut(&mut parameters).clone();
 
-                let mut refining_field = GravityField::init(final_positions.clone(),Arc::new(refining_parameters));
+                let mut refining_field = GravityField::init(final_positions.clone().into(),Arc::new(refining_parameters));
 
                 final_positions = refining_field.fit();
                 predictions = refining_field.fuzzy_predict();
@@ -76,10 +212,10 @@ fn main() -> Result<(),Error> {
                 field = refining_field;
             }
 
-            write_vector(predictions, &parameters.report_address)?;
+            write_named_vector(predictions, parameters.sample_names.as_deref(), &parameters.labels())?;
 
-            if parameters.dump_error.is_some() {
-                write_array(final_positions, &parameters.dump_error.clone().map(|x| [x,"final_pos.tsv".to_string()].join("")))?;
+            if parameters.reporting() {
+                write_reports(&field,final_positions,&parameters)?;
                 // let clusters: Vec<Array<f64,Ix1>> = field.clusters.iter().map(|x| x.id).collect();
                 // let mut cluster_acc = Array::zeros((0,field.gravity_points.shape()[1]));
                 // for cluster in clusters {
@@ -94,7 +230,7 @@ fn main() -> Result<(),Error> {
             let mut final_positions = field.fuzzy_fit_single();
             let mut predictions = field.fuzzy_predict();
 
-            let initial_fuzz = field.fuzz;
+            let initial_fuzz = field.fuzz.clone();
 
             if parameters.refining {
 
@@ -102,7 +238,7 @@ fn main() -> Result<(),Error> {
 
                 refining_parameters.scaling_factor = refining_parameters.scaling_factor.map(|x| x/5.);
 
-                let mut refining_field = GravityField::init(final_positions,Arc::new(refining_parameters));
+                let mut refining_field = GravityField::init(final_positions.into(),Arc::new(refining_parameters));
 
                 final_positions = refining_field.fuzzy_fit_single();
 
@@ -113,32 +249,177 @@ fn main() -> Result<(),Error> {
                 field = refining_field;
             }
 
-            write_vector(predictions, &parameters.report_address)?;
-            if parameters.dump_error.is_some() {
-                write_array(final_positions, &parameters.dump_error.clone().map(|x| [x,"final_pos.tsv".to_string()].join("")))?;
+            write_named_vector(predictions, parameters.sample_names.as_deref(), &parameters.labels())?;
+            if parameters.reporting() {
+                write_reports(&field,final_positions,&parameters)?;
             }
             Ok(())
         },
         Command::Mobile => {
-            let mut final_positions = field.fuzzy_fit_mobile();
-            let mut predictions = field.fuzzy_predict();
-
-            write_vector(predictions, &parameters.report_address)?;
-            if parameters.dump_error.is_some() {
-                write_array(final_positions, &parameters.dump_error.clone().map(|x| [x,"final_pos.tsv".to_string()].join("")))?;
-                let mut cluster_file = OpenOptions::new().create(true).append(true).open([parameters.dump_error.as_ref().unwrap(),"clusters.tsv"].join("")).unwrap();
-                for cluster in field.clusters {
-                    cluster_file.write(format!("{:?}",cluster.center()).as_bytes())?;
-                }
+            let final_positions = field.fuzzy_fit_mobile();
+            let predictions = match parameters.consensus {
+                Some(threshold) => field.consensus_predict(threshold),
+                None => field.fuzzy_predict(),
+            };
+
+            write_named_vector(predictions, parameters.sample_names.as_deref(), &parameters.labels())?;
+            if parameters.reporting() {
+                write_reports(&field,final_positions,&parameters)?;
             }
 
             Ok(())
-        }
+        },
+        Command::Convert => Ok(()),
 
     }
 
 }
 
+// Clusters the same points once per subsample rate. Refining is not applied,
+// each resolution is a single fit of the chosen command.
+
+fn sweep(gravity_points: Points, parameters: &Parameters, rates: Vec<usize>) -> Result<(),Error> {
+
+    let mut sweep = Sweep::new();
+
+    for rate in rates {
+
+        eprintln!("Sweep, subsample rate:{:?}",rate);
+
+        let mut resolution_parameters = parameters.clone();
+        resolution_parameters.sample_subsample = Some(rate);
+
+        let mut field = GravityField::init(gravity_points.clone(), Arc::new(resolution_parameters));
+
+        let predictions = match parameters.command {
+            Command::FitPredict => {
+                field.fit();
+                field.fuzzy_predict()
+            },
+            Command::Fuzzy => {
+                field.fuzzy_fit_single();
+                field.fuzzy_predict()
+            },
+            Command::Mobile => {
+                field.fuzzy_fit_mobile();
+                match parameters.consensus {
+                    Some(threshold) => field.consensus_predict(threshold),
+                    None => field.fuzzy_predict(),
+                }
+            },
+            _ => panic!("Sweeps need a clustering command: fitpredict, fuzzy or mobile"),
+        };
+
+        sweep.push(rate,predictions);
+    }
+
+    let header = parameters.sample_names.as_ref().map(|names| {
+        let mut header = vec!["rate".to_string()];
+        header.extend(names.iter().cloned());
+        header
+    });
+    write_named_array(sweep.label_table(), None, header.as_deref(), &parameters.labels())?;
+
+    if parameters.reporting() {
+        sweep.write_tree(&parameters.report("sweep_tree.tsv").unwrap())?;
+        write_manifest(parameters,vec!["sweep_tree.tsv"])?;
+    }
+    else {
+        eprintln!("WARNING: the sweep tree is only written alongside the other reports, please give a prefix with -error or a directory with -outdir");
+    }
+
+    Ok(())
+}
+
+fn write_reports(field: &GravityField, final_positions: Array<f64,Ix2>, parameters: &Parameters) -> Result<(),Error> {
+    let report = |name: &str| parameters.report(name).unwrap();
+    let sample_names = parameters.sample_names.as_deref();
+    let (final_pos,fuzz) = (parameters.array_report("final_pos"),parameters.array_report("fuzz"));
+    let mut written = vec![final_pos,fuzz,"outlier_scores.tsv","clusters.tsv","clusters.json"];
+    write_named_array(final_positions, sample_names, parameters.feature_names.as_deref(), &parameters.report(final_pos))?;
+    write_named_vector(field.fuzz.clone(), sample_names, &parameters.report(fuzz))?;
+    write_named_vector(field.outlier_scores(), sample_names, &parameters.report("outlier_scores.tsv"))?;
+    let cluster_reports = field.cluster_reports();
+    write_cluster_tsv(&cluster_reports,&report("clusters.tsv"))?;
+    write_cluster_json(&cluster_reports,&report("clusters.json"))?;
+    if !cluster_reports.is_empty() {
+        let cluster_ids: Vec<String> = cluster_reports.iter().map(|x| x.id.to_string()).collect();
+        let centers = Array::from_shape_vec(
+            (cluster_reports.len(),cluster_reports[0].center.len()),
+            cluster_reports.iter().flat_map(|x| x.center.iter().cloned()).collect()
+        ).unwrap();
+        write_named_array(centers, Some(&cluster_ids), parameters.feature_names.as_deref(), &parameters.report("cluster_centers.tsv"))?;
+        written.push("cluster_centers.tsv");
+    }
+    if let Some(memberships) = field.memberships() {
+        write_named_vector(membership_entropy(&memberships), sample_names, &parameters.report("membership_entropy.tsv"))?;
+        write_named_array(memberships, sample_names, None, &parameters.report("memberships.tsv"))?;
+        written.extend(&["membership_entropy.tsv","memberships.tsv"]);
+    }
+    if let Some(co_association) = field.consensus.as_ref() {
+        co_association.write(&report("consensus_atoms.tsv"),&report("consensus.tsv"))?;
+        written.extend(&["consensus_atoms.tsv","consensus.tsv"]);
+    }
+    if let Some(history) = field.merge_history.as_ref() {
+        history.write(&report("merges.tsv"),&report("linkage.tsv"),&report("dendrogram.nwk"))?;
+        written.extend(&["merges.tsv","linkage.tsv","dendrogram.nwk"]);
+    }
+    write_manifest(parameters,written)
+}
+
+// Feature names follow the features kept by each selection step
+fn select_feature_names(pipeline: &Pipeline, feature_names: &mut Option<Vec<String>>) {
+    for selection in pipeline.selections() {
+        keep_feature_names(selection.features,&selection.kept,feature_names);
+    }
+}
+
+fn keep_feature_names(features: usize, kept: &[usize], feature_names: &mut Option<Vec<String>>) {
+    if let Some(names) = feature_names.take() {
+        if names.len() != features {
+            panic!("Read {} feature names for {} features",names.len(),features);
+        }
+        *feature_names = Some(kept.iter().map(|j| names[*j].clone()).collect());
+    }
+}
+
+// Lists what a run wrote into its output directory, after the command line that
+// produced it.
+
+fn write_manifest(parameters: &Parameters, mut written: Vec<&str>) -> Result<(),Error> {
+    let location = match parameters.output_dir.as_ref() {
+        Some(_) => parameters.report("manifest.tsv").unwrap(),
+        None => return Ok(()),
+    };
+    written.insert(0,"validation.tsv");
+    if !parameters.preprocess.is_empty() && parameters.preprocess_from.is_none() {
+        written.insert(1,"preprocessing.tsv");
+        if parameters.preprocess.iter().any(|x| matches!(x,Step::Variable(..))) {
+            written.insert(2,"selected_features.tsv");
+        }
+    }
+    if parameters.pca.is_some() {
+        written.extend(&["pca_loadings.tsv","pca_variance.tsv"]);
+    }
+    if parameters.sanitizing().is_some() {
+        written.push("sanitization.tsv");
+    }
+    if parameters.borrow_neighbors.is_some() && parameters.borrow.unwrap_or(0) > 0 {
+        written.push("feature_graph.tsv");
+    }
+    if parameters.report_address.is_none() {
+        written.insert(0,parameters.array_report("labels"));
+    }
+    let mut manifest = AtomicFile::create(&location)?;
+    writeln!(manifest,"# {}",env::args().collect::<Vec<String>>().join(" "))?;
+    writeln!(manifest,"file\tcontents")?;
+    for name in written {
+        let contents = OUTPUT_FILES.iter().find(|(file,_)| *file == name).map(|(_,contents)| *contents).unwrap_or("");
+        writeln!(manifest,"{}\t{}",name,contents)?;
+    }
+    manifest.commit()
+}
+
 pub fn length(v: ArrayView<f64,Ix1>) -> f64 {
     v.fold(0.,|acc,x| acc+x.powi(2)).sqrt()
 }
@@ -162,3 +443,38 @@ pub fn sq_distance(pa1:ArrayView<f64,Ix1>,pa2:ArrayView<f64,Ix1>) -> f64 {
     Zip::from(pa1).and(pa2).apply(|p1,p2| acc += (*p1 - *p2).powi(2));
     acc
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn manifest_lists_every_file_written() {
+        let directory = env::temp_dir().join(format!("gravity_manifest_{}",std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let counts = directory.join("counts.tsv");
+        let rows: Vec<String> = (0..12).map(|i| {
+            let (a,b) = if i < 6 { (10.,1.) } else { (1.,10.) };
+            format!("{}\t{}\t{}\t3",a + i as f64 * 0.1,b,i % 3)
+        }).collect();
+        fs::write(&counts,rows.join("\n")).unwrap();
+        let output = directory.join("results");
+        let args = [
+            "gravity_clustering","fuzzy","-c",counts.to_str().unwrap(),"-outdir",output.to_str().unwrap(),
+            "-preprocess","log1p,hvf:3","-sanitize","drop","-memberships","-fuzz","3","-ss","6",
+        ];
+        run(args.iter().map(|x| x.to_string())).unwrap();
+
+        let manifest = fs::read_to_string(output.join("manifest.tsv")).unwrap();
+        let listed: HashSet<String> = manifest.lines().skip(2).map(|line| line.split('\t').next().unwrap().to_string()).collect();
+        let written: HashSet<String> = fs::read_dir(&output).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "manifest.tsv")
+            .collect();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(written.contains("selected_features.tsv") && written.contains("sanitization.tsv") && written.contains("memberships.tsv"));
+        assert_eq!(listed,written);
+    }
+}
@@ -0,0 +1,211 @@
+use std::f64;
+use std::sync::Arc;
+use std::cmp::Ordering;
+
+use ndarray::{Array,Axis,Ix1,Ix2};
+use rayon::prelude::*;
+
+use io::Parameters;
+use binary::Points;
+
+// Missing values are read as NaN, which the clustering itself can't handle. Rows,
+// then columns, with more than the -max_missing fraction of missing values are
+// dropped, along with their names and weights, and whatever is left is imputed.
+
+#[derive(Debug,Clone,Copy)]
+pub enum Impute {
+    Mean,
+    Median,
+    Knn(usize),
+}
+
+impl Impute {
+    pub fn parse(input: &str) -> Impute {
+        match input {
+            "mean" => Impute::Mean,
+            "median" => Impute::Median,
+            "knn" => Impute::Knn(5),
+            _ => panic!("Not a valid imputation, please choose from mean, median or knn"),
+        }
+    }
+}
+
+pub fn handle_missing(points: Points, parameters: &mut Parameters) -> Points {
+
+    if !points.has_nan() {
+        return points
+    }
+
+    let mut counts = points.into_owned();
+    let missing = counts.iter().filter(|x| x.is_nan()).count();
+
+    eprintln!("Missing values: {:?}",missing);
+
+    if let Some(threshold) = parameters.max_missing {
+
+        let (samples,features) = counts.dim();
+
+        let rows: Vec<usize> = (0..counts.rows())
+            .filter(|i| missing_fraction(counts.row(*i).iter()) <= threshold)
+            .collect();
+        counts = counts.select(Axis(0),&rows);
+
+        let columns: Vec<usize> = (0..counts.cols())
+            .filter(|j| missing_fraction(counts.column(*j).iter()) <= threshold)
+            .collect();
+        counts = counts.select(Axis(1),&columns);
+
+        eprintln!("Dropped {:?} rows and {:?} columns above {:?} missing",samples - rows.len(),features - columns.len(),threshold);
+
+        if let Some(sample_names) = parameters.sample_names.as_mut() {
+            *sample_names = rows.iter().map(|i| sample_names[*i].clone()).collect();
+        }
+        if let Some(feature_names) = parameters.feature_names.as_mut() {
+            *feature_names = columns.iter().map(|j| feature_names[*j].clone()).collect();
+        }
+        if let Some(weights) = parameters.weights.as_ref() {
+            parameters.weights = Some(Arc::new(weights.subset(&rows)));
+        }
+    }
+
+    if let Some(method) = parameters.impute {
+        let imputed = counts.iter().filter(|x| x.is_nan()).count();
+        impute(&mut counts,method);
+        eprintln!("Imputed {:?} values by {:?}",imputed,method);
+    }
+
+    counts.into()
+}
+
+fn missing_fraction<'a, I: ExactSizeIterator<Item = &'a f64>>(values: I) -> f64 {
+    let total = values.len();
+    let missing = values.filter(|x| x.is_nan()).count();
+    if total > 0 { missing as f64 / total as f64 } else { 0. }
+}
+
+pub fn impute(counts: &mut Array<f64,Ix2>, method: Impute) {
+    match method {
+        Impute::Mean => impute_columns(counts,|present| present.iter().sum::<f64>() / present.len() as f64),
+        Impute::Median => impute_columns(counts,median),
+        Impute::Knn(k) => impute_knn(counts,k),
+    }
+}
+
+// Fills the missing values of each column with a statistic of the present ones, or
+// with 0 if a column has nothing present.
+
+fn impute_columns<F: Fn(&mut [f64]) -> f64>(counts: &mut Array<f64,Ix2>, statistic: F) {
+    for mut column in counts.axis_iter_mut(Axis(1)) {
+        let mut present: Vec<f64> = column.iter().cloned().filter(|x| !x.is_nan()).collect();
+        let fill = if present.is_empty() { 0. } else { statistic(&mut present) };
+        column.mapv_inplace(|x| if x.is_nan() { fill } else { x });
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a,b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let n = values.len();
+    if n % 2 == 1 { values[n/2] } else { (values[n/2 - 1] + values[n/2]) / 2. }
+}
+
+// Each missing value is the mean of that feature over the k nearest samples that
+// have it. Distances are squared Euclidean over the features both samples have,
+// scaled up by the fraction of features that were compared.
+
+fn impute_knn(counts: &mut Array<f64,Ix2>, k: usize) {
+
+    let features = counts.cols();
+    let incomplete: Vec<usize> = (0..counts.rows()).filter(|i| counts.row(*i).iter().any(|x| x.is_nan())).collect();
+
+    let filled: Vec<(usize,Array<f64,Ix1>)> = incomplete.par_iter().map(|i| {
+
+        let mut neighbors: Vec<(usize,f64)> = (0..counts.rows())
+            .filter(|j| j != i)
+            .filter_map(|j| {
+                let (mut shared,mut distance) = (0,0.);
+                for (a,b) in counts.row(*i).iter().zip(counts.row(j).iter()) {
+                    if !a.is_nan() && !b.is_nan() {
+                        shared += 1;
+                        distance += (a - b).powi(2);
+                    }
+                }
+                if shared > 0 { Some((j,distance * features as f64 / shared as f64)) } else { None }
+            })
+            .collect();
+        neighbors.sort_by(|a,b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+
+        let mut row = counts.row(*i).to_owned();
+        for (f,value) in row.iter_mut().enumerate() {
+            if value.is_nan() {
+                let present: Vec<f64> = neighbors.iter().map(|(j,_)| counts[[*j,f]]).filter(|x| !x.is_nan()).take(k).collect();
+                *value = if present.is_empty() { f64::NAN } else { present.iter().sum::<f64>() / present.len() as f64 };
+            }
+        }
+        (*i,row)
+    }).collect();
+
+    for (i,row) in filled {
+        counts.row_mut(i).assign(&row);
+    }
+
+    // Anything no neighbor had
+    impute(counts,Impute::Mean);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use weights::Weights;
+
+    const NAN: f64 = f64::NAN;
+
+    #[test]
+    fn dropping_rows_drops_their_names_and_weights() {
+        let counts = Array::from_shape_vec((4,4),vec![
+            1.,2.,3.,NAN,
+            NAN,NAN,1.,NAN,
+            4.,NAN,6.,NAN,
+            7.,8.,9.,5.,
+        ]).unwrap();
+        let mut parameters = Parameters::empty();
+        parameters.max_missing = Some(0.5);
+        parameters.impute = Some(Impute::Mean);
+        parameters.sample_names = Some(vec!["a".to_string(),"b".to_string(),"c".to_string(),"d".to_string()]);
+        parameters.feature_names = Some(vec!["w".to_string(),"x".to_string(),"y".to_string(),"z".to_string()]);
+        parameters.weights = Some(Arc::new(Weights::new(vec![1.,2.,3.,4.])));
+        let counts = handle_missing(counts.into(),&mut parameters).into_owned();
+        assert_eq!(counts,Array::from_shape_vec((3,3),vec![1.,2.,3.,4.,5.,6.,7.,8.,9.]).unwrap());
+        assert_eq!(parameters.sample_names,Some(vec!["a".to_string(),"c".to_string(),"d".to_string()]));
+        assert_eq!(parameters.feature_names,Some(vec!["w".to_string(),"x".to_string(),"y".to_string()]));
+        let weights = parameters.weights.unwrap();
+        assert_eq!((0..weights.len()).map(|i| weights.weight(i)).collect::<Vec<f64>>(),vec![1.,3.,4.]);
+    }
+
+    #[test]
+    fn knn_distances_are_scaled_by_the_features_compared() {
+        // Unscaled, the third row (64 over one feature) would be nearer than the
+        // second (100 over two), scaled it is 192 against 150
+        let mut counts = Array::from_shape_vec((4,3),vec![
+            0.,0.,NAN,
+            0.,10.,100.,
+            8.,NAN,200.,
+            5.,5.,300.,
+        ]).unwrap();
+        impute(&mut counts,Impute::Knn(2));
+        assert_eq!(counts[[0,2]],200.);
+        assert!(!counts.iter().any(|x| x.is_nan()));
+    }
+
+    #[test]
+    fn columns_are_filled_by_their_statistic() {
+        let original = Array::from_shape_vec((4,2),vec![1.,NAN,2.,NAN,NAN,NAN,9.,NAN]).unwrap();
+        let mut mean = original.clone();
+        impute(&mut mean,Impute::Mean);
+        let mut median = original;
+        impute(&mut median,Impute::Median);
+        assert_eq!((mean[[2,0]],median[[2,0]]),(4.,2.));
+        // Nothing present at all falls back to 0
+        assert_eq!(mean.column(1).to_vec(),vec![0.; 4]);
+    }
+}
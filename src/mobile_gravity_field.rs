@@ -1,76 +1,116 @@
 use std::sync::Arc;
-use std::collections::HashSet;
-use ndarray::{Array,Ix1,Ix2,Ix3,Zip,Axis,ArrayView,stack};
+use std::collections::{HashSet,HashMap};
+use ndarray::{Array,Ix1,Ix2,Ix3,Axis,ArrayView};
 use std::f64;
-use std::cmp::max;
+use std::mem;
 use rayon::prelude::*;
 use std::cmp::PartialOrd;
 use std::cmp::Ordering;
 
 use io::{Parameters,Distance};
+use binary::Points;
+use consensus::CoAssociation;
+use dendrogram::{Dendrogram,MergeRecord};
+use report::ClusterReport;
 
 use cluster::Cluster;
 use single_pathfinder::Pathfinder;
-use distance;
-use length;
 
 
+// Points that have been collapsed together in mobile mode. Each row of positions
+// stands for the samples listed in the matching entry of members.
+
+struct Particles {
+    positions: Array<f64,Ix2>,
+    weights: Vec<f64>,
+    members: Vec<Vec<usize>>,
+}
+
 pub struct GravityField {
     samples: usize,
     features: usize,
-    pub initial_positions: Array<f64,Ix2>,
+    pub initial_positions: Points,
     pub current_positions: Option<Arc<Array<f64,Ix2>>>,
     pub fuzz: Array<f64,Ix1>,
     pub clusters: Vec<Cluster>,
+    pub endpoints: Option<Array<f64,Ix3>>,
+    pub round_labels: Vec<Array<i64,Ix1>>,
+    pub consensus: Option<CoAssociation>,
+    pub merge_history: Option<Dendrogram>,
     parameters: Arc<Parameters>,
     distance: Distance,
 }
 
 impl GravityField {
 
-    pub fn init(gravity_points: Array<f64,Ix2>, parameters:Arc<Parameters>) -> GravityField {
+    pub fn init(gravity_points: Points, parameters:Arc<Parameters>) -> GravityField {
 
         eprintln!("Initializing:{:?}", gravity_points.shape());
 
-        assert!(!gravity_points.iter().any(|x| x.is_nan()));
-        let samples = gravity_points.shape()[0];
-        let features = gravity_points.shape()[1];
-        let current_positions = Some(Arc::new(gravity_points.clone()));
+        if gravity_points.has_nan() {
+            panic!("Input contains missing values, which can't be clustered. Drop them with -max_missing or fill them in with -impute");
+        }
+        let (samples,features) = gravity_points.shape();
         let fuzz = Array::zeros(samples);
 
+        if let Some(weights) = parameters.weights.as_ref() {
+            if weights.len() != samples {
+                panic!("Read {} sample weights for {} samples",weights.len(),samples);
+            }
+        }
+
+        // Current positions are only materialized once points start moving, so
+        // mapped inputs are not copied into memory up front.
+
         GravityField {
-            samples: samples,
-            features: features,
+            samples,
+            features,
             initial_positions: gravity_points,
-            current_positions: current_positions,
-            fuzz: fuzz,
+            current_positions: None,
+            fuzz,
             clusters: vec![],
+            endpoints: None,
+            round_labels: vec![],
+            consensus: None,
+            merge_history: None,
             distance: parameters.distance.unwrap_or(Distance::Cosine),
-            parameters: parameters,
+            parameters,
         }
     }
 
 
     pub fn fuzzy_fit_mobile(&mut self) -> Array<f64,Ix2> {
 
-        let fuzz = self.parameters.fuzz.clone().unwrap_or(5);
+        // Every point moves, so there is nothing to gain from keeping the input sparse
+        if self.initial_positions.is_sparse() {
+            let points = mem::replace(&mut self.initial_positions,Points::Owned(Array::zeros((0,0))));
+            self.initial_positions = points.into_owned().into();
+        }
+
+        let fuzz = self.parameters.fuzz.unwrap_or(5);
 
-        let initial_positions = Arc::new(self.initial_positions.clone());
         let mut final_position_matrix: Array<f64,Ix3> = Array::zeros((fuzz,self.samples,self.features));
 
+        self.round_labels.clear();
+
         for mut fuzzy_axis in final_position_matrix.axis_iter_mut(Axis(0)) {
             self.fuzzy_fit_mobile_single();
             let predictions = self.fuzzy_predict();
+            self.round_labels.push(predictions.clone());
             let mut cluster_positions = vec![];
-            for mut cluster in self.clusters.iter_mut() {
-                cluster.array = initial_positions.clone();
-                cluster_positions.push(cluster.center());
+            for cluster in self.clusters.iter() {
+                cluster_positions.push(cluster.center_in(self.initial_positions.view()));
             }
             for (i,prediction) in predictions.iter().enumerate()  {
-                fuzzy_axis.row_mut(i).assign(&cluster_positions[*prediction]);
+                if *prediction < 0 {
+                    fuzzy_axis.row_mut(i).assign(&self.initial_positions.view().row(i));
+                }
+                else {
+                    fuzzy_axis.row_mut(i).assign(&cluster_positions[*prediction as usize]);
+                }
             }
             // eprintln!("{:?}",fuzzy_axis);
-            self.current_positions = Some(Arc::new(self.initial_positions.clone()));
+            self.current_positions = None;
             self.clusters.clear();
         }
 
@@ -85,29 +125,41 @@ impl GravityField {
                 lengths[j] = self.distance.measure(final_position_matrix.slice(s![j,i,..]),final_positions.slice(s![i,..]));
                 // lengths[j] = length((&final_position_matrix.slice(s![j,i,..]) - &final_positions.slice(s![i,..])).view());
             }
-            self.fuzz[i] = lengths.sum() / fuzz as f64;
+            self.fuzz[i] = (lengths.sum() / fuzz as f64).max(self.parameters.collapse.unwrap_or(0.));
         }
 
         self.current_positions = Some(Arc::new(final_positions.clone()));
 
+        if self.parameters.memberships {
+            self.endpoints = Some(final_position_matrix);
+        }
+
         // eprintln!("{:?}", final_positions);
         // eprintln!("{:?}", self.fuzz);
 
         // self.distance = self.parameters.distance.unwrap_or(Distance::Cosine);
 
-        return final_positions
+        final_positions
 
     }
 
 
     pub fn fuzzy_fit_mobile_single(&mut self) -> Array<f64,Ix2> {
 
+        if let Some(tolerance) = self.parameters.collapse {
+            return self.fuzzy_fit_mobile_collapsing(tolerance)
+        }
+
         let mut pathfinders: Vec<Pathfinder> = (0..self.samples).map(|i| Pathfinder::init(i,self.samples,self.features,self.parameters.clone())).collect();
 
         let mut moving_points = pathfinders.len();
 
         let mut step_counter = 0;
 
+        if self.current_positions.is_none() {
+            self.current_positions = Some(Arc::new(self.initial_positions.view().to_owned()));
+        }
+
         while moving_points > 0 {
 
             moving_points = pathfinders.len();
@@ -132,20 +184,19 @@ impl GravityField {
                 // .iter()
                 .par_iter()
                 .map(|pathfinder| {
-                    pathfinder.step(&current_positions.clone())
+                    pathfinder.step(current_positions.view().into())
                 })
                 .collect();
 
             // eprintln!("Stepped");
 
-            for (i,(mut position,new_position_option)) in
+            for (mut position,new_position_option) in
                 Arc::get_mut(&mut current_positions)
                 .unwrap()
                 .outer_iter_mut()
-                .zip(stepped_positions.iter())
-                .enumerate() {
-                    if let Some((new_position,step_length)) = new_position_option {
-                        position.assign(&new_position);
+                .zip(stepped_positions.iter()) {
+                    if let Some((new_position,_step_length)) = new_position_option {
+                        position.assign(new_position);
                     }
                     else {
                         moving_points -= 1;
@@ -153,7 +204,7 @@ impl GravityField {
             }
 
             for pathfinder in pathfinders.iter_mut() {
-                pathfinder.memorize_step(None,&current_positions);
+                pathfinder.memorize_step(None,current_positions.view().into());
             }
 
             // for (pathfinder,step) in pathfinders.iter_mut().zip(stepped_positions) {
@@ -173,7 +224,7 @@ impl GravityField {
         }
 
         for (i,pathfinder) in pathfinders.iter().enumerate() {
-            self.fuzz[i] = pathfinder.sub_fuzz(10,self.current_positions.as_ref().unwrap().clone());
+            self.fuzz[i] = pathfinder.sub_fuzz(10,self.current_positions.as_ref().unwrap().view().into());
         }
 
         Arc::get_mut(self.current_positions.as_mut().unwrap()).unwrap().clone()
@@ -181,11 +232,205 @@ impl GravityField {
 
     }
 
+    // Same stepping as fuzzy_fit_mobile_single, but points that land within the
+    // tolerance of each other are merged into a single weighted particle that
+    // remembers its member samples. Each step then costs one descent per particle
+    // rather than one per sample.
+
+    pub fn fuzzy_fit_mobile_collapsing(&mut self, tolerance: f64) -> Array<f64,Ix2> {
+
+        let positions = match self.current_positions.take() {
+            Some(positions) => positions.as_ref().clone(),
+            None => self.initial_positions.view().to_owned(),
+        };
+        let mut particles = Particles {
+            positions,
+            weights: (0..self.samples).map(|i| self.parameters.weights.as_ref().map(|w| w.weight(i)).unwrap_or(1.)).collect(),
+            members: (0..self.samples).map(|i| vec![i]).collect(),
+        };
+        let mut owners: Vec<usize> = (0..self.samples).collect();
+
+        let mut pathfinders: Vec<Pathfinder> = (0..self.samples).map(|i| Pathfinder::init(i,self.samples,self.features,self.parameters.clone())).collect();
+
+        let mut moving_points = pathfinders.len();
+
+        let mut step_counter = 0;
+
+        while moving_points > 0 {
+
+            let shared_owners = Arc::new(owners.clone());
+
+            let cached_distance_sums: Arc<Vec<f64>> = Arc::new(particles.positions
+                                            .axis_iter(Axis(0))
+                                            .map(|x| {
+                                                x.map(|y| y.powi(2)).sum().sqrt()
+                                            }).collect());
+
+            for pathfinder in pathfinders.iter_mut() {
+                pathfinder.set_cached_distance_sums(cached_distance_sums.clone());
+                pathfinder.set_owners(shared_owners.clone());
+            }
+
+            let stepped_positions: Vec<Option<(Array<f64,Ix1>,f64)>> =
+                pathfinders
+                .par_iter()
+                .map(|pathfinder| {
+                    pathfinder.step(particles.positions.view().into())
+                })
+                .collect();
+
+            moving_points = pathfinders.len();
+
+            for (mut position,new_position_option) in particles.positions.outer_iter_mut().zip(stepped_positions.iter()) {
+                if let Some((new_position,_step_length)) = new_position_option {
+                    position.assign(new_position);
+                }
+                else {
+                    moving_points -= 1;
+                }
+            }
+
+            for pathfinder in pathfinders.iter_mut() {
+                pathfinder.memorize_step(None,particles.positions.view().into());
+            }
+
+            let (collapsed_particles,collapsed_pathfinders) = self.collapse_particles(particles,pathfinders,tolerance);
+
+            particles = collapsed_particles;
+            pathfinders = collapsed_pathfinders;
+
+            for (i,particle_members) in particles.members.iter().enumerate() {
+                for member in particle_members {
+                    owners[*member] = i;
+                }
+            }
+
+            eprintln!("Stepping: {},{},{}", step_counter, moving_points, particles.positions.rows());
+
+            step_counter += 1;
+
+            if step_counter > self.parameters.steps.unwrap_or(500) {
+                break
+            }
+
+        }
+
+        let shared_owners = Arc::new(owners.clone());
+
+        let mut final_positions = Array::zeros((self.samples,self.features));
+
+        // Members of a particle only agree with each other up to the tolerance, so
+        // the fuzz of a particle never drops below it.
+
+        for (pathfinder,particle_members) in pathfinders.iter_mut().zip(particles.members.iter()) {
+            pathfinder.set_owners(shared_owners.clone());
+            let particle_fuzz = pathfinder.sub_fuzz(10,particles.positions.view().into()).max(tolerance);
+            for member in particle_members {
+                final_positions.row_mut(*member).assign(&particles.positions.row(pathfinder.id));
+                self.fuzz[*member] = particle_fuzz;
+            }
+        }
+
+        self.current_positions = Some(Arc::new(final_positions.clone()));
+
+        final_positions
+
+    }
+
+    // Merges particles within the tolerance of a heavier one, measured by the
+    // configured distance. Heavier particles lead, and every other particle joins the
+    // nearest leader within the tolerance. Leaders are kept sorted by a key that
+    // can't differ by more than the window between particles within the tolerance
+    // (a projection onto the diagonal, of the unit vector under cosine), so only
+    // leaders in that window are measured. Other distances measure every leader.
+
+    fn collapse_particles(&self, particles: Particles, pathfinders: Vec<Pathfinder>, tolerance: f64) -> (Particles,Vec<Pathfinder>) {
+
+        let Particles { positions, weights, members } = particles;
+
+        let diagonal = (self.features as f64).sqrt();
+        let (keys,window): (Vec<f64>,f64) = match self.distance {
+            Distance::Euclidean => (positions.outer_iter().map(|p| p.sum() / diagonal).collect(),tolerance),
+            Distance::Cosine => (positions.outer_iter().map(|p| {
+                let norm = p.dot(&p).sqrt();
+                if norm > 0. { p.sum() / (norm * diagonal) } else { 0. }
+            }).collect(),(2. * tolerance).sqrt()),
+            _ => (vec![0.; positions.rows()],f64::INFINITY),
+        };
+
+        let mut order: Vec<usize> = (0..positions.rows()).collect();
+        order.sort_by(|a,b| weights[*b].partial_cmp(&weights[*a]).unwrap_or(Ordering::Equal).then(a.cmp(b)));
+
+        // (key, group) of every leader, sorted by key
+        let mut leaders: Vec<(f64,usize)> = vec![];
+        let mut groups: Vec<Vec<usize>> = vec![];
+
+        for i in order {
+            let start = leaders.partition_point(|(key,_)| *key < keys[i] - window);
+            let end = leaders.partition_point(|(key,_)| *key <= keys[i] + window);
+            let nearest = leaders[start..end].iter()
+                .map(|(_,group)| (*group,self.distance.measure(positions.row(groups[*group][0]),positions.row(i))))
+                .filter(|(_,distance)| *distance < tolerance)
+                .min_by(|a,b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+            match nearest {
+                Some((group,_)) => groups[group].push(i),
+                None => {
+                    let position = leaders.partition_point(|(key,_)| *key < keys[i]);
+                    leaders.insert(position,(keys[i],groups.len()));
+                    groups.push(vec![i]);
+                },
+            }
+        }
+
+        if groups.len() == positions.rows() {
+            return (Particles { positions, weights, members },pathfinders)
+        }
+
+        groups.sort_by_key(|group| group[0]);
+
+        let mut collapsed_particles = Array::zeros((groups.len(),self.features));
+        let mut collapsed_weights = Vec::with_capacity(groups.len());
+        let mut collapsed_members = Vec::with_capacity(groups.len());
+        let mut leaders = HashMap::with_capacity(groups.len());
+
+        for (i,group) in groups.iter().enumerate() {
+            let group_weight: f64 = group.iter().map(|j| weights[*j]).sum();
+            let mut position = collapsed_particles.row_mut(i);
+            let mut group_members = vec![];
+            for j in group {
+                let share = if group_weight > 0. { weights[*j] / group_weight } else { 1. / group.len() as f64 };
+                position.scaled_add(share, &positions.row(*j));
+                group_members.extend(members[*j].iter().cloned());
+            }
+            collapsed_weights.push(group_weight);
+            collapsed_members.push(group_members);
+            leaders.insert(group[0],i);
+        }
+
+        let mut collapsed_pathfinders: Vec<Option<Pathfinder>> = (0..groups.len()).map(|_| None).collect();
+
+        for mut pathfinder in pathfinders {
+            if let Some(i) = leaders.get(&pathfinder.id) {
+                pathfinder.id = *i;
+                collapsed_pathfinders[*i] = Some(pathfinder);
+            }
+        }
+
+        let collapsed = Particles {
+            positions: collapsed_particles,
+            weights: collapsed_weights,
+            members: collapsed_members,
+        };
+
+        (collapsed,collapsed_pathfinders.into_iter().map(|x| x.unwrap()).collect())
+
+    }
+
     pub fn fit(&mut self) -> Array<f64,Ix2> {
 
         eprintln!("Starting a fuzzy fit:");
 
-        let shared_positions = Arc::new(self.initial_positions.clone());
+        let shared_positions = self.initial_positions.rows();
         let mut final_positions = Array::zeros((self.samples,self.features));
 
         let position_vec: Vec<(Array<f64,Ix1>,f64)> = (0..self.samples)
@@ -197,7 +442,7 @@ impl GravityField {
                 };
                 // eprintln!("{:?}",shared_positions.row(sample));
                 let mut pathfinder = Pathfinder::init(sample, self.samples,self.features, self.parameters.clone());
-                pathfinder.single_descend(&shared_positions)
+                pathfinder.single_descend(shared_positions)
             }).collect();
 
         for (i,(position,fuzz)) in position_vec.into_iter().enumerate() {
@@ -218,10 +463,12 @@ impl GravityField {
 
         eprintln!("Starting a fuzzy fit:");
 
-        let shared_positions = Arc::new(self.initial_positions.clone());
+        let shared_positions = self.initial_positions.rows();
         let mut final_positions = Array::zeros((self.samples,self.features));
 
-        let position_vec: Vec<(Array<f64,Ix1>,(f64,f64))> = (0..self.samples)
+        let keep_endpoints = self.parameters.memberships;
+
+        let position_vec: Vec<_> = (0..self.samples)
             // .into_iter()
             .into_par_iter()
             .map(|sample| {
@@ -230,14 +477,23 @@ impl GravityField {
                 };
                 // eprintln!("{:?}",shared_positions.row(sample));
                 let mut pathfinder = Pathfinder::init(sample, self.samples,self.features, self.parameters.clone());
-                pathfinder.fuzzy_descend(self.parameters.fuzz,shared_positions.clone())
+                let (position,deviations,endpoints) = pathfinder.fuzzy_descend_endpoints(self.parameters.fuzz,shared_positions);
+                (position,deviations,if keep_endpoints {Some(endpoints)} else {None})
             }).collect();
 
-        for (i,(position,(deviation,_displacement))) in position_vec.into_iter().enumerate() {
+        let fuzz = self.parameters.fuzz.unwrap_or(10);
+        let mut endpoints: Option<Array<f64,Ix3>> = if keep_endpoints {Some(Array::zeros((fuzz,self.samples,self.features)))} else {None};
+
+        for (i,(position,(deviation,_displacement),sample_endpoints)) in position_vec.into_iter().enumerate() {
             final_positions.row_mut(i).assign(&position);
-            self.fuzz[i] = deviation
+            self.fuzz[i] = deviation;
+            if let (Some(endpoints),Some(sample_endpoints)) = (endpoints.as_mut(),sample_endpoints) {
+                endpoints.slice_mut(s![..,i,..]).assign(&sample_endpoints);
+            }
         }
 
+        self.endpoints = endpoints;
+
         // eprintln!("{:?}",shared_positions.row(0));
         // eprintln!("{:?}",final_positions.row(0));
 
@@ -247,24 +503,36 @@ impl GravityField {
 
     }
 
-    pub fn fuzzy_predict(&mut self) -> Array<usize,Ix1> {
+    // Labels each sample with its cluster, or with -1 if the sample is noise. Noise
+    // is anything that failed the fuzz or displacement limits before clustering, or
+    // that ended up in a cluster below the minimum size after merging.
 
-        let mut predictions = Array::zeros(self.samples);
+    pub fn fuzzy_predict(&mut self) -> Array<i64,Ix1> {
+
+        let mut predictions = Array::from_elem(self.samples,-1);
 
         self.cluster_points();
 
         self.merge_clusters();
 
-        for (i,mut cluster) in self.clusters.iter_mut().enumerate() {
+        if let Some(min_size) = self.parameters.min_cluster_size {
+            let clusters = self.clusters.len();
+            self.clusters.retain(|cluster| cluster.weight >= min_size);
+            eprintln!("Dissolved {:?} clusters below minimum size",clusters - self.clusters.len());
+        }
+
+        for (i,cluster) in self.clusters.iter_mut().enumerate() {
             cluster.id = i;
         }
 
         for cluster in &self.clusters {
             for point in &cluster.members {
-                predictions[*point] = cluster.id;
+                predictions[*point] = cluster.id as i64;
             }
         }
 
+        eprintln!("Noise:{:?}",predictions.iter().filter(|x| **x < 0).count());
+
         for cluster in &self.clusters {
             eprintln!("C:{:?},{:?}",cluster.id,cluster.members.len())
         }
@@ -272,6 +540,50 @@ impl GravityField {
         predictions
     }
 
+    // Labels samples by cutting the co-association of the rounds of fuzzy_fit_mobile
+    // instead of clustering the averaged positions. Clusters are rebuilt from the
+    // consensus labels so that they can be reported as usual.
+
+    pub fn consensus_predict(&mut self, threshold: f64) -> Array<i64,Ix1> {
+
+        let co_association = CoAssociation::from_labels(&self.round_labels);
+        let labels = co_association.cut(threshold);
+        self.consensus = Some(co_association);
+        self.merge_history = None;
+
+        let final_positions = self.current_positions.as_ref().unwrap().clone();
+
+        let mut clusters: Vec<Cluster> = vec![];
+        for (i,label) in labels.iter().enumerate() {
+            let label = *label as usize;
+            if label < clusters.len() {
+                clusters[label].merge_point(final_positions.row(i),i);
+            }
+            else {
+                clusters.push(Cluster::init(label,final_positions.clone(),i,self.parameters.clone()));
+            }
+        }
+
+        eprintln!("Consensus clusters: {:?}",clusters.len());
+
+        self.clusters = clusters;
+
+        if let Some(min_size) = self.parameters.min_cluster_size {
+            self.clusters.retain(|cluster| cluster.weight >= min_size);
+        }
+
+        let mut predictions = Array::from_elem(self.samples,-1);
+
+        for (i,cluster) in self.clusters.iter_mut().enumerate() {
+            cluster.id = i;
+            for point in &cluster.members {
+                predictions[*point] = i as i64;
+            }
+        }
+
+        predictions
+    }
+
     // pub fn very_fuzzy_predict(&mut self) -> Array<usize,Ix1> {
     //
     // }
@@ -280,17 +592,19 @@ impl GravityField {
 
         let final_positions = self.current_positions.as_ref().unwrap();
 
-        let mut available_points: HashSet<usize> = (0..final_positions.shape()[0]).into_iter().collect();
+        let mut available_points: HashSet<usize> = (0..final_positions.shape()[0]).filter(|i| self.supported(*i)).collect();
 
         let first_cluster_candidate = self.best_cluster_candidate(Some(&available_points));
 
         if let Some(first_cluster_ind) = first_cluster_candidate {
 
+            // The seed is already a member, like the seeds of later clusters
+            available_points.remove(&first_cluster_ind);
             let first_cluster = Cluster::init(1,final_positions.clone(),first_cluster_ind,self.parameters.clone());
 
             self.clusters.push(first_cluster);
 
-            while available_points.len() > 0 {
+            while !available_points.is_empty() {
 
                 let mut moved_points = vec![];
 
@@ -298,8 +612,6 @@ impl GravityField {
 
                     let point = final_positions.row(point_index);
 
-                    let displacement = self.distance.measure(self.initial_positions.row(point_index),point);
-
                     // let displacement = length((&self.initial_positions.row(point_index) - &point).view());
 
                     let mut distances_to_clusters = vec![];
@@ -356,7 +668,7 @@ impl GravityField {
                     available_points.remove(point);
                 }
 
-                if moved_points.len() < 1 {
+                if moved_points.is_empty() {
                     if let Some(new_cluster_point) = self.best_cluster_candidate(Some(&available_points)) {
                         available_points.remove(&new_cluster_point);
                         let new_cluster = Cluster::init(self.clusters.len()+1, final_positions.clone(), new_cluster_point,self.parameters.clone());
@@ -382,7 +694,9 @@ impl GravityField {
     pub fn merge_clusters(&mut self) {
 
         let mut clusters = self.clusters.clone();
-        let final_positions = self.current_positions.as_ref().unwrap();
+
+        let mut history = Dendrogram::new(clusters.len());
+        let mut nodes: Vec<usize> = (0..clusters.len()).collect();
 
 
         loop {
@@ -394,11 +708,9 @@ impl GravityField {
 
                 let c1 = &clusters[i];
 
-                'j_loop: for j in 0..clusters.len() {
+                for (j,c2) in clusters.iter().enumerate() {
                     if i != j {
 
-                        let c2 = &clusters[j];
-
                         // if self.parameters.distance.unwrap_or(Distance::Cosine).measure(c1.center.view(),c2.center.view()) < (c1.radius + c2.radius)*2. {
                         //     eprintln!("Failed");
                         //     eprintln!("C1:{:?}",c1.center);
@@ -426,9 +738,14 @@ impl GravityField {
             }
 
             if let Some((c1i,c2i)) = merge_candidates {
-                let new_cluster = clusters[c1i].merge_cluster(&clusters[c2i]);
+                let mut new_cluster = clusters[c1i].merge_cluster(&clusters[c2i]);
                 // eprintln!("N:{:?}",new_cluster.center);
                 // eprintln!("N:{:?}",new_cluster.radius);
+                let record = self.merge_record(&clusters,&nodes,(c1i,c2i),&new_cluster,true);
+                // The largest threshold any merge into this cluster needed
+                new_cluster.merge_threshold = Some(new_cluster.merge_threshold.map_or(record.threshold,|x| x.max(record.threshold)));
+                nodes[c1i] = history.record(record);
+                nodes.remove(c2i);
                 clusters[c1i] = new_cluster;
                 clusters.remove(c2i);
             }
@@ -440,19 +757,132 @@ impl GravityField {
 
         eprintln!("Merged Clusters: {:?}",clusters.len());
 
+        // Keep joining the closest remaining clusters, for the record only, so the
+        // history ends in a single tree.
+
+        let mut remaining = clusters.clone();
+
+        while remaining.len() > 1 {
+            let mut closest = (0,1,f64::INFINITY);
+            for i in 0..remaining.len() {
+                for j in (i+1)..remaining.len() {
+                    let distance = self.distance.measure(remaining[i].center.view(),remaining[j].center.view());
+                    if distance < closest.2 {
+                        closest = (i,j,distance);
+                    }
+                }
+            }
+            let (c1i,c2i,_) = closest;
+            let new_cluster = remaining[c1i].merge_cluster(&remaining[c2i]);
+            nodes[c1i] = history.record(self.merge_record(&remaining,&nodes,(c1i,c2i),&new_cluster,false));
+            nodes.remove(c2i);
+            remaining[c1i] = new_cluster;
+            remaining.remove(c2i);
+        }
+
+        self.merge_history = Some(history);
+
         self.clusters = clusters;
     }
 
+    fn merge_record(&self, clusters: &[Cluster], nodes: &[usize], (c1i,c2i): (usize,usize), merged: &Cluster, within_threshold: bool) -> MergeRecord {
+        let (c1,c2) = (&clusters[c1i],&clusters[c2i]);
+        MergeRecord {
+            first: nodes[c1i],
+            second: nodes[c2i],
+            distance: self.distance.measure(c1.center.view(),c2.center.view()),
+            threshold: (c1.radius.sqrt() + c2.radius.sqrt()).powi(2),
+            size: merged.members.len(),
+            within_threshold,
+        }
+    }
+
+    // For every sample, the fraction of its individual descents that ended closest to
+    // each of the final clusters. Only available when endpoints were kept.
+
+    pub fn memberships(&self) -> Option<Array<f64,Ix2>> {
+        let endpoints = self.endpoints.as_ref()?;
+        let descents = endpoints.shape()[0];
+        let mut memberships = Array::zeros((self.samples,self.clusters.len()));
+        if self.clusters.is_empty() {
+            return Some(memberships)
+        }
+        for i in 0..self.samples {
+            for j in 0..descents {
+                let endpoint = endpoints.slice(s![j,i,..]);
+                let nearest = self.clusters
+                    .iter()
+                    .map(|cluster| self.distance.measure(endpoint,cluster.center.view()))
+                    .enumerate()
+                    .min_by(|a,b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Greater))
+                    .map(|(k,_)| k)
+                    .unwrap();
+                memberships[[i,nearest]] += 1.;
+            }
+        }
+        memberships /= descents as f64;
+        Some(memberships)
+    }
+
+    pub fn displacement(&self, point: usize) -> f64 {
+        match self.current_positions.as_ref() {
+            Some(positions) => self.distance.measure(self.initial_positions.row(point).view(),positions.row(point)),
+            None => 0.,
+        }
+    }
+
+    // Whether a point is allowed to seed or join a cluster at all
+
+    fn supported(&self, point: usize) -> bool {
+        let fuzz_ok = self.parameters.max_fuzz.map(|limit| self.fuzz[point] <= limit).unwrap_or(true);
+        let displacement_ok = self.parameters.max_displacement.map(|limit| self.displacement(point) <= limit).unwrap_or(true);
+        fuzz_ok && displacement_ok
+    }
+
+    // Fuzz and displacement, each relative to its median over all samples, averaged.
+    // Values near 1 are typical, large values mark points that were either unstable
+    // between descents or travelled unusually far to reach a cluster.
+
+    pub fn outlier_scores(&self) -> Array<f64,Ix1> {
+        let displacements: Array<f64,Ix1> = (0..self.samples).map(|i| self.displacement(i)).collect();
+        let fuzz_scale = typical_scale(self.fuzz.view());
+        let displacement_scale = typical_scale(displacements.view());
+        let mut scores = Array::zeros(self.samples);
+        for i in 0..self.samples {
+            let relative_fuzz = if fuzz_scale > 0. { self.fuzz[i] / fuzz_scale } else { 0. };
+            let relative_displacement = if displacement_scale > 0. { displacements[i] / displacement_scale } else { 0. };
+            scores[i] = (relative_fuzz + relative_displacement) / 2.;
+        }
+        scores
+    }
+
+    pub fn cluster_reports(&self) -> Vec<ClusterReport> {
+        let sample_names = self.parameters.sample_names.as_ref();
+        self.clusters.iter().map(|cluster| {
+            let member_fuzz: Vec<f64> = cluster.members.iter().map(|i| self.fuzz[*i]).collect();
+            ClusterReport {
+                id: cluster.id,
+                size: cluster.members.len(),
+                weight: cluster.weight,
+                center: cluster.center().to_vec(),
+                radius: cluster.radius,
+                mean_fuzz: member_fuzz.iter().sum::<f64>() / member_fuzz.len().max(1) as f64,
+                max_fuzz: member_fuzz.iter().cloned().fold(0.,f64::max),
+                merge_threshold: cluster.merge_threshold,
+                members: cluster.members.iter().map(|i| {
+                    sample_names.and_then(|names| names.get(*i)).cloned().unwrap_or_else(|| i.to_string())
+                }).collect(),
+            }
+        }).collect()
+    }
+
     pub fn best_cluster_candidate(&self, from_points: Option<&HashSet<usize>>) -> Option<usize> {
 
         let mut best_candidate = (0,f64::MAX);
         let mut any_candidate = false;
-        let mut candidate_points = HashSet::with_capacity(0);
-        if from_points.is_some() {
-            candidate_points = from_points.unwrap().clone();
-        }
-        else {
-            candidate_points = (0..self.samples).into_iter().collect();
+        let candidate_points: HashSet<usize> = match from_points {
+            Some(points) => points.clone(),
+            None => (0..self.samples).collect(),
         };
         for point in candidate_points.iter() {
             if self.fuzz[*point] < best_candidate.1 {
@@ -476,3 +906,143 @@ impl GravityField {
     //     array
     // }
 }
+
+// The median, or the mean if more than half the values are zero
+
+fn typical_scale(values: ArrayView<f64,Ix1>) -> f64 {
+    let mut sorted: Vec<f64> = values.iter().cloned().filter(|x| x.is_finite()).collect();
+    if sorted.is_empty() {
+        return 0.
+    }
+    sorted.sort_by(|a,b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let median = sorted[sorted.len()/2];
+    if median > 0. {
+        median
+    }
+    else {
+        sorted.iter().sum::<f64>() / sorted.len() as f64
+    }
+}
+
+// Shannon entropy of each row in bits. 0 means every descent agreed, 1 means a
+// sample was split evenly between two clusters.
+
+pub fn membership_entropy(memberships: &Array<f64,Ix2>) -> Array<f64,Ix1> {
+    memberships
+        .outer_iter()
+        .map(|row| row.iter().filter(|p| **p > 0.).map(|p| p * (1. / p).log2()).sum::<f64>())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use weights::Weights;
+
+    fn field(initial: Vec<f64>, current: Vec<f64>, fuzz: Vec<f64>, parameters: Parameters) -> GravityField {
+        let samples = fuzz.len();
+        let initial = Array::from_shape_vec((samples,initial.len() / samples),initial).unwrap();
+        let current = Array::from_shape_vec(initial.dim(),current).unwrap();
+        let mut field = GravityField::init(initial.into(),Arc::new(parameters));
+        field.current_positions = Some(Arc::new(current));
+        field.fuzz = Array::from(fuzz);
+        field
+    }
+
+    fn euclidean() -> Parameters {
+        let mut parameters = Parameters::empty();
+        parameters.distance = Some(Distance::Euclidean);
+        parameters
+    }
+
+    #[test]
+    fn the_first_seed_is_counted_once() {
+        let positions = vec![0.,0.,0.1,0.,10.,10.];
+        let mut field = field(positions.clone(),positions,vec![0.1,0.5,0.5],euclidean());
+        let labels = field.fuzzy_predict();
+        assert_eq!(labels.to_vec(),vec![0,0,1]);
+        let mut members = field.clusters[0].members.clone();
+        members.sort();
+        assert_eq!(members,vec![0,1]);
+        assert_eq!(field.clusters[0].weight,2.);
+    }
+
+    // Three close points, a heavy one far away, and a close one that is too fuzzy
+    fn noisy(min_cluster_size: f64) -> GravityField {
+        let positions = vec![0.,0.,0.1,0.,0.,0.1,10.,10.,0.,0.];
+        let mut parameters = euclidean();
+        parameters.weights = Some(Arc::new(Weights::new(vec![1.,1.,1.,5.,1.])));
+        parameters.max_fuzz = Some(1.);
+        parameters.min_cluster_size = Some(min_cluster_size);
+        field(positions.clone(),positions,vec![0.5,0.5,0.5,0.1,5.],parameters)
+    }
+
+    #[test]
+    fn unsupported_points_are_noise() {
+        let labels = noisy(1.).fuzzy_predict();
+        assert_eq!(labels.to_vec(),vec![1,1,1,0,-1]);
+    }
+
+    #[test]
+    fn small_clusters_are_dissolved_by_weight() {
+        // The far cluster has one member but weighs 5, the close one has three weighing 3
+        let mut field = noisy(4.);
+        let labels = field.fuzzy_predict();
+        assert_eq!(labels.to_vec(),vec![-1,-1,-1,0,-1]);
+        assert_eq!(field.clusters.len(),1);
+        assert_eq!(field.clusters[0].weight,5.);
+    }
+
+    #[test]
+    fn outlier_scores_average_relative_fuzz_and_displacement() {
+        let mut field = field(vec![0.,0.,1.,0.,2.,0.],vec![0.,1.,1.,1.,2.,4.],vec![1.,2.,3.],euclidean());
+        assert_eq!(field.outlier_scores().to_vec(),vec![0.75,1.,2.75]);
+        // Without any fuzz only displacement counts
+        field.fuzz = Array::zeros(3);
+        assert_eq!(field.outlier_scores().to_vec(),vec![0.5,0.5,2.]);
+    }
+
+    fn collapsed(distance: Distance, positions: Vec<f64>, weights: Vec<f64>, tolerance: f64) -> Particles {
+        let samples = weights.len();
+        let mut parameters = Parameters::empty();
+        parameters.distance = Some(distance);
+        let field = field(positions.clone(),positions.clone(),vec![0.; samples],parameters);
+        let particles = Particles {
+            positions: Array::from_shape_vec((samples,2),positions).unwrap(),
+            weights,
+            members: (0..samples).map(|i| vec![i]).collect(),
+        };
+        let pathfinders = (0..samples).map(|i| Pathfinder::init(i,samples,2,field.parameters.clone())).collect();
+        let (particles,pathfinders) = field.collapse_particles(particles,pathfinders,tolerance);
+        assert_eq!(pathfinders.iter().map(|p| p.id).collect::<Vec<usize>>(),(0..particles.weights.len()).collect::<Vec<usize>>());
+        particles
+    }
+
+    #[test]
+    fn collapses_particles_within_the_tolerance() {
+        let particles = collapsed(Distance::Euclidean,vec![0.,0.,0.3,0.,5.,5.],vec![1.,2.,1.],0.5);
+        assert_eq!(particles.members,vec![vec![1,0],vec![2]]);
+        assert_eq!(particles.weights,vec![3.,1.]);
+        assert!((particles.positions[[0,0]] - 0.2).abs() < 1e-12);
+        assert_eq!(particles.positions.row(1).to_vec(),vec![5.,5.]);
+        let particles = collapsed(Distance::Euclidean,vec![0.,0.,0.3,0.,5.,5.],vec![1.,2.,1.],0.2);
+        assert_eq!(particles.members,vec![vec![0],vec![1],vec![2]]);
+    }
+
+    // Both pairs are within the tolerance, with keys as far apart as each window allows
+
+    #[test]
+    fn euclidean_window_is_the_tolerance() {
+        let particles = collapsed(Distance::Euclidean,vec![0.,0.,0.3,0.3],vec![1.,1.],0.45);
+        assert_eq!(particles.members,vec![vec![0,1]]);
+    }
+
+    #[test]
+    fn cosine_window_is_wider_than_the_tolerance() {
+        // 24 degrees apart, a cosine distance of 0.086 but keys 0.41 apart
+        let angle = -21f64.to_radians();
+        let particles = collapsed(Distance::Cosine,vec![1.,-1.,angle.cos(),angle.sin()],vec![1.,1.],0.1);
+        assert_eq!(particles.members,vec![vec![0,1]]);
+    }
+}
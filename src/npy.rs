@@ -0,0 +1,471 @@
+use std::fs;
+use std::fs::File;
+use std::io::{Error,ErrorKind};
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use flate2::read::DeflateDecoder;
+use memmap::MmapOptions;
+use ndarray::{Array,ArrayBase,Data,Dimension,Ix2};
+
+use io::AtomicFile;
+use binary::{Points,MappedArray,data_length};
+
+// NumPy .npy files: the magic b"\x93NUMPY", a major and minor version, the header
+// length (u16 for version 1, u32 after), then a python dict literal describing the
+// dtype, order and shape, padded with spaces to a multiple of 64 bytes. The values
+// follow. An .npz is a zip of .npy files, stored or deflated.
+//
+// Floats, signed and unsigned integers and booleans of either byte order are read,
+// and 1-d arrays read as a single column. Little-endian f64 in C order is mapped in
+// place like a binary count file, everything else is decoded into memory.
+
+const MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData,message)
+}
+
+#[derive(Debug,Clone)]
+struct NpyHeader {
+    big_endian: bool,
+    kind: char,
+    width: usize,
+    fortran_order: bool,
+    rows: usize,
+    cols: usize,
+    data_offset: usize,
+}
+
+impl NpyHeader {
+
+    fn parse(bytes: &[u8]) -> Result<NpyHeader,Error> {
+
+        if bytes.len() < 10 || &bytes[..6] != MAGIC {
+            return Err(invalid("Not a .npy file, the magic is missing".to_string()))
+        }
+
+        let (length,start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8],bytes[9]]) as usize,10),
+            2 | 3 => {
+                if bytes.len() < 12 {
+                    return Err(invalid("Truncated .npy header".to_string()))
+                }
+                (u32::from_le_bytes([bytes[8],bytes[9],bytes[10],bytes[11]]) as usize,12)
+            },
+            version => return Err(invalid(format!("Unsupported .npy version {}",version))),
+        };
+
+        let dict = bytes.get(start..start + length).ok_or_else(|| invalid("Truncated .npy header".to_string()))?;
+        let dict = String::from_utf8_lossy(dict);
+
+        let descr = dict_value(&dict,"descr")?;
+        let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+        let mut chars = descr.chars();
+        let big_endian = match chars.next() {
+            Some('>') => true,
+            Some('<') | Some('|') => false,
+            Some('=') => cfg!(target_endian = "big"),
+            _ => return Err(invalid(format!("Unsupported .npy dtype {:?}",descr))),
+        };
+        let kind = chars.next().unwrap_or(' ');
+        let width = chars.as_str().parse::<usize>().map_err(|_| invalid(format!("Unsupported .npy dtype {:?}",descr)))?;
+        match (kind,width) {
+            ('f',4) | ('f',8) | ('i',1) | ('i',2) | ('i',4) | ('i',8) | ('u',1) | ('u',2) | ('u',4) | ('u',8) | ('b',1) => {},
+            _ => return Err(invalid(format!("Unsupported .npy dtype {:?}, expected floats, integers or booleans",descr))),
+        }
+
+        let fortran_order = dict_value(&dict,"fortran_order")? == "True";
+
+        let shape = dict_value(&dict,"shape")?;
+        let shape: Vec<usize> = shape.trim_matches(|c| c == '(' || c == ')')
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<usize>().map_err(|_| invalid(format!("Bad .npy shape {:?}",shape))))
+            .collect::<Result<Vec<usize>,Error>>()?;
+        let (rows,cols) = match shape.len() {
+            1 => (shape[0],1),
+            2 => (shape[0],shape[1]),
+            dimensions => return Err(invalid(format!("Expected a 1-d or 2-d array, found {} dimensions",dimensions))),
+        };
+
+        if data_length(rows,cols,width).and_then(|x| x.checked_add(start + length)).is_none() {
+            return Err(invalid(format!("A {}x{} .npy array is too large",rows,cols)))
+        }
+
+        Ok(NpyHeader { big_endian, kind, width, fortran_order, rows, cols, data_offset: start + length })
+    }
+
+    fn mappable(&self) -> bool {
+        self.kind == 'f' && self.width == 8 && !self.big_endian && !self.fortran_order && self.data_offset.is_multiple_of(8)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Array<f64,Ix2>,Error> {
+
+        let length = data_length(self.rows,self.cols,self.width).ok_or_else(|| invalid("The .npy array is too large".to_string()))?;
+        let end = self.data_offset.checked_add(length).ok_or_else(|| invalid("The .npy array is too large".to_string()))?;
+        let data = bytes.get(self.data_offset..end).ok_or_else(|| invalid("Truncated .npy data".to_string()))?;
+
+        let values: Vec<f64> = data.chunks(self.width).map(|chunk| {
+            // Little-endian in the low bytes, so unsigned values can be read as a u64
+            let mut buffer = [0u8; 8];
+            buffer[..self.width].copy_from_slice(chunk);
+            if self.big_endian {
+                buffer[..self.width].reverse();
+            }
+            match (self.kind,self.width) {
+                ('f',4) => f32::from_le_bytes([buffer[0],buffer[1],buffer[2],buffer[3]]) as f64,
+                ('f',_) => f64::from_le_bytes(buffer),
+                ('i',1) => buffer[0] as i8 as f64,
+                ('i',2) => i16::from_le_bytes([buffer[0],buffer[1]]) as f64,
+                ('i',4) => i32::from_le_bytes([buffer[0],buffer[1],buffer[2],buffer[3]]) as f64,
+                ('i',_) => i64::from_le_bytes(buffer) as f64,
+                _ => u64::from_le_bytes(buffer) as f64,
+            }
+        }).collect();
+
+        if self.fortran_order {
+            let transposed = Array::from_shape_vec((self.cols,self.rows),values).map_err(|e| invalid(format!("{:?}",e)))?;
+            Ok(transposed.t().to_owned())
+        }
+        else {
+            Array::from_shape_vec((self.rows,self.cols),values).map_err(|e| invalid(format!("{:?}",e)))
+        }
+    }
+}
+
+// Pulls the literal after 'key': out of the header dict, up to the next top-level comma
+fn dict_value(dict: &str, key: &str) -> Result<String,Error> {
+    let quoted = format!("'{}':",key);
+    let start = dict.find(&quoted).ok_or_else(|| invalid(format!("The .npy header has no {:?}",key)))? + quoted.len();
+    let rest = dict[start..].trim_start();
+    let mut depth = 0;
+    for (i,c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' | '}' if depth == 0 => return Ok(rest[..i].trim().to_string()),
+            _ => {},
+        }
+    }
+    Err(invalid(format!("Unterminated {:?} in the .npy header",key)))
+}
+
+pub fn read_npy(location: &str, verbose: bool) -> Result<Points,Error> {
+
+    let file = File::open(location)?;
+    let map = unsafe { MmapOptions::new().map(&file)? };
+    let header = NpyHeader::parse(&map)?;
+
+    if verbose {
+        eprintln!("NumPy counts: {},{} ({}{})",header.rows,header.cols,header.kind,header.width);
+    }
+
+    if header.mappable() && cfg!(target_endian = "little") {
+        return Ok(Points::Mapped(MappedArray::new(Arc::new(map),header.data_offset,header.rows,header.cols)?))
+    }
+
+    if verbose {
+        eprintln!("Decoding .npy counts into memory");
+    }
+
+    Ok(Points::Owned(header.decode(&map)?))
+}
+
+// Reads the array named key, with or without its .npy suffix, or the only array in
+// the archive if no key is given.
+
+pub fn read_npz(location: &str, key: Option<&str>, verbose: bool) -> Result<Points,Error> {
+
+    let archive = fs::read(location)?;
+    let entries = zip_entries(&archive)?;
+
+    let names: Vec<&str> = entries.iter().map(|x| x.name.as_str()).collect();
+    if verbose {
+        eprintln!("Arrays in {}: {:?}",location,names);
+    }
+
+    let entry = match key {
+        Some(key) => entries.iter().find(|x| x.name == key || x.name.trim_end_matches(".npy") == key)
+            .ok_or_else(|| invalid(format!("No array {:?} in {}, found {:?}",key,location,names)))?,
+        None if entries.len() == 1 => &entries[0],
+        None => return Err(invalid(format!("{} holds several arrays {:?}, choose one with -npz_key",location,names))),
+    };
+
+    let bytes = entry.read(&archive)?;
+    let header = NpyHeader::parse(&bytes)?;
+
+    if verbose {
+        eprintln!("NumPy counts: {},{} ({}{})",header.rows,header.cols,header.kind,header.width);
+    }
+
+    Ok(Points::Owned(header.decode(&bytes)?))
+}
+
+#[derive(Debug,Clone)]
+struct ZipEntry {
+    name: String,
+    method: u16,
+    compressed_size: usize,
+    header_offset: usize,
+}
+
+impl ZipEntry {
+
+    fn read(&self, archive: &[u8]) -> Result<Vec<u8>,Error> {
+        let local = self.header_offset;
+        if read_u32(archive,local)? != 0x0403_4b50 {
+            return Err(invalid(format!("Bad local header for {:?}",self.name)))
+        }
+        let start = local + 30 + read_u16(archive,local + 26)? as usize + read_u16(archive,local + 28)? as usize;
+        let data = archive.get(start..start + self.compressed_size).ok_or_else(|| invalid(format!("Truncated entry {:?}",self.name)))?;
+        match self.method {
+            0 => Ok(data.to_vec()),
+            8 => {
+                let mut inflated = vec![];
+                DeflateDecoder::new(data).read_to_end(&mut inflated)?;
+                Ok(inflated)
+            },
+            method => Err(invalid(format!("Unsupported zip compression method {} for {:?}",method,self.name))),
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16,Error> {
+    let b = bytes.get(at..at + 2).ok_or_else(|| invalid("Truncated zip archive".to_string()))?;
+    Ok(u16::from_le_bytes([b[0],b[1]]))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32,Error> {
+    let b = bytes.get(at..at + 4).ok_or_else(|| invalid("Truncated zip archive".to_string()))?;
+    Ok(u32::from_le_bytes([b[0],b[1],b[2],b[3]]))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Result<u64,Error> {
+    Ok(read_u32(bytes,at)? as u64 | (read_u32(bytes,at + 4)? as u64) << 32)
+}
+
+// Walks the central directory, which has the sizes even when the local headers
+// don't. NumPy writes zip64 entries, so their extra fields are honoured.
+
+fn zip_entries(archive: &[u8]) -> Result<Vec<ZipEntry>,Error> {
+
+    let search_from = archive.len().saturating_sub(22 + 0xFFFF);
+    let end = (search_from..archive.len().saturating_sub(21)).rev()
+        .find(|i| read_u32(archive,*i).ok() == Some(0x0605_4b50))
+        .ok_or_else(|| invalid("Not a zip archive".to_string()))?;
+
+    let mut count = read_u16(archive,end + 10)? as usize;
+    let mut offset = read_u32(archive,end + 16)? as usize;
+
+    if (count == 0xFFFF || offset == 0xFFFF_FFFF) && end >= 20 && read_u32(archive,end - 20)? == 0x0706_4b50 {
+        let zip64_end = read_u64(archive,end - 12)? as usize;
+        if read_u32(archive,zip64_end)? != 0x0606_4b50 {
+            return Err(invalid("Bad zip64 end of central directory".to_string()))
+        }
+        count = read_u64(archive,zip64_end + 32)? as usize;
+        offset = read_u64(archive,zip64_end + 48)? as usize;
+    }
+
+    let mut entries = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if read_u32(archive,offset)? != 0x0201_4b50 {
+            return Err(invalid("Bad zip central directory".to_string()))
+        }
+        let method = read_u16(archive,offset + 10)?;
+        let mut compressed_size = read_u32(archive,offset + 20)? as u64;
+        let uncompressed_size = read_u32(archive,offset + 24)?;
+        let name_length = read_u16(archive,offset + 28)? as usize;
+        let extra_length = read_u16(archive,offset + 30)? as usize;
+        let comment_length = read_u16(archive,offset + 32)? as usize;
+        let mut header_offset = read_u32(archive,offset + 42)? as u64;
+        let name = archive.get(offset + 46..offset + 46 + name_length).ok_or_else(|| invalid("Truncated zip archive".to_string()))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        // Zip64 extra fields only carry the values that overflowed, in this order
+        let mut extra = offset + 46 + name_length;
+        let extra_end = extra + extra_length;
+        while extra + 4 <= extra_end {
+            let (id,size) = (read_u16(archive,extra)?,read_u16(archive,extra + 2)? as usize);
+            if id == 0x0001 {
+                let mut field = extra + 4;
+                if uncompressed_size == 0xFFFF_FFFF {
+                    field += 8;
+                }
+                if compressed_size == 0xFFFF_FFFF {
+                    compressed_size = read_u64(archive,field)?;
+                    field += 8;
+                }
+                if header_offset == 0xFFFF_FFFF {
+                    header_offset = read_u64(archive,field)?;
+                }
+            }
+            extra += 4 + size;
+        }
+
+        entries.push(ZipEntry { name, method, compressed_size: compressed_size as usize, header_offset: header_offset as usize });
+        offset = extra_end + comment_length;
+    }
+
+    Ok(entries)
+}
+
+/// Element types that can be written to .npy
+pub trait NpyElement: Copy {
+    const DESCR: &'static str;
+    fn le_bytes(&self) -> [u8; 8];
+}
+
+impl NpyElement for f64 {
+    const DESCR: &'static str = "<f8";
+    fn le_bytes(&self) -> [u8; 8] { self.to_le_bytes() }
+}
+
+impl NpyElement for i64 {
+    const DESCR: &'static str = "<i8";
+    fn le_bytes(&self) -> [u8; 8] { self.to_le_bytes() }
+}
+
+pub fn write_npy<T: NpyElement, S: Data<Elem=T>, D: Dimension>(input: &ArrayBase<S,D>, location: &str) -> Result<(),Error> {
+
+    let shape = match input.shape() {
+        [n] => format!("({},)",n),
+        dimensions => format!("({})",dimensions.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let mut dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",T::DESCR,shape);
+
+    // Magic, version and length take 10 bytes, and the dict ends in a newline
+    let padded = (10 + dict.len() + 1).div_ceil(64) * 64;
+    while 10 + dict.len() + 1 < padded {
+        dict.push(' ');
+    }
+    dict.push('\n');
+
+    let mut writer = AtomicFile::create(location)?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1,0])?;
+    writer.write_all(&(dict.len() as u16).to_le_bytes())?;
+    writer.write_all(dict.as_bytes())?;
+    for value in input.iter() {
+        writer.write_all(&value.le_bytes())?;
+    }
+    writer.commit()
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::env;
+
+    fn location(name: &str) -> String {
+        env::temp_dir().join(format!("gravity_{}_{}",name,std::process::id())).to_str().unwrap().to_string()
+    }
+
+    // A version 1 .npy with the given dict and raw values
+    pub fn npy_bytes(dict: &str, data: &[u8]) -> Vec<u8> {
+        let mut dict = dict.to_string();
+        while !(10 + dict.len() + 1).is_multiple_of(64) {
+            dict.push(' ');
+        }
+        dict.push('\n');
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[1,0]);
+        bytes.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    // A zip of stored entries, which is all read_npz needs
+    pub fn zip_bytes(entries: &[(&str,Vec<u8>)]) -> Vec<u8> {
+        let (mut archive,mut directory) = (vec![],vec![]);
+        for (name,data) in entries {
+            let offset = archive.len() as u32;
+            archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            archive.extend_from_slice(&[0; 14]);
+            archive.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            archive.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            archive.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            archive.extend_from_slice(&[0; 2]);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(data);
+
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&[0; 16]);
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&[0; 6]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&[0; 2]);
+        archive
+    }
+
+    #[test]
+    fn round_trips_f64() {
+        let array = Array::from_shape_vec((2,3),vec![1.,-2.,3.5,0.,1e300,-0.25]).unwrap();
+        let location = location("round_trip.npy");
+        write_npy(&array,&location).unwrap();
+        let read = read_npy(&location,false).unwrap();
+        assert!(matches!(read,Points::Mapped(_)));
+        assert_eq!(read.into_owned(),array);
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn decodes_fortran_order_big_endian_integers() {
+        // Column major [[1,2,3],[4,5,6]]
+        let data: Vec<u8> = [1i32,4,2,5,3,6].iter().flat_map(|x| x.to_be_bytes().to_vec()).collect();
+        let bytes = npy_bytes("{'descr': '>i4', 'fortran_order': True, 'shape': (2, 3), }",&data);
+        let header = NpyHeader::parse(&bytes).unwrap();
+        assert_eq!(header.decode(&bytes).unwrap(),Array::from_shape_vec((2,3),vec![1.,2.,3.,4.,5.,6.]).unwrap());
+    }
+
+    #[test]
+    fn reads_vectors_as_a_column() {
+        let data: Vec<u8> = [1.5f32,2.5].iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        let bytes = npy_bytes("{'descr': '<f4', 'fortran_order': False, 'shape': (2,), }",&data);
+        let header = NpyHeader::parse(&bytes).unwrap();
+        assert_eq!(header.decode(&bytes).unwrap(),Array::from_shape_vec((2,1),vec![1.5,2.5]).unwrap());
+    }
+
+    #[test]
+    fn rejects_overflowing_and_truncated_shapes() {
+        let huge = npy_bytes("{'descr': '<f8', 'fortran_order': False, 'shape': (2305843009213693952, 3), }",&[]);
+        assert_eq!(NpyHeader::parse(&huge).err().unwrap().kind(),ErrorKind::InvalidData);
+        let short = npy_bytes("{'descr': '<u2', 'fortran_order': False, 'shape': (2, 2), }",&[0; 6]);
+        assert!(NpyHeader::parse(&short).and_then(|header| header.decode(&short)).is_err());
+    }
+
+    #[test]
+    fn reads_arrays_from_npz_by_key() {
+        let counts: Vec<u8> = [1.,2.,3.,4.].iter().flat_map(|x: &f64| x.to_le_bytes().to_vec()).collect();
+        let labels: Vec<u8> = [7i64,8].iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        let archive = zip_bytes(&[
+            ("counts.npy",npy_bytes("{'descr': '<f8', 'fortran_order': False, 'shape': (2, 2), }",&counts)),
+            ("labels.npy",npy_bytes("{'descr': '<i8', 'fortran_order': False, 'shape': (2,), }",&labels)),
+        ]);
+        let location = location("arrays.npz");
+        fs::write(&location,archive).unwrap();
+
+        let read = read_npz(&location,Some("counts"),false).unwrap().into_owned();
+        assert_eq!(read,Array::from_shape_vec((2,2),vec![1.,2.,3.,4.]).unwrap());
+        let read = read_npz(&location,Some("labels.npy"),false).unwrap().into_owned();
+        assert_eq!(read,Array::from_shape_vec((2,1),vec![7.,8.]).unwrap());
+        assert!(read_npz(&location,None,false).is_err());
+        assert!(read_npz(&location,Some("missing"),false).is_err());
+        fs::remove_file(&location).unwrap();
+    }
+}
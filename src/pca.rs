@@ -0,0 +1,255 @@
+use std::f64;
+use std::io::Error;
+use std::io::prelude::*;
+use std::cmp::Ordering;
+
+use ndarray::{Array,ArrayView,Axis,Ix1,Ix2};
+use rand::Rng;
+use rand::distributions::StandardNormal;
+
+use io::{AtomicFile,write_named_array};
+
+// Principal components by randomized SVD (Halko, Martinsson and Tropp), without a
+// linear algebra backend. The centered counts are multiplied by a random Gaussian
+// matrix a few components wider than needed, sharpened by power iterations, and the
+// small projected matrix is decomposed exactly by Jacobi rotations.
+
+const OVERSAMPLING: usize = 10;
+const POWER_ITERATIONS: usize = 4;
+
+#[derive(Debug,Clone,Copy)]
+pub enum PcaTarget {
+    Components(usize),
+    // Fewest components explaining at least this fraction of the variance
+    Variance(f64),
+}
+
+#[derive(Debug,Clone)]
+pub struct Pca {
+    pub means: Array<f64,Ix1>,
+    // Components x features
+    pub components: Array<f64,Ix2>,
+    pub explained_variance: Array<f64,Ix1>,
+    pub explained_ratio: Array<f64,Ix1>,
+}
+
+impl Pca {
+
+    pub fn fit<R: Rng>(counts: ArrayView<f64,Ix2>, target: PcaTarget, rng: &mut R) -> Pca {
+
+        let (samples,features) = counts.dim();
+        let rank = samples.min(features);
+        let means = counts.mean_axis(Axis(0));
+        let centered = &counts - &means;
+        let total_variance = centered.iter().map(|x| x.powi(2)).sum::<f64>() / (samples.max(2) - 1) as f64;
+
+        let (components,variances) = match target {
+            PcaTarget::Components(k) => {
+                if k > rank {
+                    eprintln!("WARNING: {} components asked for, but the counts have rank at most {}",k,rank);
+                }
+                randomized_svd(centered.view(),k.min(rank),rng)
+            },
+            PcaTarget::Variance(fraction) => {
+                // Widens the decomposition until enough variance is explained
+                let mut k = rank.min(10);
+                loop {
+                    let (components,variances) = randomized_svd(centered.view(),k,rng);
+                    let mut cumulative = 0.;
+                    let enough = variances.iter().position(|v| { cumulative += v / total_variance; cumulative >= fraction - 1e-12 });
+                    match enough {
+                        Some(i) => break (components.slice(s![..i+1,..]).to_owned(),variances.slice(s![..i+1]).to_owned()),
+                        None if k >= rank => break (components,variances),
+                        None => k = (k * 2).min(rank),
+                    }
+                }
+            },
+        };
+
+        let explained_ratio = variances.mapv(|v| if total_variance > 0. { v / total_variance } else { 0. });
+
+        eprintln!("PCA: {} components explaining {:.4} of the variance",components.rows(),explained_ratio.sum());
+
+        Pca { means, components, explained_variance: variances, explained_ratio }
+    }
+
+    pub fn transform(&self, counts: ArrayView<f64,Ix2>) -> Array<f64,Ix2> {
+        (&counts - &self.means).dot(&self.components.t())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        (1..=self.components.rows()).map(|i| format!("PC{}",i)).collect()
+    }
+
+    // Loadings are features x components, named by feature when names are known
+    pub fn write(&self, feature_names: Option<&[String]>, loadings: &str, variance: &str) -> Result<(),Error> {
+
+        write_named_array(self.components.t().to_owned(),feature_names,Some(&self.names()),&Some(loadings.to_string()))?;
+
+        let mut variance_file = AtomicFile::create(variance)?;
+        writeln!(variance_file,"component\tvariance\tratio\tcumulative")?;
+        let mut cumulative = 0.;
+        for (i,(v,ratio)) in self.explained_variance.iter().zip(self.explained_ratio.iter()).enumerate() {
+            cumulative += ratio;
+            writeln!(variance_file,"PC{}\t{:?}\t{:?}\t{:?}",i + 1,v,ratio,cumulative)?;
+        }
+        variance_file.commit()
+    }
+}
+
+// The top k right singular vectors of a centered matrix, as rows, and the variance
+// along each. Directions the matrix has no variance along are left out.
+
+fn randomized_svd<R: Rng>(centered: ArrayView<f64,Ix2>, k: usize, rng: &mut R) -> (Array<f64,Ix2>,Array<f64,Ix1>) {
+
+    let (samples,features) = centered.dim();
+    let width = (k + OVERSAMPLING).min(samples.min(features));
+
+    let omega: Array<f64,Ix2> = Array::from_shape_fn((features,width),|_| rng.sample(StandardNormal));
+    let mut range = orthonormalize(centered.dot(&omega));
+    for _ in 0..POWER_ITERATIONS {
+        let co_range = orthonormalize(centered.t().dot(&range));
+        range = orthonormalize(centered.dot(&co_range));
+    }
+
+    // B = Q^T X is small, and the eigenvectors of B B^T are its left singular vectors
+    let projected = range.t().dot(&centered);
+    let (eigenvalues,eigenvectors) = symmetric_eigen(projected.dot(&projected.t()));
+
+    let mut order: Vec<usize> = (0..eigenvalues.len()).collect();
+    order.sort_by(|a,b| eigenvalues[*b].partial_cmp(&eigenvalues[*a]).unwrap_or(Ordering::Equal));
+
+    let tolerance = eigenvalues.iter().cloned().fold(0.,f64::max) * 1e-12;
+    let kept: Vec<usize> = order.into_iter().filter(|i| eigenvalues[*i] > tolerance).take(k).collect();
+
+    let mut components = Array::zeros((kept.len(),features));
+    let mut variances = Array::zeros(kept.len());
+    for (c,i) in kept.iter().enumerate() {
+        let singular = eigenvalues[*i].sqrt();
+        components.row_mut(c).assign(&(projected.t().dot(&eigenvectors.column(*i)) / singular));
+        variances[c] = eigenvalues[*i] / (samples.max(2) - 1) as f64;
+    }
+
+    // Signs are arbitrary, fix them so the largest loading is positive
+    for mut component in components.outer_iter_mut() {
+        let largest = component.iter().cloned().fold(0.,|a: f64,b: f64| if b.abs() > a.abs() { b } else { a });
+        if largest < 0. {
+            component.mapv_inplace(|x| -x);
+        }
+    }
+
+    (components,variances)
+}
+
+// Modified Gram-Schmidt on the columns. Columns that are dependent on earlier ones
+// are zeroed.
+
+fn orthonormalize(mut matrix: Array<f64,Ix2>) -> Array<f64,Ix2> {
+    for j in 0..matrix.cols() {
+        for i in 0..j {
+            let projection = matrix.column(i).dot(&matrix.column(j));
+            let previous = matrix.column(i).to_owned();
+            matrix.column_mut(j).scaled_add(-projection,&previous);
+        }
+        let norm = matrix.column(j).dot(&matrix.column(j)).sqrt();
+        if norm > 1e-10 {
+            matrix.column_mut(j).mapv_inplace(|x| x / norm);
+        }
+        else {
+            matrix.column_mut(j).fill(0.);
+        }
+    }
+    matrix
+}
+
+// Cyclic Jacobi rotations, returning eigenvalues and eigenvectors as columns
+fn symmetric_eigen(mut matrix: Array<f64,Ix2>) -> (Array<f64,Ix1>,Array<f64,Ix2>) {
+
+    let n = matrix.rows();
+    let mut vectors: Array<f64,Ix2> = Array::eye(n);
+    let scale = matrix.iter().map(|x| x.powi(2)).sum::<f64>().sqrt();
+
+    for _ in 0..100 {
+        let off_diagonal: f64 = (0..n).flat_map(|i| (0..n).filter(move |j| *j != i).map(move |j| (i,j))).map(|(i,j)| matrix[[i,j]].powi(2)).sum::<f64>().sqrt();
+        if off_diagonal <= scale * 1e-14 {
+            break
+        }
+        for p in 0..n {
+            for q in p+1..n {
+                if matrix[[p,q]].abs() <= f64::MIN_POSITIVE {
+                    continue
+                }
+                let theta = (matrix[[q,q]] - matrix[[p,p]]) / (2. * matrix[[p,q]]);
+                let t = theta.signum() / (theta.abs() + (theta.powi(2) + 1.).sqrt());
+                let c = 1. / (t.powi(2) + 1.).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (kp,kq) = (matrix[[k,p]],matrix[[k,q]]);
+                    matrix[[k,p]] = c * kp - s * kq;
+                    matrix[[k,q]] = s * kp + c * kq;
+                }
+                for k in 0..n {
+                    let (pk,qk) = (matrix[[p,k]],matrix[[q,k]]);
+                    matrix[[p,k]] = c * pk - s * qk;
+                    matrix[[q,k]] = s * pk + c * qk;
+                }
+                for k in 0..n {
+                    let (kp,kq) = (vectors[[k,p]],vectors[[k,q]]);
+                    vectors[[k,p]] = c * kp - s * kq;
+                    vectors[[k,q]] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+
+    (matrix.diag().to_owned(),vectors)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::{SeedableRng,StdRng};
+
+    fn sorted(values: &Array<f64,Ix1>) -> Vec<f64> {
+        let mut values = values.to_vec();
+        values.sort_by(|a,b| a.partial_cmp(b).unwrap());
+        values
+    }
+
+    #[test]
+    fn finds_known_eigenvalues() {
+        let matrix = Array::from_shape_vec((3,3),vec![2.,-1.,0.,-1.,2.,-1.,0.,-1.,2.]).unwrap();
+        let (values,vectors) = symmetric_eigen(matrix.clone());
+        let expected = [2. - 2f64.sqrt(),2.,2. + 2f64.sqrt()];
+        assert!(sorted(&values).iter().zip(expected.iter()).all(|(a,b)| (a - b).abs() < 1e-10));
+        // Orthonormal eigenvectors in the columns
+        for (k,value) in values.iter().enumerate() {
+            let vector = vectors.column(k);
+            assert!((matrix.dot(&vector) - &vector.mapv(|x| x * value)).iter().all(|x| x.abs() < 1e-10));
+        }
+        assert!((vectors.t().dot(&vectors) - Array::<f64,Ix2>::eye(3)).iter().all(|x| x.abs() < 1e-10));
+    }
+
+    #[test]
+    fn leaves_diagonal_matrices_alone() {
+        let matrix = Array::from_shape_vec((2,2),vec![3.,0.,0.,-1.]).unwrap();
+        let (values,vectors) = symmetric_eigen(matrix);
+        assert_eq!(values.to_vec(),vec![3.,-1.]);
+        assert_eq!(vectors,Array::eye(2));
+    }
+
+    #[test]
+    fn recovers_a_line() {
+        // Samples along (1,2,2)/3, offset by (5,5,5)
+        let counts = Array::from_shape_fn((20,3),|(i,j)| 5. + (i as f64 - 9.5) * [1.,2.,2.][j] / 3.);
+        let mut rng = StdRng::from_seed([7; 32]);
+        let pca = Pca::fit(counts.view(),PcaTarget::Variance(0.99),&mut rng);
+        assert_eq!(pca.components.rows(),1);
+        assert!((pca.explained_ratio[0] - 1.).abs() < 1e-10);
+        let direction = pca.components.row(0);
+        assert!((direction.dot(&Array::from_vec(vec![1.,2.,2.])).abs() / 3. - 1.).abs() < 1e-10);
+        let projected = pca.transform(counts.view());
+        assert!(((projected[[19,0]] - projected[[0,0]]).abs() - 19.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,392 @@
+use std::fs::File;
+use std::io;
+use std::io::{Error,ErrorKind};
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use ndarray::{Array,Axis,Ix1,Ix2};
+
+use io::AtomicFile;
+use binary::Points;
+use sparse::CsrMatrix;
+use selection::{FeatureSelection,Dispersion,column_stats,sparse_column_stats};
+
+// Transforms applied to the counts before clustering, in the order they were given.
+// Steps that learn something from the data (means and scales for z-scores, minima
+// and ranges for min-max scaling) keep it, so the same transform can be written out
+// and applied again to new samples with -preprocess_from.
+//
+// Steps that map 0 to 0 are applied to sparse counts without densifying them.
+//
+// Feature selection is a step too, so it can come after normalization and before
+// scaling, and its choice of features is kept like any other fitted parameter.
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Step {
+    ZScore,
+    Log1p,
+    // Scales every sample to this total, 1e6 for counts per million
+    LibrarySize(f64),
+    MinMax,
+    Arcsinh(f64),
+    Clip(f64,f64),
+    // Keeps this many of the most variable features
+    Variable(usize,Dispersion),
+}
+
+impl Step {
+
+    // zscore, log1p, cpm, libsize:<total>, minmax, arcsinh:<cofactor>, clip:<low>:<high>,
+    // hvf:<n>, hvf_binned:<n>:<bins>
+    pub fn parse(input: &str) -> Step {
+        let fields: Vec<&str> = input.split(':').collect();
+        let number = |i: usize| fields.get(i).map(|x| x.parse::<f64>().expect("Preprocessing parameter parse error. Not a number?"));
+        match fields[0] {
+            "zscore" | "standardize" => Step::ZScore,
+            "log1p" | "log" => Step::Log1p,
+            "cpm" => Step::LibrarySize(1e6),
+            "libsize" | "library_size" => Step::LibrarySize(number(1).unwrap_or(1e4)),
+            "minmax" => Step::MinMax,
+            "arcsinh" | "asinh" => Step::Arcsinh(number(1).unwrap_or(5.)),
+            "clip" => Step::Clip(number(1).expect("clip needs bounds, e.g. clip:-3:3"),number(2).expect("clip needs bounds, e.g. clip:-3:3")),
+            "hvf" => Step::Variable(number(1).expect("hvf needs a number of features, e.g. hvf:2000") as usize,Dispersion::Raw),
+            "hvf_binned" => Step::Variable(number(1).expect("hvf_binned needs a number of features, e.g. hvf_binned:2000") as usize,Dispersion::Binned(number(2).unwrap_or(20.) as usize)),
+            _ => panic!("Not a valid preprocessing step, please choose from zscore, log1p, cpm, libsize:<total>, minmax, arcsinh:<cofactor>, clip:<low>:<high>, hvf:<n> or hvf_binned:<n>:<bins>"),
+        }
+    }
+
+    pub fn parse_list(input: &str) -> Vec<Step> {
+        input.split(',').filter(|x| !x.is_empty()).map(Step::parse).collect()
+    }
+
+    // Whether the step maps 0 to 0, fitted or not
+    fn keeps_zeros(&self) -> bool {
+        match self {
+            Step::ZScore | Step::MinMax => false,
+            Step::Clip(low,high) => *low <= 0. && *high >= 0.,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum Fitted {
+    ZScore { means: Array<f64,Ix1>, scales: Array<f64,Ix1> },
+    Log1p,
+    LibrarySize(f64),
+    MinMax { minima: Array<f64,Ix1>, ranges: Array<f64,Ix1> },
+    Arcsinh(f64),
+    Clip(f64,f64),
+    Select(FeatureSelection),
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Pipeline {
+    pub steps: Vec<Fitted>,
+}
+
+impl Pipeline {
+
+    // Fits each step on the output of the previous one, transforming as it goes
+    pub fn fit_transform(steps: &[Step], points: Points) -> (Pipeline,Points) {
+
+        if let Points::Sparse(matrix) = &points {
+            if steps.iter().all(|x| x.keeps_zeros()) {
+                let mut matrix = (**matrix).clone();
+                let mut pipeline = Pipeline::default();
+                for step in steps {
+                    let fitted = fit_sparse(step,&matrix);
+                    apply_sparse(&fitted,&mut matrix);
+                    pipeline.steps.push(fitted);
+                }
+                return (pipeline,Points::Sparse(Arc::new(matrix)))
+            }
+        }
+
+        let mut counts = points.into_owned();
+        let mut pipeline = Pipeline::default();
+        for step in steps {
+            let fitted = fit(step,&counts);
+            apply(&fitted,&mut counts);
+            check(&fitted,counts.iter());
+            pipeline.steps.push(fitted);
+        }
+        (pipeline,counts.into())
+    }
+
+    pub fn transform(&self, points: Points) -> Points {
+        if let Points::Sparse(matrix) = &points {
+            if self.steps.iter().all(|x| x.step().keeps_zeros()) {
+                let mut matrix = (**matrix).clone();
+                for fitted in &self.steps {
+                    check_features(fitted,matrix.cols);
+                    apply_sparse(fitted,&mut matrix);
+                }
+                return Points::Sparse(Arc::new(matrix))
+            }
+        }
+        let mut counts = points.into_owned();
+        for fitted in &self.steps {
+            check_features(fitted,counts.cols());
+            apply(fitted,&mut counts);
+            check(fitted,counts.iter());
+        }
+        counts.into()
+    }
+
+    pub fn selections(&self) -> Vec<&FeatureSelection> {
+        self.steps.iter().filter_map(|x| match x { Fitted::Select(selection) => Some(selection), _ => None }).collect()
+    }
+
+    // Every selection step, each with the feature names it saw
+    pub fn write_selections(&self, feature_names: Option<&[String]>, location: &str) -> Result<(),Error> {
+        let mut report_file = AtomicFile::create(location)?;
+        writeln!(report_file,"step\tfeature\tname\tmean\tvariance\tscore\tkept")?;
+        let mut names = feature_names.map(|names| names.to_vec());
+        for (step,fitted) in self.steps.iter().enumerate() {
+            if let Fitted::Select(selection) = fitted {
+                selection.write_rows(step,names.as_deref(),&mut report_file)?;
+                names = names.map(|names| selection.kept.iter().map(|j| names[*j].clone()).collect());
+            }
+        }
+        report_file.commit()
+    }
+
+    pub fn write(&self, location: &str) -> Result<(),Error> {
+        let mut pipeline_file = AtomicFile::create(location)?;
+        writeln!(pipeline_file,"# step\tparameters")?;
+        let join = |values: &Array<f64,Ix1>| values.iter().map(|x| format!("{:?}",x)).collect::<Vec<String>>().join(",");
+        for fitted in &self.steps {
+            match fitted {
+                Fitted::ZScore { means, scales } => writeln!(pipeline_file,"zscore\t{}\t{}",join(means),join(scales))?,
+                Fitted::Log1p => writeln!(pipeline_file,"log1p")?,
+                Fitted::LibrarySize(total) => writeln!(pipeline_file,"libsize\t{:?}",total)?,
+                Fitted::MinMax { minima, ranges } => writeln!(pipeline_file,"minmax\t{}\t{}",join(minima),join(ranges))?,
+                Fitted::Arcsinh(cofactor) => writeln!(pipeline_file,"arcsinh\t{:?}",cofactor)?,
+                Fitted::Clip(low,high) => writeln!(pipeline_file,"clip\t{:?}\t{:?}",low,high)?,
+                Fitted::Select(selection) => writeln!(pipeline_file,"select\t{}\t{}",selection.features,selection.kept.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(","))?,
+            }
+        }
+        pipeline_file.commit()
+    }
+
+    pub fn read(location: &str) -> Result<Pipeline,Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData,message);
+        let mut pipeline = Pipeline::default();
+        for line in io::BufReader::new(File::open(location)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let numbers = |i: usize| -> Result<Vec<f64>,Error> {
+                fields.get(i).ok_or_else(|| invalid(format!("Missing parameters in preprocessing line {:?}",line)))?
+                    .split(',')
+                    .map(|x| x.parse::<f64>().map_err(|_| invalid(format!("Bad number {:?} in preprocessing line {:?}",x,line))))
+                    .collect()
+            };
+            let number = |i: usize| numbers(i).map(|x| x[0]);
+            pipeline.steps.push(match fields[0] {
+                "zscore" => {
+                    let (means,scales) = (numbers(1)?,numbers(2)?);
+                    if means.len() != scales.len() {
+                        return Err(invalid(format!("{} means but {} scales in {:?}",means.len(),scales.len(),line)))
+                    }
+                    Fitted::ZScore { means: Array::from_vec(means), scales: Array::from_vec(scales) }
+                },
+                "log1p" => Fitted::Log1p,
+                "libsize" => Fitted::LibrarySize(number(1)?),
+                "minmax" => {
+                    let (minima,ranges) = (numbers(1)?,numbers(2)?);
+                    if minima.len() != ranges.len() {
+                        return Err(invalid(format!("{} minima but {} ranges in {:?}",minima.len(),ranges.len(),line)))
+                    }
+                    Fitted::MinMax { minima: Array::from_vec(minima), ranges: Array::from_vec(ranges) }
+                },
+                "arcsinh" => Fitted::Arcsinh(number(1)?),
+                "clip" => Fitted::Clip(number(1)?,number(2)?),
+                "select" => {
+                    let features = number(1)? as usize;
+                    let kept: Vec<usize> = numbers(2)?.into_iter().map(|x| x as usize).collect();
+                    if kept.iter().any(|x| *x >= features) {
+                        return Err(invalid(format!("Selected features out of range in {:?}",line)))
+                    }
+                    Fitted::Select(FeatureSelection { features, means: Array::zeros(0), variances: Array::zeros(0), scores: Array::zeros(0), kept })
+                },
+                other => return Err(invalid(format!("Unknown preprocessing step {:?}",other))),
+            });
+        }
+        Ok(pipeline)
+    }
+}
+
+impl Fitted {
+    fn features(&self) -> Option<usize> {
+        match self {
+            Fitted::ZScore { means, .. } => Some(means.len()),
+            Fitted::MinMax { minima, .. } => Some(minima.len()),
+            Fitted::Select(selection) => Some(selection.features),
+            _ => None,
+        }
+    }
+
+    // The step this was fitted from. How features were scored isn't kept after
+    // selection, so selections come back as raw dispersion.
+    fn step(&self) -> Step {
+        match self {
+            Fitted::ZScore { .. } => Step::ZScore,
+            Fitted::Log1p => Step::Log1p,
+            Fitted::LibrarySize(total) => Step::LibrarySize(*total),
+            Fitted::MinMax { .. } => Step::MinMax,
+            Fitted::Arcsinh(cofactor) => Step::Arcsinh(*cofactor),
+            Fitted::Clip(low,high) => Step::Clip(*low,*high),
+            Fitted::Select(selection) => Step::Variable(selection.kept.len(),Dispersion::Raw),
+        }
+    }
+}
+
+// The input was checked for missing values, so any now are the step's doing, eg log1p
+// of values below -1
+fn check<'a, I: Iterator<Item = &'a f64>>(fitted: &Fitted, mut values: I) {
+    if values.any(|x| x.is_nan()) {
+        panic!("Preprocessing with {:?} produced missing values, is the input in its domain?",fitted);
+    }
+}
+
+fn check_features(fitted: &Fitted, features: usize) {
+    if let Some(fitted_features) = fitted.features() {
+        if fitted_features != features {
+            panic!("Preprocessing was fitted on {} features, the counts have {}",fitted_features,features);
+        }
+    }
+}
+
+fn fit_sparse(step: &Step, counts: &CsrMatrix) -> Fitted {
+    match step {
+        Step::Variable(keep,method) => {
+            let (means,variances) = sparse_column_stats(counts);
+            Fitted::Select(FeatureSelection::select(means,variances,*method,*keep))
+        },
+        other => elementwise(other),
+    }
+}
+
+// Constant features get a scale of 1, so they end up all 0 rather than NaN
+fn fit(step: &Step, counts: &Array<f64,Ix2>) -> Fitted {
+    match step {
+        Step::ZScore => {
+            let means = counts.mean_axis(Axis(0));
+            let scales = counts.var_axis(Axis(0),0.).mapv(|x| if x > 0. { x.sqrt() } else { 1. });
+            Fitted::ZScore { means, scales }
+        },
+        Step::MinMax => {
+            let minima = counts.fold_axis(Axis(0),f64::INFINITY,|a,b| a.min(*b));
+            let maxima = counts.fold_axis(Axis(0),f64::NEG_INFINITY,|a,b| a.max(*b));
+            let ranges = (&maxima - &minima).mapv(|x| if x > 0. { x } else { 1. });
+            Fitted::MinMax { minima, ranges }
+        },
+        Step::Variable(keep,method) => {
+            let (means,variances) = column_stats(counts.view());
+            Fitted::Select(FeatureSelection::select(means,variances,*method,*keep))
+        },
+        other => elementwise(other),
+    }
+}
+
+fn elementwise(step: &Step) -> Fitted {
+    match step {
+        Step::Log1p => Fitted::Log1p,
+        Step::LibrarySize(total) => Fitted::LibrarySize(*total),
+        Step::Arcsinh(cofactor) => Fitted::Arcsinh(*cofactor),
+        Step::Clip(low,high) => Fitted::Clip(*low,*high),
+        _ => unreachable!("{:?} has parameters to fit",step),
+    }
+}
+
+fn apply_sparse(fitted: &Fitted, matrix: &mut CsrMatrix) {
+    match fitted {
+        Fitted::LibrarySize(total) => {
+            for i in 0..matrix.rows {
+                let (start,end) = (matrix.indptr[i],matrix.indptr[i + 1]);
+                let sum: f64 = matrix.data[start..end].iter().sum();
+                if sum != 0. {
+                    matrix.data[start..end].iter_mut().for_each(|x| *x *= total / sum);
+                }
+            }
+        },
+        Fitted::Select(selection) => *matrix = matrix.select_columns(&selection.kept),
+        elementwise => matrix.data.iter_mut().for_each(|x| *x = apply_value(elementwise,*x)),
+    }
+    check(fitted,matrix.data.iter());
+}
+
+fn apply(fitted: &Fitted, counts: &mut Array<f64,Ix2>) {
+    match fitted {
+        Fitted::ZScore { means, scales } => {
+            for mut row in counts.outer_iter_mut() {
+                row -= means;
+                row /= scales;
+            }
+        },
+        Fitted::MinMax { minima, ranges } => {
+            for mut row in counts.outer_iter_mut() {
+                row -= minima;
+                row /= ranges;
+            }
+        },
+        Fitted::Select(selection) => *counts = counts.select(Axis(1),&selection.kept),
+        Fitted::LibrarySize(total) => {
+            for mut row in counts.outer_iter_mut() {
+                let sum = row.sum();
+                if sum != 0. {
+                    row *= *total / sum;
+                }
+            }
+        },
+        elementwise => counts.mapv_inplace(|x| apply_value(elementwise,x)),
+    }
+}
+
+fn apply_value(fitted: &Fitted, x: f64) -> f64 {
+    match fitted {
+        Fitted::Log1p => x.ln_1p(),
+        Fitted::Arcsinh(cofactor) => (x / cofactor).asinh(),
+        Fitted::Clip(low,high) => x.max(*low).min(*high),
+        _ => unreachable!("{:?} isn't elementwise",fitted),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn read(name: &str, text: &str) -> Result<Pipeline,Error> {
+        let location = env::temp_dir().join(format!("gravity_{}_{}.tsv",name,std::process::id())).to_str().unwrap().to_string();
+        fs::write(&location,text).unwrap();
+        let pipeline = Pipeline::read(&location);
+        fs::remove_file(&location).unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn round_trips_fitted_steps() {
+        let counts = Array::from_shape_vec((3,2),vec![1.,10.,2.,20.,6.,30.]).unwrap();
+        let steps = Step::parse_list("log1p,zscore,clip:-1:1");
+        let (pipeline,fitted) = Pipeline::fit_transform(&steps,counts.clone().into());
+        let location = env::temp_dir().join(format!("gravity_pipeline_{}.tsv",std::process::id())).to_str().unwrap().to_string();
+        pipeline.write(&location).unwrap();
+        let read = Pipeline::read(&location).unwrap();
+        fs::remove_file(&location).unwrap();
+        assert_eq!(read.transform(counts.into()).into_owned(),fitted.into_owned());
+    }
+
+    #[test]
+    fn rejects_mismatched_parameter_lengths() {
+        assert_eq!(read("zscore","zscore\t1,2\t1\n").err().unwrap().kind(),ErrorKind::InvalidData);
+        assert_eq!(read("minmax","minmax\t0\t1,1\n").err().unwrap().kind(),ErrorKind::InvalidData);
+        assert!(read("matching","zscore\t1,2\t1,1\n").is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+use ndarray::{Array,Ix2};
+use rand::Rng;
+use rand::distributions::StandardNormal;
+
+use binary::Points;
+
+// Johnson-Lindenstrauss random projection to a lower dimension. Distances between
+// samples are preserved up to a small distortion with high probability, for a
+// fraction of the cost of PCA. Entries of the projection are either Gaussian, or
+// Achlioptas' sparse sqrt(3) * {+1, 0, -1} with probabilities {1/6, 2/3, 1/6},
+// both scaled by 1/sqrt(dimension).
+//
+// Sparse counts are projected without densifying them.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Projection {
+    Gaussian,
+    Achlioptas,
+}
+
+impl Projection {
+
+    pub fn parse(input: &str) -> Projection {
+        match input {
+            "gaussian" | "normal" => Projection::Gaussian,
+            "sparse" | "achlioptas" => Projection::Achlioptas,
+            _ => panic!("Not a valid projection, please choose from gaussian or sparse"),
+        }
+    }
+
+    // Features x dimension
+    pub fn matrix<R: Rng>(&self, features: usize, dimension: usize, rng: &mut R) -> Array<f64,Ix2> {
+        let scale = 1. / (dimension as f64).sqrt();
+        match self {
+            Projection::Gaussian => Array::from_shape_fn((features,dimension),|_| rng.sample::<f64,_>(StandardNormal) * scale),
+            Projection::Achlioptas => Array::from_shape_fn((features,dimension),|_| {
+                match rng.gen_range(0,6) {
+                    0 => 3f64.sqrt() * scale,
+                    1 => -(3f64.sqrt()) * scale,
+                    _ => 0.,
+                }
+            }),
+        }
+    }
+
+    pub fn project<R: Rng>(&self, points: Points, dimension: usize, rng: &mut R) -> Points {
+
+        let (samples,features) = points.shape();
+        if dimension >= features {
+            eprintln!("WARNING: projecting {} features up to {} dimensions doesn't reduce anything",features,dimension);
+        }
+
+        let matrix = self.matrix(features,dimension,rng);
+
+        let projected = match &points {
+            Points::Sparse(counts) => {
+                let mut projected = Array::zeros((samples,dimension));
+                for (i,mut row) in projected.outer_iter_mut().enumerate() {
+                    let sparse = counts.row(i);
+                    for (j,x) in sparse.indices.iter().zip(sparse.data.iter()) {
+                        row.scaled_add(*x,&matrix.row(*j));
+                    }
+                }
+                projected
+            },
+            dense => dense.view().dot(&matrix),
+        };
+
+        eprintln!("Projected {} features onto {} {:?} random dimensions",features,dimension,self);
+
+        projected.into()
+    }
+}
+
+pub fn names(dimension: usize) -> Vec<String> {
+    (1..=dimension).map(|i| format!("RP{}",i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::Arc;
+    use io::Parameters;
+    use sparse::CsrMatrix;
+
+    fn seeded(seed: u64) -> Parameters {
+        let mut parameters = Parameters::empty();
+        parameters.seed = Some(seed);
+        parameters
+    }
+
+    fn counts() -> Array<f64,Ix2> {
+        Array::from_shape_fn((5,20),|(i,j)| if (i + j) % 3 == 0 { (i * j) as f64 } else { 0. })
+    }
+
+    #[test]
+    fn seeded_projections_repeat() {
+        for projection in &[Projection::Gaussian,Projection::Achlioptas] {
+            let first = projection.project(counts().into(),4,&mut seeded(7).rng()).into_owned();
+            let second = projection.project(counts().into(),4,&mut seeded(7).rng()).into_owned();
+            let other = projection.project(counts().into(),4,&mut seeded(8).rng()).into_owned();
+            assert_eq!(first.dim(),(5,4));
+            assert_eq!(first,second);
+            assert!(first != other);
+        }
+    }
+
+    #[test]
+    fn sparse_counts_project_like_dense_ones() {
+        let sparse = Points::Sparse(Arc::new(CsrMatrix::from_dense(counts().view())));
+        let projected = Projection::Gaussian.project(sparse,3,&mut seeded(1).rng()).into_owned();
+        let dense = Projection::Gaussian.project(counts().into(),3,&mut seeded(1).rng()).into_owned();
+        assert_eq!(projected.dim(),(5,3));
+        assert!(projected.iter().zip(dense.iter()).all(|(a,b)| (a - b).abs() < 1e-9));
+    }
+
+    #[test]
+    fn achlioptas_entries_take_three_values() {
+        let matrix = Projection::Achlioptas.matrix(300,9,&mut seeded(3).rng());
+        let value = 3f64.sqrt() / 3.;
+        assert_eq!(matrix.dim(),(300,9));
+        assert!(matrix.iter().all(|x| *x == 0. || *x == value || *x == -value));
+        // Two thirds are zero, give or take
+        let zeros = matrix.iter().filter(|x| **x == 0.).count() as f64 / matrix.len() as f64;
+        assert!((zeros - 2. / 3.).abs() < 0.05);
+    }
+}
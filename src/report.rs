@@ -0,0 +1,140 @@
+use std::io::Error;
+use std::io::prelude::*;
+
+use io::AtomicFile;
+
+// A summary of one final cluster. Members are sample names when they were given,
+// otherwise sample indices. The merge threshold is the largest one any merge into
+// the cluster needed, and is missing for clusters that were never merged.
+
+#[derive(Debug,Clone)]
+pub struct ClusterReport {
+    pub id: usize,
+    pub size: usize,
+    pub weight: f64,
+    pub center: Vec<f64>,
+    pub radius: f64,
+    pub mean_fuzz: f64,
+    pub max_fuzz: f64,
+    pub merge_threshold: Option<f64>,
+    pub members: Vec<String>,
+}
+
+// TSV with one cluster per line. Centers and members are comma separated within
+// their column, and a member name holding a comma, quote, tab or line break is
+// quoted as in CSV. Missing thresholds are written as NA.
+
+pub fn write_cluster_tsv(reports: &[ClusterReport], location: &str) -> Result<(),Error> {
+    let mut report_file = AtomicFile::create(location)?;
+    writeln!(report_file,"id\tsize\tweight\tradius\tmean_fuzz\tmax_fuzz\tmerge_threshold\tcenter\tmembers")?;
+    for report in reports {
+        writeln!(
+            report_file,"{}\t{}\t{:?}\t{:?}\t{:?}\t{:?}\t{}\t{}\t{}",
+            report.id,report.size,report.weight,report.radius,report.mean_fuzz,report.max_fuzz,
+            report.merge_threshold.map(|x| format!("{:?}",x)).unwrap_or_else(|| "NA".to_string()),
+            report.center.iter().map(|x| format!("{:?}",x)).collect::<Vec<String>>().join(","),
+            report.members.iter().map(|x| quoted_name(x)).collect::<Vec<String>>().join(","),
+        )?;
+    }
+    report_file.commit()
+}
+
+pub fn write_cluster_json(reports: &[ClusterReport], location: &str) -> Result<(),Error> {
+    let mut report_file = AtomicFile::create(location)?;
+    writeln!(report_file,"[")?;
+    for (i,report) in reports.iter().enumerate() {
+        writeln!(report_file,"  {{")?;
+        writeln!(report_file,"    \"id\": {},",report.id)?;
+        writeln!(report_file,"    \"size\": {},",report.size)?;
+        writeln!(report_file,"    \"weight\": {},",json_number(report.weight))?;
+        writeln!(report_file,"    \"radius\": {},",json_number(report.radius))?;
+        writeln!(report_file,"    \"mean_fuzz\": {},",json_number(report.mean_fuzz))?;
+        writeln!(report_file,"    \"max_fuzz\": {},",json_number(report.max_fuzz))?;
+        writeln!(report_file,"    \"merge_threshold\": {},",report.merge_threshold.map(json_number).unwrap_or_else(|| "null".to_string()))?;
+        writeln!(report_file,"    \"center\": [{}],",report.center.iter().map(|x| json_number(*x)).collect::<Vec<String>>().join(", "))?;
+        writeln!(report_file,"    \"members\": [{}]",report.members.iter().map(|x| json_string(x)).collect::<Vec<String>>().join(", "))?;
+        writeln!(report_file,"  }}{}",if i + 1 < reports.len() { "," } else { "" })?;
+    }
+    writeln!(report_file,"]")?;
+    report_file.commit()
+}
+
+fn quoted_name(x: &str) -> String {
+    if x.contains(&[',','"','\t','\n','\r'][..]) {
+        format!("\"{}\"",x.replace('"',"\"\""))
+    }
+    else {
+        x.to_string()
+    }
+}
+
+// JSON has no NaN or infinity
+fn json_number(x: f64) -> String {
+    if x.is_finite() { format!("{:?}",x) } else { "null".to_string() }
+}
+
+fn json_string(x: &str) -> String {
+    let mut escaped = String::with_capacity(x.len() + 2);
+    escaped.push('"');
+    for c in x.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}",c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn reports() -> Vec<ClusterReport> {
+        vec![
+            ClusterReport {
+                id: 0, size: 3, weight: 3., center: vec![0.5,-1.], radius: 0.25, mean_fuzz: 0.1, max_fuzz: 0.2, merge_threshold: Some(0.5),
+                members: vec!["plain".to_string(),"CD4, total".to_string(),"say \"hi\"\\there\tnow".to_string()],
+            },
+            ClusterReport {
+                id: 1, size: 1, weight: 1., center: vec![f64::NAN,2.], radius: 0., mean_fuzz: 0., max_fuzz: 0., merge_threshold: None,
+                members: vec!["single".to_string()],
+            },
+        ]
+    }
+
+    fn written(name: &str, write: fn(&[ClusterReport],&str) -> Result<(),Error>) -> String {
+        let location = env::temp_dir().join(format!("gravity_report_{}_{}",name,std::process::id()));
+        let location = location.to_str().unwrap();
+        write(&reports(),location).unwrap();
+        let contents = fs::read_to_string(location).unwrap();
+        fs::remove_file(location).unwrap();
+        contents
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("a\"b\\c\td\ne\u{1}"),"\"a\\\"b\\\\c\\td\\ne\\u0001\"");
+        let json = written("json",write_cluster_json);
+        assert!(json.contains("\"members\": [\"plain\", \"CD4, total\", \"say \\\"hi\\\"\\\\there\\tnow\"]"));
+        assert!(json.contains("\"center\": [null, 2.0],"));
+        assert!(json.contains("\"merge_threshold\": null,"));
+    }
+
+    #[test]
+    fn quotes_member_names_in_tsv() {
+        let tsv = written("tsv",write_cluster_tsv);
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines.len(),3);
+        assert_eq!(lines[1],"0\t3\t3.0\t0.25\t0.1\t0.2\t0.5\t0.5,-1.0\tplain,\"CD4, total\",\"say \"\"hi\"\"\\there\tnow\"");
+        assert_eq!(lines[2],"1\t1\t1.0\t0.0\t0.0\t0.0\tNA\tNaN,2.0\tsingle");
+    }
+}
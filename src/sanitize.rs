@@ -0,0 +1,288 @@
+use std::f64;
+use std::io::Error;
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+
+use ndarray::Axis;
+
+use io::AtomicFile;
+use binary::Points;
+use sparse::{CsrMatrix,SparseRow};
+
+// Removes the features borrowing and cosine similarity between features can't
+// handle. All-zero features have no direction and constant features carry no
+// information, so they are dropped, or with `repair` all-zero features are
+// filled with 1 and constant ones left alone. A feature that is a multiple of an
+// earlier one (up to rounding) is dropped in favor of it, and with a collinearity
+// threshold so is any feature correlated at least that strongly with an earlier
+// one. Every change is kept for the report.
+//
+// Features are compared as sparse columns. Multiples share their nonzero entries,
+// so only features with the same (hashed) pattern are compared for those.
+
+const MULTIPLE_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Sanitize {
+    Drop,
+    Repair,
+}
+
+impl Sanitize {
+    pub fn parse(input: &str) -> Sanitize {
+        match input {
+            "drop" => Sanitize::Drop,
+            "repair" | "fill" => Sanitize::Repair,
+            _ => panic!("Not a valid sanitization, please choose from drop or repair"),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Change {
+    pub feature: usize,
+    pub action: &'static str,
+    pub reason: &'static str,
+    pub partner: Option<usize>,
+    pub value: f64,
+}
+
+#[derive(Debug,Clone)]
+pub struct Sanitization {
+    pub features: usize,
+    // Kept feature indices, in their original order
+    pub kept: Vec<usize>,
+    pub changes: Vec<Change>,
+}
+
+impl Sanitization {
+
+    pub fn sanitize(points: Points, mode: Sanitize, collinear: Option<f64>) -> (Points,Sanitization) {
+
+        let (samples,features) = points.shape();
+
+        // Features as rows
+        let columns = match &points {
+            Points::Sparse(counts) => counts.transpose(),
+            dense => CsrMatrix::from_dense(dense.view().t()),
+        };
+
+        let mut changes = vec![];
+        let mut filled = vec![];
+        let mut candidates = vec![];
+
+        for j in 0..features {
+            let column = columns.row(j);
+            let nonzero = column.data.iter().filter(|x| **x != 0.).count();
+            let mut minimum = column.data.iter().cloned().fold(f64::INFINITY,f64::min);
+            let mut maximum = column.data.iter().cloned().fold(f64::NEG_INFINITY,f64::max);
+            if column.data.len() < samples {
+                minimum = minimum.min(0.);
+                maximum = maximum.max(0.);
+            }
+            if nonzero == 0 {
+                match mode {
+                    Sanitize::Drop => changes.push(Change { feature: j, action: "dropped", reason: "all_zero", partner: None, value: 0. }),
+                    Sanitize::Repair => {
+                        changes.push(Change { feature: j, action: "filled", reason: "all_zero", partner: None, value: 1. });
+                        filled.push(j);
+                        candidates.push(j);
+                    },
+                }
+            }
+            else if minimum == maximum {
+                match mode {
+                    Sanitize::Drop => changes.push(Change { feature: j, action: "dropped", reason: "constant", partner: None, value: minimum }),
+                    Sanitize::Repair => candidates.push(j),
+                }
+            }
+            else {
+                candidates.push(j);
+            }
+        }
+
+        let mut is_filled = vec![false; features];
+        for j in &filled {
+            is_filled[*j] = true;
+        }
+
+        // Filled features are all equal to each other, but they are kept as repaired
+        let mut patterns: HashMap<u64,Vec<usize>> = HashMap::new();
+        for j in candidates.iter().filter(|j| !is_filled[**j]) {
+            let column = columns.row(*j);
+            let mut hasher = DefaultHasher::new();
+            for (i,x) in column.indices.iter().zip(column.data.iter()) {
+                if *x != 0. {
+                    i.hash(&mut hasher);
+                }
+            }
+            patterns.entry(hasher.finish()).or_default().push(*j);
+        }
+
+        let mut dropped = vec![false; features];
+        let mut multiples = vec![];
+        for group in patterns.values().filter(|group| group.len() > 1) {
+            let mut representatives: Vec<usize> = vec![];
+            for j in group {
+                let column = columns.row(*j);
+                let partner = representatives.iter().find(|i| {
+                    let other = columns.row(**i);
+                    (sparse_dot(&other,&column) / (other.norm() * column.norm())).abs() >= 1. - MULTIPLE_TOLERANCE
+                });
+                match partner {
+                    Some(i) => {
+                        let other = columns.row(*i);
+                        let factor = sparse_dot(&other,&column) / other.norm().powi(2);
+                        let reason = if column.data == other.data { "duplicate" } else { "multiple" };
+                        multiples.push(Change { feature: *j, action: "dropped", reason, partner: Some(*i), value: factor });
+                        dropped[*j] = true;
+                    },
+                    None => representatives.push(*j),
+                }
+            }
+        }
+        candidates.retain(|j| !dropped[*j]);
+        changes.extend(multiples);
+
+        if let Some(threshold) = collinear {
+            let n = samples as f64;
+            let mean = |column: &SparseRow| column.data.iter().sum::<f64>() / n;
+            let deviation = |column: &SparseRow| (column.data.iter().map(|x| x.powi(2)).sum::<f64>() / n - mean(column).powi(2)).max(0.).sqrt();
+            let mut kept: Vec<usize> = vec![];
+            for j in candidates.iter().filter(|j| !is_filled[**j]) {
+                let column = columns.row(*j);
+                let correlation = |i: &usize| {
+                    let other = columns.row(*i);
+                    let covariance = sparse_dot(&other,&column) / n - mean(&other) * mean(&column);
+                    covariance / (deviation(&other) * deviation(&column))
+                };
+                match kept.iter().map(|i| (*i,correlation(i))).find(|(_,r)| r.abs() >= threshold) {
+                    Some((i,r)) => {
+                        changes.push(Change { feature: *j, action: "dropped", reason: "collinear", partner: Some(i), value: r });
+                        dropped[*j] = true;
+                    },
+                    None => kept.push(*j),
+                }
+            }
+            candidates.retain(|j| !dropped[*j]);
+        }
+
+        changes.sort_by_key(|change| change.feature);
+        let kept = candidates;
+
+        if kept.is_empty() {
+            panic!("Sanitization dropped all {} features, nothing is left to cluster",features);
+        }
+        eprintln!("Sanitization: kept {} of {} features, filled {}",kept.len(),features,filled.len());
+
+        let sanitized = match points {
+            Points::Sparse(counts) => {
+                let mut triplets = vec![];
+                for i in 0..samples {
+                    let row = counts.row(i);
+                    triplets.extend(row.indices.iter().zip(row.data.iter()).map(|(j,x)| (i,*j,*x)));
+                    triplets.extend(filled.iter().map(|j| (i,*j,1.)));
+                }
+                Points::Sparse(Arc::new(CsrMatrix::from_triplets(samples,features,triplets).select_columns(&kept)))
+            },
+            dense => {
+                let mut counts = dense.into_owned();
+                for j in &filled {
+                    counts.column_mut(*j).fill(1.);
+                }
+                counts.select(Axis(1),&kept).into()
+            },
+        };
+
+        (sanitized,Sanitization { features, kept, changes })
+    }
+
+    pub fn write(&self, feature_names: Option<&[String]>, location: &str) -> Result<(),Error> {
+        let name = |j: usize| feature_names.map(|names| names[j].clone()).unwrap_or_else(|| j.to_string());
+        let mut report_file = AtomicFile::create(location)?;
+        writeln!(report_file,"feature\tname\taction\treason\tpartner\tvalue")?;
+        for change in &self.changes {
+            let partner = change.partner.map(name).unwrap_or_default();
+            writeln!(report_file,"{}\t{}\t{}\t{}\t{}\t{:?}",change.feature,name(change.feature),change.action,change.reason,partner,change.value)?;
+        }
+        report_file.commit()
+    }
+}
+
+fn sparse_dot(a: &SparseRow, b: &SparseRow) -> f64 {
+    let (mut i,mut j) = (0,0);
+    let mut sum = 0.;
+    while i < a.indices.len() && j < b.indices.len() {
+        if a.indices[i] == b.indices[j] {
+            sum += a.data[i] * b.data[j];
+            i += 1;
+            j += 1;
+        }
+        else if a.indices[i] < b.indices[j] {
+            i += 1;
+        }
+        else {
+            j += 1;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ndarray::Array;
+
+    // Features: all zero, constant, varying, twice the varying one, one correlated with it
+    fn counts() -> Points {
+        Array::from_shape_vec((4,5),vec![
+            0.,3.,1.,2.,1.,
+            0.,3.,2.,4.,2.,
+            0.,3.,3.,6.,4.,
+            0.,3.,5.,10.,5.,
+        ]).unwrap().into()
+    }
+
+    fn reasons(sanitization: &Sanitization) -> Vec<(usize,&'static str,&'static str,Option<usize>)> {
+        sanitization.changes.iter().map(|change| (change.feature,change.action,change.reason,change.partner)).collect()
+    }
+
+    #[test]
+    fn drops_uniform_features_and_multiples() {
+        let (points,sanitization) = Sanitization::sanitize(counts(),Sanitize::Drop,None);
+        assert_eq!(sanitization.kept,vec![2,4]);
+        assert_eq!(reasons(&sanitization),vec![(0,"dropped","all_zero",None),(1,"dropped","constant",None),(3,"dropped","multiple",Some(2))]);
+        assert!((sanitization.changes[2].value - 2.).abs() < 1e-12);
+        assert_eq!(points.shape(),(4,2));
+    }
+
+    #[test]
+    fn repair_fills_zero_features_and_keeps_constant_ones() {
+        let (points,sanitization) = Sanitization::sanitize(counts(),Sanitize::Repair,None);
+        assert_eq!(sanitization.kept,vec![0,1,2,4]);
+        assert_eq!(reasons(&sanitization),vec![(0,"filled","all_zero",None),(3,"dropped","multiple",Some(2))]);
+        assert_eq!(points.view().column(0).to_vec(),vec![1.;4]);
+    }
+
+    #[test]
+    fn collinear_features_are_dropped_above_the_threshold() {
+        let (_,sanitization) = Sanitization::sanitize(counts(),Sanitize::Drop,Some(0.95));
+        assert_eq!(sanitization.kept,vec![2]);
+        assert_eq!(reasons(&sanitization)[3],(4,"dropped","collinear",Some(2)));
+        let (_,sanitization) = Sanitization::sanitize(counts(),Sanitize::Drop,Some(0.99));
+        assert_eq!(sanitization.kept,vec![2,4]);
+    }
+
+    #[test]
+    fn sparse_counts_sanitize_like_dense_ones() {
+        let sparse = Points::Sparse(Arc::new(CsrMatrix::from_dense(counts().view())));
+        let (points,sanitization) = Sanitization::sanitize(sparse,Sanitize::Repair,None);
+        let (dense,_) = Sanitization::sanitize(counts(),Sanitize::Repair,None);
+        assert_eq!(sanitization.kept,vec![0,1,2,4]);
+        assert_eq!(points.into_owned(),dense.into_owned());
+    }
+}
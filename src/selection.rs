@@ -0,0 +1,177 @@
+use std::f64;
+use std::io::Error;
+use std::io::prelude::*;
+use std::cmp::Ordering;
+
+use ndarray::{Array,ArrayView,Axis,Ix1,Ix2};
+
+use sparse::CsrMatrix;
+
+// Highly variable feature selection. Features are ranked by dispersion, variance
+// over mean, either as is or normalized within bins of features with similar means
+// (as Seurat does), so that highly expressed features don't win by their mean alone.
+// Features with a mean of 0 have no dispersion and are ranked last.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Dispersion {
+    Raw,
+    // z-score of the log dispersion within this many equal-width bins of log1p(mean)
+    Binned(usize),
+}
+
+#[derive(Debug,Clone)]
+pub struct FeatureSelection {
+    pub features: usize,
+    pub means: Array<f64,Ix1>,
+    pub variances: Array<f64,Ix1>,
+    pub scores: Array<f64,Ix1>,
+    // Kept feature indices, in their original order
+    pub kept: Vec<usize>,
+}
+
+pub fn column_stats(counts: ArrayView<f64,Ix2>) -> (Array<f64,Ix1>,Array<f64,Ix1>) {
+    (counts.mean_axis(Axis(0)),counts.var_axis(Axis(0),0.))
+}
+
+pub fn sparse_column_stats(counts: &CsrMatrix) -> (Array<f64,Ix1>,Array<f64,Ix1>) {
+    let mut sums: Array<f64,Ix1> = Array::zeros(counts.cols);
+    let mut squares: Array<f64,Ix1> = Array::zeros(counts.cols);
+    for (j,x) in counts.indices.iter().zip(counts.data.iter()) {
+        sums[*j] += x;
+        squares[*j] += x.powi(2);
+    }
+    let n = counts.rows.max(1) as f64;
+    let means = sums / n;
+    let variances = (squares / n - &means.mapv(|x| x.powi(2))).mapv(|x| x.max(0.));
+    (means,variances)
+}
+
+impl FeatureSelection {
+
+    pub fn select(means: Array<f64,Ix1>, variances: Array<f64,Ix1>, method: Dispersion, keep: usize) -> FeatureSelection {
+
+        let features = means.len();
+
+        let dispersions: Array<f64,Ix1> = Array::from_shape_fn(features,|j| {
+            if means[j] > 0. { variances[j] / means[j] } else { f64::NAN }
+        });
+
+        let scores = match method {
+            Dispersion::Raw => dispersions.clone(),
+            Dispersion::Binned(bins) => binned_dispersion(&means,&dispersions,bins.max(1)),
+        };
+
+        let mut order: Vec<usize> = (0..features).collect();
+        order.sort_by(|a,b| {
+            let (a,b) = (scores[*a],scores[*b]);
+            match (a.is_nan(),b.is_nan()) {
+                (false,false) => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+                (x,y) => x.cmp(&y),
+            }
+        });
+        let mut kept: Vec<usize> = order.into_iter().take(keep).collect();
+        kept.sort();
+
+        if keep > features {
+            eprintln!("WARNING: asked for {} variable features out of {}, keeping all of them",keep,features);
+        }
+        eprintln!("Selected {} of {} features by {:?} dispersion",kept.len(),features,method);
+
+        FeatureSelection { features, means, variances, scores, kept }
+    }
+
+    // One line per feature, after the index of the pipeline step that did the selection
+    pub fn write_rows<W: Write>(&self, step: usize, feature_names: Option<&[String]>, report_file: &mut W) -> Result<(),Error> {
+        let mut kept = self.kept.iter().peekable();
+        for j in 0..self.features {
+            let is_kept = kept.peek() == Some(&&j);
+            if is_kept {
+                kept.next();
+            }
+            let name = feature_names.map(|names| names[j].clone()).unwrap_or_else(|| j.to_string());
+            writeln!(report_file,"{}\t{}\t{}\t{:?}\t{:?}\t{:?}\t{}",step,j,name,self.means[j],self.variances[j],self.scores[j],is_kept)?;
+        }
+        Ok(())
+    }
+}
+
+fn binned_dispersion(means: &Array<f64,Ix1>, dispersions: &Array<f64,Ix1>, bins: usize) -> Array<f64,Ix1> {
+
+    let log_means = means.mapv(|x| x.ln_1p());
+    let log_dispersions = dispersions.mapv(|x| x.ln());
+
+    let defined: Vec<usize> = (0..means.len()).filter(|j| log_dispersions[*j].is_finite()).collect();
+    let lowest = defined.iter().map(|j| log_means[*j]).fold(f64::INFINITY,f64::min);
+    let highest = defined.iter().map(|j| log_means[*j]).fold(f64::NEG_INFINITY,f64::max);
+    let width = (highest - lowest) / bins as f64;
+
+    let bin = |j: usize| if width > 0. { (((log_means[j] - lowest) / width) as usize).min(bins - 1) } else { 0 };
+
+    let mut members: Vec<Vec<usize>> = vec![vec![]; bins];
+    for j in &defined {
+        members[bin(*j)].push(*j);
+    }
+
+    let mut scores = Array::from_elem(means.len(),f64::NAN);
+    for bin in members {
+        let n = bin.len() as f64;
+        let mean = bin.iter().map(|j| log_dispersions[*j]).sum::<f64>() / n;
+        let deviation = (bin.iter().map(|j| (log_dispersions[*j] - mean).powi(2)).sum::<f64>() / (n - 1.).max(1.)).sqrt();
+        for j in bin {
+            scores[j] = if deviation > 0. { (log_dispersions[j] - mean) / deviation } else { 0. };
+        }
+    }
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Three features with a low mean, one of them dispersed, two with a high mean and
+    // higher dispersions, and one that is always 0
+    fn select(method: Dispersion, keep: usize) -> FeatureSelection {
+        let means = Array::from(vec![1.,1.,1.,10.,10.,0.]);
+        let variances = Array::from(vec![1.,1.,6.,100.,80.,0.]);
+        FeatureSelection::select(means,variances,method,keep)
+    }
+
+    #[test]
+    fn keeps_the_most_dispersed_features() {
+        assert_eq!(select(Dispersion::Raw,2).kept,vec![3,4]);
+        assert_eq!(select(Dispersion::Raw,5).kept,vec![0,1,2,3,4]);
+        assert_eq!(select(Dispersion::Raw,10).kept,vec![0,1,2,3,4,5]);
+        assert!(select(Dispersion::Raw,1).scores[5].is_nan());
+    }
+
+    #[test]
+    fn binning_ranks_features_against_similar_means() {
+        let selection = select(Dispersion::Binned(2),1);
+        assert_eq!(selection.kept,vec![2]);
+        assert!((selection.scores[2] - 2. / 3f64.sqrt()).abs() < 1e-9);
+        assert!((selection.scores[3] - 0.5f64.sqrt()).abs() < 1e-9);
+        assert_eq!(select(Dispersion::Binned(2),2).kept,vec![2,3]);
+        // A single bin ranks like the raw dispersion
+        assert_eq!(select(Dispersion::Binned(1),2).kept,vec![3,4]);
+    }
+
+    #[test]
+    fn writes_one_row_per_feature() {
+        let mut rows = vec![];
+        select(Dispersion::Raw,1).write_rows(2,None,&mut rows).unwrap();
+        let rows = String::from_utf8(rows).unwrap();
+        assert_eq!(rows.lines().count(),6);
+        assert_eq!(rows.lines().nth(3).unwrap(),"2\t3\t3\t10.0\t100.0\t10.0\ttrue");
+        assert!(rows.lines().nth(4).unwrap().ends_with("\tfalse"));
+    }
+
+    #[test]
+    fn sparse_stats_match_dense_ones() {
+        let counts = Array::from_shape_vec((3,2),vec![1.,0.,3.,2.,0.,0.]).unwrap();
+        let (means,variances) = column_stats(counts.view());
+        let (sparse_means,sparse_variances) = sparse_column_stats(&CsrMatrix::from_dense(counts.view()));
+        assert_eq!(means,sparse_means);
+        assert!(variances.iter().zip(sparse_variances.iter()).all(|(a,b)| (a - b).abs() < 1e-12));
+    }
+}
@@ -2,23 +2,22 @@ use ndarray::{Array,ArrayView,Ix1,Ix2,Axis};
 use std::f64;
 use std::sync::Arc;
 use std::collections::VecDeque;
-use rand::{Rng,ThreadRng,thread_rng};
+use rand::thread_rng;
 use rand::seq::sample_indices;
-use std::cmp::{min,max};
 use io::Parameters;
+use weights::Weights;
 // use ndarray_parallel::prelude::*;
 use length;
 use io::Distance;
+use sparse::{Rows,Row};
 
 #[derive(Debug)]
 pub struct Pathfinder {
     pub id: usize,
     samples: usize,
-    sample_indecies: Vec<usize>,
     features: usize,
     // points: Arc<Array<f64,Ix2>>,
     sample_subsample:usize,
-    sample_subsamples: Vec<usize>,
     previous_steps: VecDeque<Array<f64,Ix1>>,
     // previous_steps: VecDeque<f64>,
     distance: Distance,
@@ -27,23 +26,22 @@ pub struct Pathfinder {
     converged: bool,
     step_fraction: f64,
     cached_distance_sums: Arc<Vec<f64>>,
+    owners: Option<Arc<Vec<usize>>>,
+    weights: Option<Arc<Weights>>,
+    weighted_subsample: bool,
 }
 
 impl Pathfinder {
     // pub fn init(origin: ArrayView<'a,f64,Ix1>, gravity_points: Arc<Array<f64,Ix2>>, skip: usize, scaling_factor:Option<f64>, subsample_arg: Option<usize>, convergence_arg: Option<f64>,locality: Option<f64>) -> Pathfinder {
     pub fn init(id: usize, samples: usize,features: usize, parameters: Arc<Parameters>) -> Pathfinder {
 
-        let mut sample_indecies: Vec<usize> = (0..samples).collect();
-        sample_indecies.remove(id);
-
-        let mut subsample_size = sample_indecies.len();
+        let mut subsample_size = samples - 1;
         if samples > 1000 {
-            subsample_size = max(min(1000, subsample_size/10),2);
+            subsample_size = (subsample_size/10).clamp(2,1000);
         };
-        if parameters.sample_subsample.is_some() {
-            subsample_size = parameters.sample_subsample.unwrap();
+        if let Some(sample_subsample) = parameters.sample_subsample {
+            subsample_size = sample_subsample;
         };
-        let sample_subsamples = Vec::with_capacity(subsample_size);
 
         assert!(parameters.scaling_factor.as_ref().map(|x| if *x == 0. {0.} else {1.}).unwrap_or(1.) != 0.);
 
@@ -55,33 +53,31 @@ impl Pathfinder {
         // eprintln!("INITIALIZED");
 
         Pathfinder {
-            id: id,
-            samples: samples,
-            sample_indecies: sample_indecies,
-            features: features,
+            id,
+            samples,
+            features,
             // points: points,
             sample_subsample: subsample_size,
-            sample_subsamples: sample_subsamples,
             previous_steps: VecDeque::with_capacity(51),
             smoothing: parameters.smoothing.unwrap_or(5),
             distance: parameters.distance.unwrap_or(Distance::Cosine),
             convergence: parameters.convergence_factor.unwrap_or(1.),
             converged: false,
             step_fraction: parameters.step_fraction.unwrap_or(0.3),
-            cached_distance_sums: Arc::new(vec![])
+            cached_distance_sums: Arc::new(vec![]),
+            owners: None,
+            weights: parameters.weights.clone(),
+            weighted_subsample: parameters.weighted_subsample,
         }
 
     }
 
-    fn point(&self,points:&Arc<Array<f64,Ix2>>) -> Array<f64,Ix1> {
-        points.row(self.id).to_owned()
-    }
-
-    fn point_view<'a>(&'a self,points:&'a Arc<Array<f64,Ix2>>) -> ArrayView<'a,f64,Ix1> {
-        points.row(self.id)
+    // Dense rows are borrowed, only sparse rows are copied out
+    fn point<'a>(&self,points: Rows<'a>) -> Row<'a> {
+        points.row_view(self.id)
     }
 
-    pub fn memorize_step(&mut self,point_option: Option<Array<f64,Ix1>>,points:&Arc<Array<f64,Ix2>>) {
+    pub fn memorize_step(&mut self,point_option: Option<Array<f64,Ix1>>,points: Rows) {
         if !self.converged(points) {
             // eprintln!("PO:{:?}", point_option);
             if let Some(point) = point_option {
@@ -91,7 +87,7 @@ impl Pathfinder {
                 }
             }
             else {
-                let current_point = self.point(points);
+                let current_point = self.point(points).view().to_owned();
                 self.previous_steps.push_front(current_point);
                 if self.previous_steps.len() > 50 {
                     self.previous_steps.pop_back();
@@ -105,64 +101,69 @@ impl Pathfinder {
         self.cached_distance_sums = cache;
     }
 
-    fn subsampled_nearest(&self,points:Arc<Array<f64,Ix2>>) -> Option<(Array<f64,Ix1>,f64)> {
+    // When points have been collapsed into weighted particles, samples are still
+    // drawn uniformly, but each sample is looked up through the row of the particle
+    // that owns it. A particle carrying many samples is therefore proportionally
+    // more likely to be among the neighbors, as its members would have been.
 
-        // eprintln!("SS:{:?}",self.sample_subsamples);
-
-        let mut jump_point = None;
-
-        let mut maximum_jump_distance = f64::MAX;
+    pub fn set_owners(&mut self, owners: Arc<Vec<usize>>) {
+        self.owners = Some(owners);
+    }
 
-        for mut sub_point_index in sample_indices(&mut thread_rng(), self.samples, self.sample_subsample) {
-            // eprintln!("P:{:?}",self.point.view());
-            // eprintln!("S1:{:?}",self.points.row(*sub_point_index).view());
-            // eprintln!("D:{:?}",distance(self.point.view(),self.points.row(*sub_point_index).view()));
-            let point_distance = self.distance.measure(self.point_view(&points),points.row(sub_point_index).view());
-            if (point_distance < maximum_jump_distance) && point_distance > 0. {
-                maximum_jump_distance = point_distance;
-                jump_point = Some(sub_point_index);
-            }
+    fn subsample_indices(&self) -> Vec<usize> {
+        match (self.weighted_subsample,self.weights.as_ref()) {
+            (true,Some(weights)) => weights.sample(&mut thread_rng(), self.sample_subsample),
+            _ => sample_indices(&mut thread_rng(), self.samples, self.sample_subsample),
         }
-
-        jump_point.map(|x| (points.row(x).to_owned(),maximum_jump_distance))
-
     }
 
-    fn subsampled_nearest_n(&self,n: usize,points:Arc<Array<f64,Ix2>>) -> Vec<(Array<f64,Ix1>,f64)> {
+    fn row(&self,sample: usize) -> usize {
+        match self.owners.as_ref() {
+            Some(owners) => owners[sample],
+            None => sample,
+        }
+    }
 
-        self.subsampled_nearest_n_to(self.point_view(&points),n,points.clone())
+    // Neighbors drawn in proportion to their weight already count once per draw,
+    // so they are not weighted a second time when averaged.
 
+    fn neighbor_weight(&self,sample: usize) -> f64 {
+        match (self.weighted_subsample,self.weights.as_ref()) {
+            (false,Some(weights)) => weights.weight(sample),
+            _ => 1.,
+        }
     }
 
-    fn subsampled_nearest_n_to(&self,center: ArrayView<f64,Ix1>,n: usize,points:Arc<Array<f64,Ix2>>) -> Vec<(Array<f64,Ix1>,f64)> {
+    // The n nearest of a subsample, as (row, distance, weight)
 
-        let mut sub_points: Vec<(Array<f64,Ix1>,f64)> = Vec::with_capacity(n+1);
+    fn subsampled_nearest_n_to(&self,center: ArrayView<f64,Ix1>,n: usize,points: Rows) -> Vec<(usize,f64,f64)> {
 
-        let sample_subsamples = sample_indices(&mut thread_rng(), self.samples, self.sample_subsample);
+        let mut sub_points: Vec<(usize,f64,f64)> = Vec::with_capacity(n+1);
 
-        if let Some(first_index) = sample_subsamples.get(0) {
-            let first_subsample = points.row(*first_index);
-            sub_points.push((first_subsample.to_owned(),self.distance.measure(center, first_subsample.view())));
+        let sample_subsamples = self.subsample_indices();
+        let center_norm = center.dot(&center).sqrt();
+
+        if let Some(first_sample) = sample_subsamples.first() {
+            let first_row = self.row(*first_sample);
+            sub_points.push((first_row,points.measure_from(self.distance,center,center_norm,first_row),self.neighbor_weight(*first_sample)));
         }
 
         match self.distance {
             Distance::Cosine => {
-                let p1ss = center.map(|x| x.powi(2)).sum().sqrt();
-                for sub_point_index in sample_subsamples {
-                    let p2ss;
-                    if self.cached_distance_sums.len() > 0 {
-                        p2ss = self.cached_distance_sums[sub_point_index];
+                for sub_sample in sample_subsamples {
+                    let sub_point_index = self.row(sub_sample);
+                    let p2ss = if !self.cached_distance_sums.is_empty() {
+                        self.cached_distance_sums[sub_point_index]
                     }
                     else {
-                        p2ss = points.row(sub_point_index).map(|y| y.powi(2)).sum().sqrt();
-                    }
-                    let sub_point = points.row(sub_point_index);
-                    let dot_product = center.dot(&sub_point);
-                    let sub_point_distance = 1.0 - (dot_product / (p1ss * p2ss));
+                        points.norm(sub_point_index)
+                    };
+                    let dot_product = points.dot(sub_point_index,center);
+                    let sub_point_distance = 1.0 - (dot_product / (center_norm * p2ss));
 
                     let mut insert_index = None;
 
-                    for (i,(previous_point,previous_distance)) in sub_points.iter().enumerate() {
+                    for (i,(_previous_point,previous_distance,_)) in sub_points.iter().enumerate() {
                         if sub_point_distance < *previous_distance {
                             insert_index = Some(i);
                             break
@@ -170,7 +171,7 @@ impl Pathfinder {
                     }
 
                     if let Some(insert) = insert_index {
-                        sub_points.insert(insert,(sub_point.to_owned(),sub_point_distance));
+                        sub_points.insert(insert,(sub_point_index,sub_point_distance,self.neighbor_weight(sub_sample)));
                     }
 
                     sub_points.truncate(n+1);
@@ -178,14 +179,15 @@ impl Pathfinder {
                 }
             }
             _ => {
-                for sub_point_index in sample_subsamples {
+                for sub_sample in sample_subsamples {
+
+                    let sub_point_index = self.row(sub_sample);
 
-                    let sub_point = points.row(sub_point_index);
-                    let sub_point_distance = self.distance.measure(center,sub_point.view());
+                    let sub_point_distance = points.measure_from(self.distance,center,center_norm,sub_point_index);
 
                     let mut insert_index = None;
 
-                    for (i,(previous_point,previous_distance)) in sub_points.iter().enumerate() {
+                    for (i,(_previous_point,previous_distance,_)) in sub_points.iter().enumerate() {
                         if sub_point_distance < *previous_distance {
                             insert_index = Some(i);
                             break
@@ -193,7 +195,7 @@ impl Pathfinder {
                     }
 
                     if let Some(insert) = insert_index {
-                        sub_points.insert(insert,(sub_point.to_owned(),sub_point_distance));
+                        sub_points.insert(insert,(sub_point_index,sub_point_distance,self.neighbor_weight(sub_sample)));
                     }
 
                     sub_points.truncate(n+1);
@@ -208,16 +210,16 @@ impl Pathfinder {
 
 
 
-    pub fn step(&self,points:&Arc<Array<f64,Ix2>>) -> Option<(Array<f64,Ix1>,f64)> {
+    pub fn step(&self,points: Rows) -> Option<(Array<f64,Ix1>,f64)> {
 
         // eprintln!("Pathfinder data: {:?},{:?}", points.rows(),points.cols());
         // eprintln!("Stepping from {:?}", self.point_view(&points));
 
-        self.step_from(self.point_view(&points),points)
+        self.step_from(self.point(points).view(),points)
 
     }
 
-    pub fn step_from(&self,point: ArrayView<f64,Ix1>,points:&Arc<Array<f64,Ix2>>) -> Option<(Array<f64,Ix1>,f64)> {
+    pub fn step_from(&self,point: ArrayView<f64,Ix1>,points: Rows) -> Option<(Array<f64,Ix1>,f64)> {
 
         if self.converged {
             return None
@@ -231,24 +233,24 @@ impl Pathfinder {
 
         let mut jump_point = Array::zeros(self.features);
         // let mut total_distance = 0.;
-        let mut bag_counter = 0;
+        let mut bag_weight = 0.;
 
-        for (bagged_point,_jump_distance) in self.subsampled_nearest_n_to(point,smoothing,points.clone()) {
-            jump_point += &bagged_point;
-            bag_counter += 1;
+        for (bagged_row,_jump_distance,weight) in self.subsampled_nearest_n_to(point,smoothing,points) {
+            points.add_row(&mut jump_point,bagged_row,weight);
+            bag_weight += weight;
         };
 
         // eprintln!("Subsampling successful");
 
-        if bag_counter > 0 {
+        if bag_weight > 0. {
 
-            jump_point /= bag_counter as f64;
+            jump_point /= bag_weight;
 
             // eprintln!("J:{:?}",jump_point);
 
             jump_point = (jump_point * self.step_fraction) + (&point * (1. - self.step_fraction));
 
-            let jump_distance = self.distance.measure(jump_point.view(),self.point_view(&points));
+            let jump_distance = points.measure(self.distance,jump_point.view(),self.id);
 
             // eprintln!("J:{:?}",jump_point);
 
@@ -258,28 +260,19 @@ impl Pathfinder {
         None
     }
 
-    pub fn single_descend(&mut self,points:&Arc<Array<f64,Ix2>>) -> (Array<f64,Ix1>,f64) {
+    pub fn single_descend(&mut self,points: Rows) -> (Array<f64,Ix1>,f64) {
 
-        let mut point = self.point(points);
-        let mut step_counter = 0;
-        let mut distance = 0.;
+        let mut point = self.point(points).view().to_owned();
+        let distance = 0.;
 
         if let Distance::Cosine = self.distance {
-            self.cached_distance_sums = Arc::new(points
-                                            .axis_iter(Axis(0))
-                                            .map(|x| {
-                                                x.map(|y| y.powi(2)).sum().sqrt()
-                                            }).collect())
+            self.cached_distance_sums = Arc::new((0..points.rows()).map(|i| points.norm(i)).collect())
         }
 
-        while let Some((step,distance)) = self.step_from(point.view(),points) {
+        while let Some((step,_distance)) = self.step_from(point.view(),points) {
             point = step;
             self.memorize_step(Some(point.clone()),points);
             // self.memorize_step(Some((point.clone(),distance)));
-            step_counter += 1;
-            // if step_counter%10 == 0 {
-            //     // eprintln!("S:{:?}",step_counter);
-            // }
         }
 
         // eprintln!("Steps:{:?}", self.previous_steps);
@@ -292,11 +285,14 @@ impl Pathfinder {
 
     }
 
-    pub fn fuzzy_descend(&mut self,fuzz_opt:Option<usize>,points:Arc<Array<f64,Ix2>>) -> (Array<f64,Ix1>,(f64,f64)) {
+    // The average of several descents, their deviations, and the endpoint of every
+    // individual descent
+
+    pub fn fuzzy_descend_endpoints(&mut self,fuzz_opt:Option<usize>,points: Rows) -> (Array<f64,Ix1>,(f64,f64),Array<f64,Ix2>) {
     // pub fn fuzzy_descend(&mut self,fuzz:usize,points:&Array<f64,Ix2>) -> (Array<f64,Ix1>,(f64,f64)) {
         let fuzz = fuzz_opt.unwrap_or(10);
 
-        let mut final_points = self.multiple_descend(fuzz,&points);
+        let mut final_points = self.multiple_descend(fuzz,points);
 
         let average_point = final_points.sum_axis(Axis(0))/fuzz as f64;
 
@@ -304,7 +300,7 @@ impl Pathfinder {
 
         let mut av_deviation = 0.;
 
-        for mut final_point in final_points.outer_iter_mut() {
+        for final_point in final_points.outer_iter_mut() {
             av_deviation += self.distance.measure(average_point.view(),final_point.view()) / fuzz as f64;
             // final_point.scaled_add(-1.,&average_point);
             // av_deviation += length(final_point.view())/fuzz as f64;
@@ -312,7 +308,7 @@ impl Pathfinder {
 
         // eprintln!("Deviation:{:?}",av_deviation);
 
-        let displacement = length((&self.point(&points) - &average_point).view());
+        let displacement = length((&self.point(points).view() - &average_point).view());
 
         // let displacement = self.distance.measure(self.point_view(&points),average_point.view());
 
@@ -320,22 +316,22 @@ impl Pathfinder {
 
         // eprintln!("Steps:{:?}", self.previous_steps);
 
-        (average_point,(av_deviation,displacement))
+        (average_point,(av_deviation,displacement),final_points)
 
     }
 
-    pub fn multiple_descend(&mut self,fuzz:usize,points:&Arc<Array<f64,Ix2>>) -> Array<f64,Ix2> {
+    pub fn multiple_descend(&mut self,fuzz:usize,points: Rows) -> Array<f64,Ix2> {
 
         let mut final_points: Array<f64,Ix2> = Array::zeros((fuzz,self.features));
 
         for i in 0..fuzz {
-            final_points.row_mut(i).assign(&self.single_descend(&points).0);
+            final_points.row_mut(i).assign(&self.single_descend(points).0);
         }
 
         final_points
     }
 
-    fn converged(&mut self,points:&Arc<Array<f64,Ix2>>) -> bool {
+    fn converged(&mut self,points: Rows) -> bool {
         if self.previous_steps.len() < 50 {
             return false
         }
@@ -344,17 +340,17 @@ impl Pathfinder {
         }
         let distant_point = self.previous_steps.back().unwrap().view();
         let previous_point = self.previous_steps.front().unwrap().view();
-        let current_point = self.point(&points);
+        let current_point = self.point(points);
         let short_displacement = self.distance.measure(previous_point,current_point.view());
         let long_displacement = self.distance.measure(distant_point,current_point.view());
         // eprintln!("SD:{:?}",short_displacement);
         // eprintln!("LD:{:?}",long_displacement);
         if long_displacement < (short_displacement * self.convergence) {
             self.converged = true;
-            return true
+            true
         }
         else {
-            return false
+            false
         }
     }
 
@@ -378,23 +374,16 @@ impl Pathfinder {
     //     self.previous_steps.iter().rev().take(10).sum::<f64>() / 10.
     // }
 
-    pub fn fuzz(&self) -> f64 {
-        let distant_point = self.previous_steps.back().unwrap().view();
-        let previous_point = self.previous_steps.front().unwrap().view();
-        self.distance.measure(distant_point,previous_point)
-    }
-
-    pub fn sub_fuzz(&self,fuzz: usize,points:Arc<Array<f64,Ix2>>) -> f64 {
+    pub fn sub_fuzz(&self,fuzz: usize,points: Rows) -> f64 {
         let mut acc = 0.;
-        for (point,_jump_distance) in self.subsampled_nearest_n(fuzz, points.clone()) {
-            acc += self.distance.measure(self.point_view(&points), point.view()) / fuzz as f64;
+        let point = self.point(points);
+        let point = point.view();
+        let point_norm = point.dot(&point).sqrt();
+        for (row,_jump_distance,_weight) in self.subsampled_nearest_n_to(point.view(),fuzz,points) {
+            acc += points.measure_from(self.distance,point.view(),point_norm,row) / fuzz as f64;
         }
         acc
     }
 
 }
 
-fn test(){
-    fn is_sync<T:Sync>(){}
-    is_sync::<Pathfinder>();
-}
@@ -0,0 +1,408 @@
+use std::fs::File;
+use std::io;
+use std::io::{Error,ErrorKind};
+use std::io::prelude::*;
+
+use flate2::read::MultiGzDecoder;
+use ndarray::{Array,ArrayView,Ix1,Ix2,Axis};
+
+use io::Distance;
+
+/// Compressed sparse rows: the entries of row i are indices/data[indptr[i]..indptr[i+1]],
+/// with column indices increasing.
+#[derive(Debug,Clone)]
+pub struct CsrMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub indptr: Vec<usize>,
+    pub indices: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct SparseRow<'a> {
+    pub indices: &'a [usize],
+    pub data: &'a [f64],
+}
+
+impl<'a> SparseRow<'a> {
+
+    pub fn dot(&self, dense: ArrayView<f64,Ix1>) -> f64 {
+        self.indices.iter().zip(self.data.iter()).map(|(j,x)| x * dense[*j]).sum()
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.data.iter().map(|x| x.powi(2)).sum::<f64>().sqrt()
+    }
+}
+
+impl CsrMatrix {
+
+    // Builds the matrix from (row, column, value) entries in any order. Repeated
+    // entries are summed.
+
+    pub fn from_triplets(rows: usize, cols: usize, mut triplets: Vec<(usize,usize,f64)>) -> CsrMatrix {
+
+        triplets.sort_by_key(|(i,j,_)| (*i,*j));
+
+        let mut indptr = vec![0; rows + 1];
+        let mut indices: Vec<usize> = Vec::with_capacity(triplets.len());
+        let mut data: Vec<f64> = Vec::with_capacity(triplets.len());
+        let mut last: Option<(usize,usize)> = None;
+
+        for (i,j,x) in triplets {
+            if i >= rows || j >= cols {
+                panic!("Sparse entry ({},{}) is outside of a {}x{} matrix",i + 1,j + 1,rows,cols);
+            }
+            if last == Some((i,j)) {
+                *data.last_mut().unwrap() += x;
+                continue
+            }
+            indices.push(j);
+            data.push(x);
+            indptr[i + 1] += 1;
+            last = Some((i,j));
+        }
+
+        for i in 0..rows {
+            indptr[i + 1] += indptr[i];
+        }
+
+        CsrMatrix { rows, cols, indptr, indices, data }
+    }
+
+    // Keeps the nonzero entries
+    pub fn from_dense(dense: ArrayView<f64,Ix2>) -> CsrMatrix {
+        let triplets = dense.indexed_iter().filter(|(_,x)| **x != 0.).map(|((i,j),x)| (i,j,*x)).collect();
+        CsrMatrix::from_triplets(dense.rows(),dense.cols(),triplets)
+    }
+
+    pub fn row(&self, i: usize) -> SparseRow<'_> {
+        let (start,end) = (self.indptr[i],self.indptr[i + 1]);
+        SparseRow { indices: &self.indices[start..end], data: &self.data[start..end] }
+    }
+
+    pub fn dense_row(&self, i: usize) -> Array<f64,Ix1> {
+        let mut row = Array::zeros(self.cols);
+        let sparse = self.row(i);
+        for (j,x) in sparse.indices.iter().zip(sparse.data.iter()) {
+            row[*j] = *x;
+        }
+        row
+    }
+
+    pub fn to_dense(&self) -> Array<f64,Ix2> {
+        let mut dense = Array::zeros((self.rows,self.cols));
+        for i in 0..self.rows {
+            let sparse = self.row(i);
+            for (j,x) in sparse.indices.iter().zip(sparse.data.iter()) {
+                dense[[i,*j]] = *x;
+            }
+        }
+        dense
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn transpose(&self) -> CsrMatrix {
+        let mut triplets = Vec::with_capacity(self.nnz());
+        for i in 0..self.rows {
+            let row = self.row(i);
+            triplets.extend(row.indices.iter().zip(row.data.iter()).map(|(j,x)| (*j,i,*x)));
+        }
+        CsrMatrix::from_triplets(self.cols,self.rows,triplets)
+    }
+
+    // Keeps these columns, renumbered in the order given, which must be increasing
+    pub fn select_columns(&self, columns: &[usize]) -> CsrMatrix {
+        let mut renumbered = vec![None; self.cols];
+        for (new,old) in columns.iter().enumerate() {
+            renumbered[*old] = Some(new);
+        }
+        let mut indptr = Vec::with_capacity(self.rows + 1);
+        let mut indices = vec![];
+        let mut data = vec![];
+        indptr.push(0);
+        for i in 0..self.rows {
+            let row = self.row(i);
+            for (j,x) in row.indices.iter().zip(row.data.iter()) {
+                if let Some(new) = renumbered[*j] {
+                    indices.push(new);
+                    data.push(*x);
+                }
+            }
+            indptr.push(indices.len());
+        }
+        CsrMatrix { rows: self.rows, cols: columns.len(), indptr, indices, data }
+    }
+}
+
+/// The rows pathfinders search through, either dense or sparse. Walkers themselves
+/// are always dense, so distances are between a dense point and a row.
+#[derive(Debug,Clone,Copy)]
+pub enum Rows<'a> {
+    Dense(ArrayView<'a,f64,Ix2>),
+    Sparse(&'a CsrMatrix),
+}
+
+/// A single row of `Rows`, borrowed when dense and densified when sparse
+#[derive(Debug,Clone)]
+pub enum Row<'a> {
+    Dense(ArrayView<'a,f64,Ix1>),
+    Sparse(Array<f64,Ix1>),
+}
+
+impl<'a> Row<'a> {
+    pub fn view(&self) -> ArrayView<'_,f64,Ix1> {
+        match self {
+            Row::Dense(view) => view.view(),
+            Row::Sparse(row) => row.view(),
+        }
+    }
+}
+
+impl<'a> From<ArrayView<'a,f64,Ix2>> for Rows<'a> {
+    fn from(view: ArrayView<'a,f64,Ix2>) -> Rows<'a> {
+        Rows::Dense(view)
+    }
+}
+
+impl<'a> Rows<'a> {
+
+    pub fn rows(&self) -> usize {
+        match self {
+            Rows::Dense(view) => view.rows(),
+            Rows::Sparse(matrix) => matrix.rows,
+        }
+    }
+
+    pub fn row(&self, i: usize) -> Array<f64,Ix1> {
+        match self {
+            Rows::Dense(view) => view.row(i).to_owned(),
+            Rows::Sparse(matrix) => matrix.dense_row(i),
+        }
+    }
+
+    pub fn row_view(&self, i: usize) -> Row<'a> {
+        match *self {
+            Rows::Dense(view) => Row::Dense(view.index_axis_move(Axis(0),i)),
+            Rows::Sparse(matrix) => Row::Sparse(matrix.dense_row(i)),
+        }
+    }
+
+    pub fn norm(&self, i: usize) -> f64 {
+        match self {
+            Rows::Dense(view) => view.row(i).map(|y| y.powi(2)).sum().sqrt(),
+            Rows::Sparse(matrix) => matrix.row(i).norm(),
+        }
+    }
+
+    pub fn dot(&self, i: usize, point: ArrayView<f64,Ix1>) -> f64 {
+        match self {
+            Rows::Dense(view) => point.dot(&view.row(i)),
+            Rows::Sparse(matrix) => matrix.row(i).dot(point),
+        }
+    }
+
+    // Cosine and Euclidean distances only touch the stored entries of a sparse row,
+    // other distances see it densified.
+
+    pub fn measure(&self, distance: Distance, point: ArrayView<f64,Ix1>, i: usize) -> f64 {
+        match self {
+            Rows::Dense(view) => distance.measure(point,view.row(i)),
+            Rows::Sparse(_) => self.measure_from(distance,point,point.dot(&point).sqrt(),i),
+        }
+    }
+
+    // As measure, with the norm of the point computed once by the caller, for
+    // comparing one point to many rows.
+
+    pub fn measure_from(&self, distance: Distance, point: ArrayView<f64,Ix1>, point_norm: f64, i: usize) -> f64 {
+        match (self,distance) {
+            (Rows::Dense(view),_) => distance.measure(point,view.row(i)),
+            (Rows::Sparse(matrix),Distance::Cosine) => {
+                let row = matrix.row(i);
+                1.0 - (row.dot(point) / (point_norm * row.norm()))
+            },
+            (Rows::Sparse(matrix),Distance::Euclidean) => {
+                let row = matrix.row(i);
+                let mut acc = point_norm.powi(2);
+                for (j,x) in row.indices.iter().zip(row.data.iter()) {
+                    acc += (point[*j] - x).powi(2) - point[*j].powi(2);
+                }
+                acc.max(0.).sqrt()
+            },
+            (Rows::Sparse(matrix),_) => distance.measure(point,matrix.dense_row(i).view()),
+        }
+    }
+
+    pub fn add_row(&self, acc: &mut Array<f64,Ix1>, i: usize, weight: f64) {
+        match self {
+            Rows::Dense(view) => acc.scaled_add(weight,&view.row(i)),
+            Rows::Sparse(matrix) => {
+                let row = matrix.row(i);
+                for (j,x) in row.indices.iter().zip(row.data.iter()) {
+                    acc[*j] += weight * x;
+                }
+            },
+        }
+    }
+}
+
+// Matrix Market coordinate files: a "%%MatrixMarket matrix coordinate <field> <symmetry>"
+// banner, % comments, a "rows cols entries" line, then 1-based "row col value" lines.
+// Pattern matrices have no values and read as 1.
+
+pub fn read_mtx<R: BufRead>(reader: R, verbose: bool) -> Result<CsrMatrix,Error> {
+
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData,message);
+
+    let mut lines = reader.lines();
+
+    let banner = lines.next().ok_or_else(|| invalid("Empty Matrix Market file".to_string()))??;
+    let banner_fields: Vec<String> = banner.split_whitespace().map(|x| x.to_lowercase()).collect();
+    if banner_fields.len() < 5 || banner_fields[0] != "%%matrixmarket" || banner_fields[1] != "matrix" {
+        return Err(invalid(format!("Not a Matrix Market matrix: {:?}",banner)))
+    }
+    if banner_fields[2] != "coordinate" {
+        return Err(invalid(format!("Only coordinate Matrix Market files are supported, found {:?}",banner_fields[2])))
+    }
+    let pattern = banner_fields[3] == "pattern";
+    if banner_fields[3] == "complex" {
+        return Err(invalid("Complex Matrix Market files are not supported".to_string()))
+    }
+    let symmetric = match &banner_fields[4][..] {
+        "general" => false,
+        "symmetric" => true,
+        other => return Err(invalid(format!("Unsupported Matrix Market symmetry: {:?}",other))),
+    };
+
+    let mut size: Option<(usize,usize,usize)> = None;
+    let mut triplets: Vec<(usize,usize,f64)> = vec![];
+    let mut read_entries = 0;
+
+    for (line_number,line) in lines.enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('%') {
+            continue
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parse_index = |x: &str| x.parse::<usize>().map_err(|_| invalid(format!("Bad index {:?} on line {}",x,line_number + 2)));
+        match size {
+            None => {
+                if fields.len() < 3 {
+                    return Err(invalid(format!("Bad Matrix Market size line: {:?}",line)))
+                }
+                let (rows,cols,entries) = (parse_index(fields[0])?,parse_index(fields[1])?,parse_index(fields[2])?);
+                triplets.reserve(if symmetric { entries * 2 } else { entries });
+                size = Some((rows,cols,entries));
+            },
+            Some((rows,cols,_)) => {
+                if fields.len() < if pattern { 2 } else { 3 } {
+                    return Err(invalid(format!("Bad Matrix Market entry on line {}: {:?}",line_number + 2,line)))
+                }
+                let (i,j) = (parse_index(fields[0])?,parse_index(fields[1])?);
+                if i == 0 || j == 0 {
+                    return Err(invalid(format!("Matrix Market indices start at 1, found 0 on line {}",line_number + 2)))
+                }
+                if i > rows || j > cols {
+                    return Err(invalid(format!("Matrix Market entry ({},{}) on line {} is outside of a {}x{} matrix",i,j,line_number + 2,rows,cols)))
+                }
+                let value = if pattern { 1. } else {
+                    fields[2].parse::<f64>().map_err(|_| invalid(format!("Bad value {:?} on line {}",fields[2],line_number + 2)))?
+                };
+                triplets.push((i - 1,j - 1,value));
+                read_entries += 1;
+                if symmetric && i != j {
+                    triplets.push((j - 1,i - 1,value));
+                }
+            },
+        }
+    }
+
+    let (rows,cols,entries) = size.ok_or_else(|| invalid("Matrix Market file has no size line".to_string()))?;
+    if read_entries != entries {
+        return Err(invalid(format!("Matrix Market file lists {} entries, found {}",entries,read_entries)))
+    }
+
+    if verbose {
+        eprintln!("Matrix Market: {}x{}, {} entries",rows,cols,entries);
+    }
+
+    Ok(CsrMatrix::from_triplets(rows,cols,triplets))
+}
+
+// Gzipped if the name ends in .gz
+pub fn read_mtx_file(location: &str, verbose: bool) -> Result<CsrMatrix,Error> {
+    read_mtx(open_maybe_gz(location)?,verbose)
+}
+
+pub fn open_maybe_gz(location: &str) -> Result<Box<dyn BufRead>,Error> {
+    let file = File::open(location)?;
+    if location.ends_with(".gz") {
+        Ok(Box::new(io::BufReader::new(MultiGzDecoder::new(file))))
+    }
+    else {
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn read(text: &str) -> Result<CsrMatrix,Error> {
+        read_mtx(text.as_bytes(),false)
+    }
+
+    #[test]
+    fn reads_general_coordinates_summing_duplicates() {
+        let matrix = read("%%MatrixMarket matrix coordinate real general\n% comment\n2 3 4\n1 1 1.5\n2 3 -2\n1 1 0.5\n2 1 4\n").unwrap();
+        assert_eq!(matrix.to_dense(),Array::from_shape_vec((2,3),vec![2.,0.,0.,4.,0.,-2.]).unwrap());
+        assert_eq!(matrix.nnz(),3);
+    }
+
+    #[test]
+    fn mirrors_symmetric_and_fills_pattern_entries() {
+        let matrix = read("%%MatrixMarket matrix coordinate pattern symmetric\n3 3 2\n2 1\n3 3\n").unwrap();
+        assert_eq!(matrix.to_dense(),Array::from_shape_vec((3,3),vec![0.,1.,0.,1.,0.,0.,0.,0.,1.]).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(read("").is_err());
+        assert!(read("%%MatrixMarket matrix array real general\n2 2\n1\n2\n3\n4\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate complex general\n1 1 1\n1 1 1 0\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1\n").is_err());
+        assert!(read("%%MatrixMarket matrix coordinate real general\n2 2 2\n1 1 1\n").is_err());
+    }
+
+    #[test]
+    fn sparse_distances_match_dense() {
+        let dense = Array::from_shape_vec((2,3),vec![1.,0.,2.,0.,3.,-1.]).unwrap();
+        let sparse = CsrMatrix::from_dense(dense.view());
+        let point: Array<f64,Ix1> = Array::from_vec(vec![0.5,1.,-1.]);
+        let norm = point.dot(&point).sqrt();
+        for distance in [Distance::Cosine,Distance::Euclidean].iter() {
+            for i in 0..2 {
+                let expected = Rows::Dense(dense.view()).measure(*distance,point.view(),i);
+                assert!((Rows::Sparse(&sparse).measure_from(*distance,point.view(),norm,i) - expected).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn row_views_borrow_dense_rows() {
+        let dense = Array::from_shape_vec((2,3),vec![1.,0.,2.,0.,3.,-1.]).unwrap();
+        let sparse = CsrMatrix::from_dense(dense.view());
+        match Rows::Dense(dense.view()).row_view(1) {
+            Row::Dense(view) => assert_eq!(view.as_ptr(),dense.row(1).as_ptr()),
+            Row::Sparse(_) => panic!("Dense row was copied"),
+        }
+        assert_eq!(Rows::Sparse(&sparse).row_view(1).view(),dense.row(1));
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::HashMap;
+
+use ndarray::{Array,Ix1,Ix2};
+
+use io::AtomicFile;
+
+// Labels of the same samples clustered at several subsample rates, in the order
+// the rates were given. Clusters of neighboring resolutions are linked by how many
+// samples they share, which gives a clustree style parent/child tree.
+
+#[derive(Debug,Clone)]
+pub struct Sweep {
+    pub rates: Vec<usize>,
+    pub labels: Vec<Array<i64,Ix1>>,
+}
+
+#[derive(Debug,Clone)]
+pub struct SweepEdge {
+    pub resolution: usize,
+    pub parent: i64,
+    pub child: i64,
+    pub overlap: usize,
+    pub child_fraction: f64,
+    pub best: bool,
+}
+
+impl Sweep {
+
+    pub fn new() -> Sweep {
+        Sweep {
+            rates: vec![],
+            labels: vec![],
+        }
+    }
+
+    pub fn push(&mut self, rate: usize, labels: Array<i64,Ix1>) {
+        self.rates.push(rate);
+        self.labels.push(labels);
+    }
+
+    // One row per rate, the rate first and then the label of every sample
+    pub fn label_table(&self) -> Array<i64,Ix2> {
+        let samples = self.labels.first().map(|x| x.len()).unwrap_or(0);
+        let mut table = Array::zeros((self.labels.len(),samples + 1));
+        for ((mut row,labels),rate) in table.outer_iter_mut().zip(self.labels.iter()).zip(self.rates.iter()) {
+            row[0] = *rate as i64;
+            row.slice_mut(s![1..]).assign(labels);
+        }
+        table
+    }
+
+    // Every pair of clusters at resolutions k and k+1 that share samples, with the
+    // fraction of the child that came from the parent. The parent holding most of
+    // a child is marked as its best parent. Noise is left out.
+
+    pub fn edges(&self) -> Vec<SweepEdge> {
+
+        let mut edges = vec![];
+
+        for resolution in 0..self.labels.len().saturating_sub(1) {
+
+            let (parents,children) = (&self.labels[resolution],&self.labels[resolution+1]);
+
+            let mut overlaps: HashMap<(i64,i64),usize> = HashMap::new();
+            let mut child_sizes: HashMap<i64,usize> = HashMap::new();
+
+            for (parent,child) in parents.iter().zip(children.iter()) {
+                if *child < 0 {
+                    continue
+                }
+                *child_sizes.entry(*child).or_insert(0) += 1;
+                if *parent >= 0 {
+                    *overlaps.entry((*parent,*child)).or_insert(0) += 1;
+                }
+            }
+
+            let mut best_parents: HashMap<i64,(usize,i64)> = HashMap::new();
+            for (&(parent,child),&overlap) in overlaps.iter() {
+                let best = best_parents.entry(child).or_insert((overlap,parent));
+                if overlap > best.0 || (overlap == best.0 && parent < best.1) {
+                    *best = (overlap,parent);
+                }
+            }
+
+            let mut pairs: Vec<((i64,i64),usize)> = overlaps.into_iter().collect();
+            pairs.sort();
+
+            for ((parent,child),overlap) in pairs {
+                edges.push(SweepEdge {
+                    resolution,
+                    parent,
+                    child,
+                    overlap,
+                    child_fraction: overlap as f64 / child_sizes[&child] as f64,
+                    best: best_parents[&child].1 == parent,
+                });
+            }
+        }
+
+        edges
+    }
+
+    pub fn write_tree(&self, location: &str) -> Result<(),Error> {
+        let mut tree_file = AtomicFile::create(location)?;
+        writeln!(tree_file,"parent_resolution\tparent_rate\tparent_cluster\tchild_resolution\tchild_rate\tchild_cluster\toverlap\tchild_fraction\tbest_parent")?;
+        for edge in self.edges() {
+            writeln!(
+                tree_file,"{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}\t{}",
+                edge.resolution,self.rates[edge.resolution],edge.parent,
+                edge.resolution+1,self.rates[edge.resolution+1],edge.child,
+                edge.overlap,edge.child_fraction,edge.best,
+            )?;
+        }
+        tree_file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn sweep() -> Sweep {
+        let mut sweep = Sweep::new();
+        sweep.push(50,Array::from(vec![0,0,0,1,1,-1]));
+        sweep.push(100,Array::from(vec![0,0,1,1,2,2]));
+        sweep
+    }
+
+    #[test]
+    fn label_rows_start_with_the_rate() {
+        assert_eq!(sweep().label_table(),Array::from_shape_vec((2,7),vec![50,0,0,0,1,1,-1,100,0,0,1,1,2,2]).unwrap());
+    }
+
+    #[test]
+    fn edges_link_clusters_sharing_samples() {
+        let edges: Vec<(usize,i64,i64,usize,f64,bool)> = sweep().edges().into_iter()
+            .map(|edge| (edge.resolution,edge.parent,edge.child,edge.overlap,edge.child_fraction,edge.best))
+            .collect();
+        // Ties go to the lower parent, and the noise sample counts towards its child's size only
+        assert_eq!(edges,vec![
+            (0,0,0,2,1.,true),
+            (0,0,1,1,0.5,true),
+            (0,1,1,1,0.5,false),
+            (0,1,2,1,0.5,true),
+        ]);
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::{Error,ErrorKind};
+use std::io::prelude::*;
+use std::path::Path;
+
+use sparse::{CsrMatrix,read_mtx,open_maybe_gz};
+use delimited::deduplicate;
+
+// Cell Ranger style directories: matrix.mtx, barcodes.tsv and features.tsv (genes.tsv
+// before Cell Ranger 3), each optionally gzipped. The matrix is genes by cells, so it
+// is transposed into cells by genes. Barcodes name the samples, and gene symbols, the
+// second column of the features, name the features.
+
+pub struct TenX {
+    pub counts: CsrMatrix,
+    pub barcodes: Vec<String>,
+    pub features: Vec<String>,
+}
+
+fn find(directory: &Path, names: &[&str]) -> Result<String,Error> {
+    names.iter()
+        .flat_map(|name| vec![directory.join(name),directory.join(format!("{}.gz",name))])
+        .find(|candidate| candidate.exists())
+        .map(|found| found.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::new(ErrorKind::NotFound,format!("No {} in {}",names.join(" or "),directory.display())))
+}
+
+// One entry per line, from the given tab separated column, or the first if a line is shorter
+fn read_column(location: &str, column: usize) -> Result<Vec<String>,Error> {
+    let mut entries = vec![];
+    for line in open_maybe_gz(location)?.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        entries.push(fields.get(column).unwrap_or(&fields[0]).trim().to_string());
+    }
+    Ok(entries)
+}
+
+pub fn read_10x(directory: &str, verbose: bool) -> Result<TenX,Error> {
+
+    let directory = Path::new(directory);
+
+    let matrix = find(directory,&["matrix.mtx"])?;
+    let barcodes = find(directory,&["barcodes.tsv"])?;
+    let features = find(directory,&["features.tsv","genes.tsv"])?;
+
+    if verbose {
+        eprintln!("10x: reading {}, {} and {}",matrix,barcodes,features);
+    }
+
+    let counts = read_mtx(open_maybe_gz(&matrix)?,verbose)?.transpose();
+    let barcodes = read_column(&barcodes,0)?;
+    let features = deduplicate(read_column(&features,1)?);
+
+    if barcodes.len() != counts.rows || features.len() != counts.cols {
+        return Err(Error::new(ErrorKind::InvalidData,format!(
+            "{} barcodes and {} features don't match a matrix of {} genes by {} cells",
+            barcodes.len(),features.len(),counts.cols,counts.rows
+        )))
+    }
+
+    eprintln!("10x: {} cells, {} genes, {} nonzero",counts.rows,counts.cols,counts.nnz());
+
+    Ok(TenX { counts, barcodes, features })
+}
@@ -0,0 +1,239 @@
+use std::io::Error;
+use std::io::prelude::*;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+
+use ndarray::{ArrayView,Ix2};
+
+use io::AtomicFile;
+use sparse::CsrMatrix;
+
+// What is wrong with an input matrix. Ragged rows and unparsable cells are found
+// while reading text, everything else is checked on the values, whatever they
+// were read from. Lines and fields are 1-based positions in the input file,
+// samples and features are 0-based indices into the matrix.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Policy {
+    // Anything in the report stops the run
+    Strict,
+    // Ragged rows and unparsable cells stop the run, the rest are warnings
+    Default,
+    // Ragged rows are dropped and unparsable cells read as missing
+    Lenient,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct ValidationReport {
+    pub expected_fields: usize,
+    pub ragged_rows: Vec<(usize,usize)>,
+    pub non_numeric: Vec<(usize,usize,String)>,
+    pub sample_lines: Option<Vec<usize>>,
+    pub zero_rows: Vec<usize>,
+    pub constant_columns: Vec<usize>,
+    pub duplicate_rows: Vec<(usize,usize)>,
+}
+
+impl ValidationReport {
+
+    pub fn new() -> ValidationReport {
+        ValidationReport::default()
+    }
+
+    pub fn check_values(&mut self, counts: ArrayView<f64,Ix2>) {
+
+        self.zero_rows = counts.outer_iter().enumerate()
+            .filter(|(_,row)| row.iter().all(|x| *x == 0.))
+            .map(|(i,_)| i)
+            .collect();
+
+        if counts.rows() > 1 {
+            self.constant_columns = counts.axis_iter(ndarray::Axis(1)).enumerate()
+                .filter(|(_,column)| column.iter().all(|x| *x == column[0]))
+                .map(|(j,_)| j)
+                .collect();
+        }
+
+        // Rows are bucketed by a hash of their bits and only compared within a bucket
+
+        let mut buckets: HashMap<u64,Vec<usize>> = HashMap::new();
+        self.duplicate_rows.clear();
+
+        for (i,row) in counts.outer_iter().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            for x in row.iter() {
+                x.to_bits().hash(&mut hasher);
+            }
+            let bucket = buckets.entry(hasher.finish()).or_default();
+            match bucket.iter().find(|j| counts.row(**j).iter().zip(row.iter()).all(|(a,b)| a.to_bits() == b.to_bits())) {
+                Some(j) => self.duplicate_rows.push((i,*j)),
+                None => bucket.push(i),
+            }
+        }
+    }
+
+    // The same checks on the stored entries of a sparse matrix. A column is constant
+    // if it has no entries, or one equal entry in every row.
+
+    pub fn check_sparse(&mut self, counts: &CsrMatrix) {
+
+        self.zero_rows = (0..counts.rows)
+            .filter(|i| counts.row(*i).data.iter().all(|x| *x == 0.))
+            .collect();
+
+        if counts.rows > 1 {
+            let mut entries: Vec<Vec<f64>> = vec![vec![]; counts.cols];
+            for (j,x) in counts.indices.iter().zip(counts.data.iter()) {
+                entries[*j].push(*x);
+            }
+            self.constant_columns = entries.iter().enumerate()
+                .filter(|(_,column)| column.iter().all(|x| *x == 0.) || (column.len() == counts.rows && column.iter().all(|x| *x == column[0])))
+                .map(|(j,_)| j)
+                .collect();
+        }
+
+        let mut buckets: HashMap<u64,Vec<usize>> = HashMap::new();
+        self.duplicate_rows.clear();
+
+        for i in 0..counts.rows {
+            let row = counts.row(i);
+            let mut hasher = DefaultHasher::new();
+            row.indices.hash(&mut hasher);
+            for x in row.data.iter() {
+                x.to_bits().hash(&mut hasher);
+            }
+            let bucket = buckets.entry(hasher.finish()).or_default();
+            let same = |j: usize| {
+                let other = counts.row(j);
+                other.indices == row.indices && other.data.iter().zip(row.data.iter()).all(|(a,b)| a.to_bits() == b.to_bits())
+            };
+            match bucket.iter().find(|j| same(**j)) {
+                Some(j) => self.duplicate_rows.push((i,*j)),
+                None => bucket.push(i),
+            }
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.ragged_rows.is_empty() && self.non_numeric.is_empty() && self.zero_rows.is_empty() &&
+        self.constant_columns.is_empty() && self.duplicate_rows.is_empty()
+    }
+
+    fn line(&self, sample: usize) -> String {
+        self.sample_lines.as_ref().map(|lines| lines[sample].to_string()).unwrap_or_else(|| "-".to_string())
+    }
+
+    fn issues(&self) -> Vec<(&str,String,String,String,String)> {
+        let mut issues = vec![];
+        for (line,fields) in &self.ragged_rows {
+            issues.push(("ragged_row","-".to_string(),line.to_string(),"-".to_string(),format!("{} fields, expected {}",fields,self.expected_fields)));
+        }
+        for (line,field,content) in &self.non_numeric {
+            issues.push(("non_numeric","-".to_string(),line.to_string(),field.to_string(),format!("{:?}",content)));
+        }
+        for sample in &self.zero_rows {
+            issues.push(("zero_row",sample.to_string(),self.line(*sample),"-".to_string(),"undefined under cosine distance".to_string()));
+        }
+        for feature in &self.constant_columns {
+            issues.push(("constant_column","-".to_string(),"-".to_string(),(feature + 1).to_string(),format!("feature {}",feature)));
+        }
+        for (sample,first) in &self.duplicate_rows {
+            issues.push(("duplicate_row",sample.to_string(),self.line(*sample),"-".to_string(),format!("same as sample {} on line {}",first,self.line(*first))));
+        }
+        issues
+    }
+
+    pub fn print(&self) {
+        eprintln!(
+            "Validation: {} ragged rows, {} non-numeric cells, {} all-zero rows, {} constant columns, {} duplicate rows",
+            self.ragged_rows.len(),self.non_numeric.len(),self.zero_rows.len(),self.constant_columns.len(),self.duplicate_rows.len()
+        );
+        let mut shown: HashMap<&str,usize> = HashMap::new();
+        for (kind,sample,line,field,detail) in self.issues() {
+            let count = shown.entry(kind).or_insert(0);
+            *count += 1;
+            if *count <= 10 {
+                eprintln!("{}: sample {}, line {}, field {}: {}",kind,sample,line,field,detail);
+            }
+            else if *count == 11 {
+                eprintln!("{}: ...",kind);
+            }
+        }
+    }
+
+    // Panics if the policy doesn't allow what was found
+    pub fn enforce(&self, policy: Policy) {
+        let fatal = match policy {
+            Policy::Strict => !self.is_clean(),
+            Policy::Default => !self.ragged_rows.is_empty() || !self.non_numeric.is_empty(),
+            Policy::Lenient => false,
+        };
+        if fatal {
+            panic!("Input failed validation, see the report above. Use -lenient to drop ragged rows and read unparsable cells as missing");
+        }
+    }
+
+    pub fn write(&self, location: &str) -> Result<(),Error> {
+        let mut report_file = AtomicFile::create(location)?;
+        writeln!(report_file,"issue\tsample\tline\tfield\tdetail")?;
+        for (kind,sample,line,field,detail) in self.issues() {
+            writeln!(report_file,"{}\t{}\t{}\t{}\t{}",kind,sample,line,field,detail)?;
+        }
+        report_file.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::panic;
+    use delimited::{read_delimited,DelimitedOptions};
+
+    fn validated(text: &str) -> ValidationReport {
+        let delimited = read_delimited(text.as_bytes(),&DelimitedOptions::new(),false);
+        let mut validation = delimited.validation;
+        validation.check_values(delimited.counts.view());
+        validation
+    }
+
+    fn stops(validation: &ValidationReport, policy: Policy) -> bool {
+        panic::catch_unwind(|| validation.enforce(policy)).is_err()
+    }
+
+    #[test]
+    fn finds_every_kind_of_issue() {
+        let validation = validated("1\t2\t3\t0\n4\tx\t3\t0\n1\t2\t3\t0\n0\t0\t0\t0\n5\t6\t0\n");
+        assert_eq!(validation.expected_fields,4);
+        assert_eq!(validation.ragged_rows,vec![(5,3)]);
+        assert_eq!(validation.non_numeric,vec![(2,2,"x".to_string())]);
+        assert_eq!(validation.zero_rows,vec![3]);
+        assert_eq!(validation.constant_columns,vec![3]);
+        assert_eq!(validation.duplicate_rows,vec![(2,0)]);
+        assert!(stops(&validation,Policy::Strict));
+        assert!(stops(&validation,Policy::Default));
+        assert!(!stops(&validation,Policy::Lenient));
+    }
+
+    #[test]
+    fn only_strict_stops_on_value_issues() {
+        let validation = validated("1\t2\n1\t2\n3\t2\n");
+        assert_eq!((validation.constant_columns.clone(),validation.duplicate_rows.clone()),(vec![1],vec![(1,0)]));
+        assert!(stops(&validation,Policy::Strict));
+        assert!(!stops(&validation,Policy::Default));
+        let clean = validated("1\t2\n3\t4\n");
+        assert!(clean.is_clean());
+        assert!(!stops(&clean,Policy::Strict));
+    }
+
+    #[test]
+    fn sparse_checks_match_dense_ones() {
+        let counts = ndarray::Array::from_shape_vec((4,3),vec![1.,0.,2.,0.,0.,0.,1.,0.,2.,3.,0.,2.]).unwrap();
+        let (mut dense,mut sparse) = (ValidationReport::new(),ValidationReport::new());
+        dense.check_values(counts.view());
+        sparse.check_sparse(&CsrMatrix::from_dense(counts.view()));
+        assert_eq!((dense.zero_rows.clone(),dense.constant_columns.clone(),dense.duplicate_rows.clone()),(vec![1],vec![1],vec![(2,0)]));
+        assert_eq!((sparse.zero_rows,sparse.constant_columns,sparse.duplicate_rows),(dense.zero_rows,dense.constant_columns,dense.duplicate_rows));
+    }
+}
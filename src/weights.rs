@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use rand::Rng;
+
+/// Per-sample weights, eg the number of observations a metacell stands for.
+#[derive(Debug,Clone)]
+pub struct Weights {
+    weights: Vec<f64>,
+    cumulative: Vec<f64>,
+}
+
+impl Weights {
+
+    pub fn new(weights: Vec<f64>) -> Weights {
+        if let Some((i,w)) = weights.iter().enumerate().find(|(_,w)| !w.is_finite() || **w < 0.) {
+            panic!("Sample weights must be finite and non-negative, found {} for sample {}",w,i);
+        }
+        let mut cumulative = Vec::with_capacity(weights.len());
+        let mut acc = 0.;
+        for w in &weights {
+            acc += w;
+            cumulative.push(acc);
+        }
+        if acc <= 0. {
+            panic!("Sample weights sum to zero");
+        }
+        Weights { weights, cumulative }
+    }
+
+    pub fn weight(&self, sample: usize) -> f64 {
+        self.weights[sample]
+    }
+
+    pub fn len(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn subset(&self, samples: &[usize]) -> Weights {
+        Weights::new(samples.iter().map(|i| self.weights[*i]).collect())
+    }
+
+    pub fn total(&self) -> f64 {
+        *self.cumulative.last().unwrap_or(&0.)
+    }
+
+    // Draws samples with probability proportional to their weight. Draws are made
+    // with replacement, so a heavy sample can show up several times, as the
+    // observations it stands for would have.
+
+    pub fn sample<R: Rng>(&self, rng: &mut R, amount: usize) -> Vec<usize> {
+        let total = self.total();
+        (0..amount).map(|_| {
+            let target = rng.gen::<f64>() * total;
+            self.cumulative.partition_point(|x| *x <= target).min(self.weights.len() - 1)
+        }).collect()
+    }
+
+}
+
+pub fn read_weights(location: &str) -> Vec<f64> {
+
+    let weight_file = File::open(location).expect("Weight file error!");
+    let weight_lines = io::BufReader::new(&weight_file).lines();
+
+    weight_lines
+        .map(|line| line.expect("Error reading weight line!"))
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().parse::<f64>().unwrap_or_else(|_| panic!("Couldn't parse a sample weight: {:?}",line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::{SeedableRng,StdRng};
+
+    #[test]
+    #[should_panic(expected = "finite and non-negative")]
+    fn rejects_negative_weights() {
+        Weights::new(vec![1.,-1.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "finite and non-negative")]
+    fn rejects_nan_weights() {
+        Weights::new(vec![1.,f64::NAN]);
+    }
+
+    #[test]
+    fn subsets_keep_their_weights() {
+        let weights = Weights::new(vec![1.,2.,3.]).subset(&[2,0]);
+        assert_eq!((weights.len(),weights.weight(0),weights.weight(1),weights.total()),(2,3.,1.,4.));
+    }
+
+    #[test]
+    fn samples_follow_the_weights() {
+        let weights = Weights::new(vec![1.,0.,3.]);
+        let draws = weights.sample(&mut StdRng::from_seed([7; 32]),4000);
+        assert_eq!(draws,weights.sample(&mut StdRng::from_seed([7; 32]),4000));
+        let counts: Vec<usize> = (0..3).map(|i| draws.iter().filter(|x| **x == i).count()).collect();
+        // Drawn with replacement, so the zero weight sample never shows up and the heavy one repeats
+        assert_eq!(counts[1],0);
+        assert!((counts[2] as f64 / counts[0] as f64 - 3.).abs() < 0.3);
+    }
+}